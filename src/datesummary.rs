@@ -16,6 +16,12 @@ pub struct DateSummary {
     file_dates: u64,
 }
 
+impl Default for DateSummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DateSummary {
     pub fn new() -> DateSummary {
         let dates: HashMap<i32, usize> = HashMap::new();
@@ -51,12 +57,12 @@ impl DateSummary {
     }
 
     pub fn add_fileindex(&mut self, f: &IndexFile) {
-        let mut date: DateTime<Utc> = f.created_at.clone();
+        let mut date: DateTime<Utc> = f.created_at;
         let mut has_exif_date = false;
-        for ref v in &f.meta {
+        for v in &f.meta {
             if v.name == "DateTaken" {
                 if let MetaValue::Date(ref value) = v.value {
-                    date = value.clone();
+                    date = *value;
                     self.exif_dates += 1;
                     has_exif_date = true;
                 }
@@ -71,7 +77,7 @@ impl DateSummary {
 
 impl FileIndexSummarizer for DateSummary {
     fn add(&mut self, f: &crate::fileindex::IndexFile) {
-        self.add_fileindex(&f);
+        self.add_fileindex(f);
     }
 }
 
@@ -193,7 +199,7 @@ impl fmt::Display for DateSummary {
                     }
                 }
             }
-            write!(f, "\n")?;
+            writeln!(f)?;
             if date.year() > max_year || (date.year() == max_year && date.month() > max_month) {
                 break;
             }
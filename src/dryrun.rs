@@ -0,0 +1,307 @@
+//! Dry run of indexing: compare files on disk with the index without
+//! changing the database or running metadata commands.
+
+use clap::ValueEnum;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::fileindex::IndexFile;
+use crate::filewalk::FoundFile;
+use crate::indexpipeline::{self, IndexOptions};
+use crate::processing::{self, AdaptiveLimiter};
+
+/// What indexing would do with a file
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DryRunStatus {
+    /// The file is not in the index
+    New,
+    /// The file is in the index with the same contents
+    Indexed,
+    /// The contents were indexed at a path that no longer exists
+    Moved,
+    /// The file is in the index, but its contents changed
+    WouldUpdate,
+    /// The file cannot be read
+    Error,
+}
+
+impl fmt::Display for DryRunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            DryRunStatus::New => "new",
+            DryRunStatus::Indexed => "indexed",
+            DryRunStatus::Moved => "moved",
+            DryRunStatus::WouldUpdate => "would-update",
+            DryRunStatus::Error => "error",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// How a dry run report is printed
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Counts and the files that are not indexed yet
+    Table,
+    /// All files with their status
+    Json,
+}
+
+/// A file found on disk, with its hash if it could be read
+#[derive(Debug, Clone)]
+pub struct ScannedFile {
+    pub filename: String,
+    pub sha256: Result<String, String>,
+}
+
+/// A file in the index
+#[derive(Debug, Clone)]
+pub struct IndexedFile {
+    pub filename: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunEntry {
+    pub status: DryRunStatus,
+    pub filename: String,
+    /// Previous location of moved files, or the error for unreadable files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DryRunReport {
+    pub counts: BTreeMap<DryRunStatus, usize>,
+    pub files: Vec<DryRunEntry>,
+}
+
+/// Hash files without changing the index. Uses the concurrency and progress
+/// options of indexing.
+pub async fn scan<I>(files: I, options: &IndexOptions) -> Vec<ScannedFile>
+where
+    I: IntoIterator<Item = FoundFile>,
+{
+    type Context = (Arc<Mutex<Vec<ScannedFile>>>, Arc<AdaptiveLimiter>);
+    async fn hash_fun(entry: FoundFile, (scanned, limiter): Context) {
+        let size = entry.size;
+        let hashing = tokio::task::spawn_blocking(move || {
+            let sha256 = match IndexFile::new(&entry.path) {
+                // not a photo or video, so it would not be indexed
+                Ok(f) if entry.media_only && f.media_type.is_none() => return,
+                Ok(f) => Ok(f.sha256),
+                Err(e) => Err(e.to_string()),
+            };
+            let filename = std::fs::canonicalize(&entry.path)
+                .ok()
+                .and_then(|p| p.to_str().map(String::from))
+                .unwrap_or(entry.path);
+            scanned
+                .lock()
+                .unwrap()
+                .push(ScannedFile { filename, sha256 });
+        });
+        let result = limiter.run(size, hashing).await;
+        if let Err(err) = result {
+            error!("Error processing file: {:?}", err);
+        }
+    }
+
+    let scanned = Arc::new(Mutex::new(Vec::new()));
+    let parallelism = std::thread::available_parallelism().unwrap().get();
+    let hash_concurrency = options
+        .hash_concurrency
+        .or(options.concurrency)
+        .unwrap_or(parallelism);
+    let limiter = Arc::new(AdaptiveLimiter::new(hash_concurrency));
+    processing::consume_concurrently(
+        files,
+        hash_fun,
+        &(scanned.clone(), limiter.clone()),
+        options.progress,
+        options.total,
+        processing::ConcurrencyOptions {
+            concurrency: options.concurrency,
+            throttle: Some(limiter),
+            item_bytes: Some(indexpipeline::file_size),
+        },
+    )
+    .await;
+    let scanned = std::mem::take(&mut *scanned.lock().unwrap());
+    scanned
+}
+
+/// Compare scanned files with the index.
+///
+/// File hashes include the file URL, so a moved file has a different hash.
+/// To find moved files, `hash_with_url` hashes the contents of a scanned file
+/// with the URL of an indexed file of the same name that no longer exists.
+pub fn classify(
+    scanned: Vec<ScannedFile>,
+    indexed: &[IndexedFile],
+    hash_with_url: impl Fn(&str, &str) -> Option<String>,
+) -> DryRunReport {
+    let indexed_hashes: HashSet<&str> = indexed.iter().map(|f| f.sha256.as_str()).collect();
+    let indexed_names: HashSet<&str> = indexed.iter().map(|f| f.filename.as_str()).collect();
+    let mut missing_by_name: HashMap<&str, Vec<&IndexedFile>> = HashMap::new();
+    for file in indexed {
+        if !Path::new(&file.filename).exists() {
+            let name = file_name(&file.filename);
+            missing_by_name.entry(name).or_default().push(file);
+        }
+    }
+
+    let mut report = DryRunReport::default();
+    for file in scanned {
+        let (status, detail) = match file.sha256 {
+            Err(e) => (DryRunStatus::Error, Some(e)),
+            Ok(ref sha256) if indexed_hashes.contains(sha256.as_str()) => {
+                (DryRunStatus::Indexed, None)
+            }
+            Ok(_) if indexed_names.contains(file.filename.as_str()) => {
+                (DryRunStatus::WouldUpdate, None)
+            }
+            Ok(_) => {
+                let moved_from =
+                    missing_by_name
+                        .get(file_name(&file.filename))
+                        .and_then(|candidates| {
+                            candidates.iter().find(|c| {
+                                hash_with_url(&file.filename, &c.url).as_deref()
+                                    == Some(c.sha256.as_str())
+                            })
+                        });
+                match moved_from {
+                    Some(c) => (DryRunStatus::Moved, Some(c.filename.clone())),
+                    None => (DryRunStatus::New, None),
+                }
+            }
+        };
+        *report.counts.entry(status).or_insert(0) += 1;
+        report.files.push(DryRunEntry {
+            status,
+            filename: file.filename,
+            detail,
+        });
+    }
+    report
+        .files
+        .sort_by(|a, b| (a.status, &a.filename).cmp(&(b.status, &b.filename)));
+    report
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(path)
+}
+
+impl DryRunReport {
+    /// Format as a table of counts, followed by all files that are not indexed yet
+    pub fn to_table(&self) -> String {
+        let mut output = String::new();
+        for (status, count) in &self.counts {
+            output.push_str(&format!("{:<14}{}\n", status.to_string(), count));
+        }
+        let changes: Vec<&DryRunEntry> = self
+            .files
+            .iter()
+            .filter(|f| f.status != DryRunStatus::Indexed)
+            .collect();
+        if !changes.is_empty() {
+            output.push('\n');
+        }
+        for file in changes {
+            output.push_str(&format!("{:<14}{}", file.status.to_string(), file.filename));
+            match (file.status, &file.detail) {
+                (DryRunStatus::Moved, Some(from)) => output.push_str(&format!(" (from {})", from)),
+                (_, Some(detail)) => output.push_str(&format!(" ({})", detail)),
+                _ => {}
+            }
+            output.push('\n');
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn indexed(filename: &str, sha256: &str) -> IndexedFile {
+        IndexedFile {
+            filename: String::from(filename),
+            url: format!("file://{}", filename),
+            sha256: String::from(sha256),
+        }
+    }
+
+    fn scanned(filename: &str, sha256: &str) -> ScannedFile {
+        ScannedFile {
+            filename: String::from(filename),
+            sha256: Ok(String::from(sha256)),
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let index = vec![
+            indexed("/photocat-missing/a.jpg", "sha-a"),
+            indexed("/photocat-missing/b.jpg", "sha-b"),
+            indexed("/photocat-missing/old/c.jpg", "sha-c"),
+        ];
+        let files = vec![
+            scanned("/photocat-missing/a.jpg", "sha-a"),
+            scanned("/photocat-missing/b.jpg", "sha-b2"),
+            scanned("/photocat-missing/new/c.jpg", "sha-c2"),
+            scanned("/photocat-missing/d.jpg", "sha-d"),
+            ScannedFile {
+                filename: String::from("/photocat-missing/e.jpg"),
+                sha256: Err(String::from("Permission denied")),
+            },
+        ];
+        // hashing c.jpg with its old URL gives its old hash
+        let hash_with_url = |filename: &str, url: &str| {
+            if filename.ends_with("c.jpg") && url.ends_with("old/c.jpg") {
+                Some(String::from("sha-c"))
+            } else {
+                Some(String::from("other"))
+            }
+        };
+        let report = classify(files, &index, hash_with_url);
+
+        assert_eq!(report.counts[&DryRunStatus::New], 1);
+        assert_eq!(report.counts[&DryRunStatus::Indexed], 1);
+        assert_eq!(report.counts[&DryRunStatus::Moved], 1);
+        assert_eq!(report.counts[&DryRunStatus::WouldUpdate], 1);
+        assert_eq!(report.counts[&DryRunStatus::Error], 1);
+
+        let moved = report
+            .files
+            .iter()
+            .find(|f| f.status == DryRunStatus::Moved)
+            .unwrap();
+        assert_eq!(moved.filename, "/photocat-missing/new/c.jpg");
+        assert_eq!(moved.detail.as_deref(), Some("/photocat-missing/old/c.jpg"));
+
+        let table = report.to_table();
+        assert!(table.contains("would-update  1\n"));
+        assert!(table.contains(
+            "moved         /photocat-missing/new/c.jpg (from /photocat-missing/old/c.jpg)\n"
+        ));
+        assert!(!table.contains("indexed       /photocat-missing/a.jpg"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["counts"]["would-update"], json!(1));
+        assert_eq!(json["files"][0]["status"], json!("new"));
+    }
+}
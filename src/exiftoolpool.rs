@@ -0,0 +1,412 @@
+//! Pool of persistent exiftool processes.
+//!
+//! Starting exiftool is dominated by Perl startup, so instead of running
+//! it once per file we keep a number of processes alive in
+//! `-stay_open True -@ -` mode and send them one request per file.
+//! Each request ends in `-echo4 {readyN}` and `-executeN`, so that both
+//! stdout and stderr of the response are terminated by `{readyN}`.
+//! Both are read by their own thread, so a process that fills one pipe
+//! while we wait on the other cannot block, and a process that does not
+//! answer within the timeout is killed and replaced.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::metacmd::{MetaCommand, MetaCommandError, MetaOutput, PATH_PLACEHOLDER};
+
+struct ExiftoolWorker {
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines read from stdout and stderr
+    stdout: Receiver<String>,
+    stderr: Receiver<String>,
+    request_id: usize,
+}
+
+/// Send the lines of a pipe to a channel until it is closed
+fn read_lines_in_thread<R: Read + Send + 'static>(pipe: R) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+impl ExiftoolWorker {
+    fn start(program: &str) -> io::Result<ExiftoolWorker> {
+        let mut child = Command::new(program)
+            .args(["-stay_open", "True", "-@", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = read_lines_in_thread(child.stdout.take().unwrap());
+        let stderr = read_lines_in_thread(child.stderr.take().unwrap());
+        Ok(ExiftoolWorker {
+            child,
+            stdin,
+            stdout,
+            stderr,
+            request_id: 0,
+        })
+    }
+
+    /// Send a request for one file and read the response, failing with
+    /// `TimedOut` if it is not complete within the timeout
+    fn request(
+        &mut self,
+        options: &[String],
+        path: &str,
+        timeout: Duration,
+    ) -> io::Result<(String, String)> {
+        self.request_id += 1;
+        let ready = format!("{{ready{}}}", self.request_id);
+        let mut request = String::new();
+        for option in options {
+            request.push_str(option);
+            request.push('\n');
+        }
+        // a line starting with a dash would be taken as an option
+        if path.starts_with('-') {
+            request.push_str("./");
+        }
+        request.push_str(&format!(
+            "{}\n-echo4\n{}\n-execute{}\n",
+            path, ready, self.request_id
+        ));
+        self.stdin.write_all(request.as_bytes())?;
+        self.stdin.flush()?;
+
+        let deadline = Instant::now() + timeout;
+        let stdout = read_until_ready(&self.stdout, &ready, deadline)?;
+        let stderr = read_until_ready(&self.stderr, &ready, deadline)?;
+        Ok((stdout, stderr))
+    }
+}
+
+impl Drop for ExiftoolWorker {
+    fn drop(&mut self) {
+        // ask exiftool to exit, and make sure it does
+        let _ = self.stdin.write_all(b"-stay_open\nFalse\n");
+        let _ = self.stdin.flush();
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
+    }
+}
+
+/// Receive lines until the line matching `ready`, returning everything
+/// before it
+fn read_until_ready(
+    lines: &Receiver<String>,
+    ready: &str,
+    deadline: Instant,
+) -> io::Result<String> {
+    let mut output = String::new();
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let line = match lines.recv_timeout(timeout) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "exiftool did not respond in time",
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "exiftool exited unexpectedly",
+                ))
+            }
+        };
+        if line.trim_end() == ready {
+            return Ok(output);
+        }
+        output.push_str(&line);
+        output.push('\n');
+    }
+}
+
+struct PoolState {
+    idle: Vec<ExiftoolWorker>,
+    /// number of processes that are running, idle or busy
+    live: usize,
+}
+
+/// A fixed number of exiftool processes that can be shared between threads
+pub struct ExiftoolPool {
+    program: String,
+    options: Vec<String>,
+    /// How long to wait for the response for one file
+    timeout: Duration,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+impl std::fmt::Debug for ExiftoolPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ExiftoolPool")
+            .field("program", &self.program)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl ExiftoolPool {
+    /// Start `size` exiftool processes. `options` are passed with every file,
+    /// and a process that takes longer than `timeout` for a file is restarted.
+    /// Fails if exiftool cannot be started.
+    pub fn new(
+        program: &str,
+        options: Vec<String>,
+        size: usize,
+        timeout: Duration,
+    ) -> io::Result<ExiftoolPool> {
+        let mut workers = Vec::new();
+        for _ in 0..size.max(1) {
+            workers.push(ExiftoolWorker::start(program)?);
+        }
+        debug!("Started {} {} processes", workers.len(), program);
+        Ok(ExiftoolPool {
+            program: String::from(program),
+            options,
+            timeout,
+            state: Mutex::new(PoolState {
+                live: workers.len(),
+                idle: workers,
+            }),
+            available: Condvar::new(),
+        })
+    }
+
+    /// Start a pool using the program and options of a per-file metadata
+    /// command such as `exiftool -j -b -`. The `-` or `{path}` arguments
+    /// are replaced by the path sent for each file, and the timeout of the
+    /// command applies to each file.
+    pub fn from_command(meta_cmd: &MetaCommand, size: usize) -> io::Result<ExiftoolPool> {
+        let argv = meta_cmd.argv();
+        let options = argv[1..]
+            .iter()
+            .filter(|a| a.as_str() != "-" && !a.contains(PATH_PLACEHOLDER))
+            .cloned()
+            .collect();
+        ExiftoolPool::new(&argv[0], options, size, meta_cmd.timeout())
+    }
+
+    /// Command line equivalent to one request, for messages
+    pub fn command_line(&self) -> String {
+        format!(
+            "{} -stay_open True {}",
+            self.program,
+            self.options.join(" ")
+        )
+    }
+
+    /// Extract metadata for a single file, waiting for an idle process
+    pub fn run(&self, path: &str) -> Result<MetaOutput, MetaCommandError> {
+        if path.contains('\n') {
+            return Err(MetaCommandError::NoOutput {
+                stderr: String::from("file names containing newlines are not supported"),
+            });
+        }
+        let mut worker = {
+            let mut state = self.state.lock().unwrap();
+            loop {
+                if let Some(worker) = state.idle.pop() {
+                    break worker;
+                }
+                if state.live == 0 {
+                    return Err(MetaCommandError::Io(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "no exiftool processes left running",
+                    )));
+                }
+                state = self.available.wait(state).unwrap();
+            }
+        };
+
+        let result = worker.request(&self.options, path, self.timeout);
+        let worker = match result {
+            Ok(_) => Ok(worker),
+            Err(ref e) => {
+                // this process is in an unknown state, start a fresh one
+                warn!("exiftool process failed ({}), restarting it", e);
+                drop(worker);
+                ExiftoolWorker::start(&self.program)
+            }
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            match worker {
+                Ok(worker) => state.idle.push(worker),
+                Err(e) => {
+                    warn!("Cannot restart exiftool process: {}", e);
+                    state.live -= 1;
+                }
+            }
+            // wake all waiters when the pool shrinks so they can give up
+            self.available.notify_all();
+        }
+
+        let (stdout, stderr) = match result {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                return Err(MetaCommandError::Timeout(self.timeout))
+            }
+            result => result?,
+        };
+        if stdout.trim().is_empty() {
+            Err(MetaCommandError::NoOutput { stderr })
+        } else {
+            Ok(MetaOutput { stdout, stderr })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use std::fs::File;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A stand-in for exiftool that speaks the -stay_open protocol and
+    /// returns the file name and the number of requests handled so far.
+    /// It does not answer for files with "hang" in their name.
+    const STUB_EXIFTOOL: &str = r#"#!/bin/sh
+file=""
+echo4=""
+count=0
+while IFS= read -r line; do
+    case "$line" in
+        -stay_open)
+            read -r flag
+            if [ "$flag" = "False" ]; then exit 0; fi ;;
+        -echo4)
+            read -r echo4 ;;
+        -execute*)
+            count=$((count + 1))
+            case "$file" in *hang*) sleep 10 ;; esac
+            if [ -f "$file" ]; then
+                printf '[{"SourceFile": "%s", "Requests": %d}]\n' "$file" "$count"
+            else
+                echo "Error: File not found - $file" >&2
+            fi
+            echo "{ready${line#-execute}}"
+            echo "$echo4" >&2
+            file="" ;;
+        -*) ;;
+        *) file="$line" ;;
+    esac
+done
+"#;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn stub_exiftool(dir: &std::path::Path) -> String {
+        let path = dir.join("exiftool");
+        std::fs::write(&path, STUB_EXIFTOOL).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_pool_reuses_processes() {
+        let dir = TempDir::new("exiftoolpool-reuse");
+        let program = stub_exiftool(&dir);
+        let image = dir.join("image.jpg");
+        File::create(&image).unwrap();
+        let image = image.to_str().unwrap();
+
+        let pool = ExiftoolPool::new(&program, vec![String::from("-j")], 1, TIMEOUT).unwrap();
+        for i in 1..=3 {
+            let output = pool.run(image).unwrap();
+            let json: serde_json::Value = serde_json::from_str(&output.stdout).unwrap();
+            assert_eq!(json[0]["SourceFile"], image);
+            assert_eq!(json[0]["Requests"], i);
+        }
+    }
+
+    #[test]
+    fn test_pool_missing_file() {
+        let dir = TempDir::new("exiftoolpool-missing");
+        let program = stub_exiftool(&dir);
+        let pool = ExiftoolPool::new(&program, vec![String::from("-j")], 2, TIMEOUT).unwrap();
+        match pool.run("/does/not/exist.jpg") {
+            Err(MetaCommandError::NoOutput { stderr }) => {
+                assert!(stderr.contains("File not found"), "{}", stderr)
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pool_timeout() {
+        let dir = TempDir::new("exiftoolpool-timeout");
+        let program = stub_exiftool(&dir);
+        let hang = dir.join("hang.jpg");
+        let image = dir.join("image.jpg");
+        File::create(&hang).unwrap();
+        File::create(&image).unwrap();
+
+        let timeout = Duration::from_millis(200);
+        let pool = ExiftoolPool::new(&program, vec![String::from("-j")], 1, timeout).unwrap();
+        let start = Instant::now();
+        assert!(matches!(
+            pool.run(hang.to_str().unwrap()),
+            Err(MetaCommandError::Timeout(_))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // the process that timed out was replaced by a fresh one
+        let output = pool.run(image.to_str().unwrap()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&output.stdout).unwrap();
+        assert_eq!(json[0]["Requests"], 1);
+    }
+
+    #[test]
+    fn test_pool_dash_path() {
+        let dir = TempDir::new("exiftoolpool-dash");
+        let program = stub_exiftool(&dir);
+        let pool = ExiftoolPool::new(&program, vec![String::from("-j")], 1, TIMEOUT).unwrap();
+        // sent as a file name, not as an option
+        match pool.run("-n.jpg") {
+            Err(MetaCommandError::NoOutput { stderr }) => {
+                assert!(stderr.contains("File not found - ./-n.jpg"), "{}", stderr)
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pool_from_command() {
+        let dir = TempDir::new("exiftoolpool-from-command");
+        let program = stub_exiftool(&dir);
+        let meta_cmd = MetaCommand::parse(
+            &format!("{} -b -j -", shlex::try_quote(&program).unwrap()),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        let pool = ExiftoolPool::from_command(&meta_cmd, 2).unwrap();
+        assert_eq!(pool.options, vec!["-b", "-j"]);
+    }
+
+    #[test]
+    fn test_pool_unavailable() {
+        assert!(
+            ExiftoolPool::new("photocat-exiftool-does-not-exist", Vec::new(), 1, TIMEOUT).is_err()
+        );
+    }
+}
@@ -4,9 +4,11 @@ use sha2::{Digest, Sha256};
 use std::fmt;
 use std::fs::File;
 use std::io::{Read, Result};
-use std::path::Path;
 use url::Url;
 
+use crate::indexerror::IndexError;
+use crate::mediatype;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IndexFile {
     pub filename: String,
@@ -14,10 +16,12 @@ pub struct IndexFile {
     pub sha256: String,
     pub created_at: DateTime<Utc>,
     pub modified_at: DateTime<Utc>,
+    /// MIME type detected from the file contents, if known
+    pub media_type: Option<String>,
     pub meta: Vec<MetaVariable>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum MetaValue {
     String(String),
     Int(i64),
@@ -62,6 +66,25 @@ pub struct MetaVariable {
     pub value: MetaValue,
 }
 
+/// Name of the variable holding the media type of a file in summaries
+pub const MEDIA_TYPE_VARIABLE: &str = "media_type";
+
+impl IndexFile {
+    /// Value of a metadata variable, or of the file's media type
+    pub fn value(&self, name: &str) -> Option<MetaValue> {
+        if let Some(v) = self.meta.iter().find(|v| v.name == name) {
+            return Some(v.value.clone());
+        }
+        if name == MEDIA_TYPE_VARIABLE {
+            return Some(match self.media_type {
+                Some(ref media_type) => MetaValue::String(media_type.clone()),
+                None => MetaValue::Null,
+            });
+        }
+        None
+    }
+}
+
 /// Creates a new `IndexFile` instance.
 ///
 /// # Arguments
@@ -70,43 +93,48 @@ pub struct MetaVariable {
 ///
 /// # Returns
 ///
-/// Returns a `Result` containing the `IndexFile` instance if successful, or an `std::io::Error` if the file does not exist.
+/// Returns a `Result` containing the `IndexFile` instance if successful, or an `IndexError` if the
+/// file cannot be read. The modification time is used as creation time on file systems that do
+/// not record it.
 /// Metadata are left empty by default.
 /// ```
 impl IndexFile {
-    pub fn new(name: &str) -> Result<IndexFile> {
-        let filename: String = std::fs::canonicalize(name)
-            .unwrap()
+    pub fn new(name: &str) -> std::result::Result<IndexFile, IndexError> {
+        let path = std::fs::canonicalize(name).map_err(IndexError::Canonicalize)?;
+        let filename: String = path
             .to_str()
-            .unwrap()
+            .ok_or_else(|| IndexError::InvalidPath(path.to_string_lossy().to_string()))?
             .to_string();
-        let url = Url::from_file_path(&filename).unwrap().to_string();
-        let sha256 = calculate_sha256_of_file(name, &url).unwrap();
-        let created = File::open(name)?.metadata()?.created().unwrap();
-        let modified = File::open(name)?.metadata()?.modified().unwrap();
-        let created_dt: DateTime<Utc> = created.into();
-        let modified_dt: DateTime<Utc> = modified.into();
-
-        if !Path::new(&filename).is_file() {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "File does not exist!",
-            ))
-        } else {
-            Ok(IndexFile {
-                url,
-                filename,
-                sha256,
-                created_at: created_dt,
-                modified_at: modified_dt,
-                meta: Vec::new(),
-            })
+        let metadata = std::fs::metadata(&path).map_err(IndexError::Metadata)?;
+        if !metadata.is_file() {
+            return Err(IndexError::NotAFile(filename));
         }
+        let modified = metadata.modified().map_err(IndexError::Metadata)?;
+        // not all file systems record when a file was created
+        let created = metadata.created().unwrap_or(modified);
+        let url = Url::from_file_path(&filename)
+            .map_err(|_| IndexError::InvalidPath(filename.clone()))?
+            .to_string();
+        // reading the start of the file is part of the stat stage
+        let media_type = mediatype::detect_media_type(&path)
+            .map_err(IndexError::Metadata)?
+            .map(String::from);
+        let sha256 = calculate_sha256_of_file(name, &url).map_err(IndexError::Hash)?;
+
+        Ok(IndexFile {
+            url,
+            filename,
+            sha256,
+            created_at: created.into(),
+            modified_at: modified.into(),
+            media_type,
+            meta: Vec::new(),
+        })
     }
 }
 
-/// Hash a file, return result as string
-fn calculate_sha256_of_file(name: &str, extra: &str) -> Result<String> {
+/// Hash a file together with its URL, return result as string
+pub fn calculate_sha256_of_file(name: &str, extra: &str) -> Result<String> {
     let mut hasher = Sha256::new();
     let mut file = File::open(name)?;
     let mut buffer = Vec::new();
@@ -116,3 +144,31 @@ fn calculate_sha256_of_file(name: &str, extra: &str) -> Result<String> {
     let result = hasher.finalize();
     Ok(format!("{:x}", result))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_index_file() {
+        let dir = TempDir::new("fileindex");
+        let path = dir.join("image.jpg");
+        std::fs::write(&path, b"not really a jpeg").unwrap();
+
+        let file = IndexFile::new(path.to_str().unwrap()).unwrap();
+        assert!(file.url.starts_with("file:///"));
+        assert_eq!(file.sha256.len(), 64);
+        assert_eq!(file.media_type, None);
+        assert_eq!(file.value(MEDIA_TYPE_VARIABLE), Some(MetaValue::Null));
+
+        match IndexFile::new(dir.to_str().unwrap()) {
+            Err(IndexError::NotAFile(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match IndexFile::new(dir.join("missing.jpg").to_str().unwrap()) {
+            Err(IndexError::Canonicalize(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,544 @@
+//! Enumerate the files to index below a number of locations.
+//!
+//! Glob patterns match either the name of a file or directory, or its path
+//! relative to the walked location, so `@eaDir` and `2024/**/*.jpg` both work.
+//! Excluded directories are not descended into.
+//!
+//! When no extensions are given, all files are returned and marked so that
+//! only photos and videos are indexed. Their type is detected from their
+//! contents when they are hashed, so that walking does not read any file.
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{error, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::mediatype;
+
+/// Name of the file listing patterns to ignore in a directory and below it
+pub const IGNORE_FILE: &str = ".photocatignore";
+
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Only files matching one of these patterns (all files if empty)
+    pub include: Vec<String>,
+    /// Skip files and directories matching any of these patterns
+    pub exclude: Vec<String>,
+    /// Lowercase extensions of files to return. When empty, photos and videos
+    /// are detected from their contents
+    pub extensions: Vec<String>,
+    /// Maximum depth below each location
+    pub max_depth: Option<usize>,
+    /// Descend into symlinked directories
+    pub follow_links: bool,
+    /// Skip files and directories whose name starts with a dot
+    pub skip_hidden: bool,
+}
+
+/// A file to index, with what was learned about it while walking
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundFile {
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Only index the file if its contents are a photo or video, because no
+    /// extensions were given
+    pub media_only: bool,
+}
+
+impl From<String> for FoundFile {
+    /// A file that was not found by walking, its size is read from the file
+    /// system (0 if it cannot be read)
+    fn from(path: String) -> FoundFile {
+        let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        FoundFile {
+            path,
+            size,
+            media_only: false,
+        }
+    }
+}
+
+impl FoundFile {
+    /// False if the file is only indexed as a photo or video and its contents
+    /// are neither. Reads the start of the file in that case. Files that
+    /// cannot be read are kept, so that indexing reports them.
+    pub fn is_indexed(&self) -> bool {
+        if !self.media_only {
+            return true;
+        }
+        match mediatype::detect_media_type(Path::new(&self.path)) {
+            Ok(media_type) => media_type.is_some(),
+            Err(e) => {
+                warn!("Cannot detect type of {}: {}", self.path, e);
+                true
+            }
+        }
+    }
+}
+
+struct Filters {
+    include: GlobSet,
+    exclude: GlobSet,
+    options: WalkOptions,
+}
+
+/// Patterns from a `.photocatignore` file. Lines starting with `#` are
+/// comments, patterns ending in `/` only match directories.
+struct IgnoreRules {
+    any: GlobSet,
+    dirs_only: GlobSet,
+}
+
+impl IgnoreRules {
+    /// Read the ignore file of a directory, if it has one. Invalid patterns
+    /// are skipped with a warning.
+    fn load(dir: &Path) -> Option<IgnoreRules> {
+        let path = dir.join(IGNORE_FILE);
+        let contents = std::fs::read_to_string(&path).ok()?;
+        let mut any = GlobSetBuilder::new();
+        let mut dirs_only = GlobSetBuilder::new();
+        for (i, line) in contents.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (builder, pattern) = match line.strip_suffix('/') {
+                Some(dir) => (&mut dirs_only, dir),
+                None => (&mut any, line),
+            };
+            match Glob::new(pattern.trim_start_matches('/')) {
+                Ok(glob) => {
+                    builder.add(glob);
+                }
+                Err(e) => warn!(
+                    "Ignoring invalid pattern on line {} of {}: {}",
+                    i + 1,
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        // a set of valid globs always builds
+        Some(IgnoreRules {
+            any: any.build().ok()?,
+            dirs_only: dirs_only.build().ok()?,
+        })
+    }
+
+    fn matches(&self, name: &str, relative: &Path, is_dir: bool) -> bool {
+        matches(&self.any, name, relative) || (is_dir && matches(&self.dirs_only, name, relative))
+    }
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+fn matches(set: &GlobSet, name: &str, relative: &Path) -> bool {
+    set.is_match(name) || set.is_match(relative)
+}
+
+/// True if the entry should be returned or descended into
+fn keep_entry(
+    entry: &DirEntry,
+    root: &Path,
+    filters: &Filters,
+    ignores: &mut HashMap<PathBuf, Option<IgnoreRules>>,
+) -> bool {
+    if entry.depth() == 0 {
+        return true;
+    }
+    keep_path(
+        entry.path(),
+        entry.file_type().is_dir(),
+        root,
+        filters,
+        ignores,
+    )
+}
+
+/// True if a file or directory below a location is not skipped
+fn keep_path(
+    path: &Path,
+    is_dir: bool,
+    root: &Path,
+    filters: &Filters,
+    ignores: &mut HashMap<PathBuf, Option<IgnoreRules>>,
+) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    if filters.options.skip_hidden && name.starts_with('.') {
+        return false;
+    }
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    if matches(&filters.exclude, &name, relative) {
+        return false;
+    }
+    for dir in path.ancestors().skip(1) {
+        if !dir.starts_with(root) {
+            break;
+        }
+        let rules = ignores
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| IgnoreRules::load(dir));
+        if let Some(rules) = rules {
+            let relative = path.strip_prefix(dir).unwrap_or(path);
+            if rules.matches(&name, relative, is_dir) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// The size of a file that was not skipped while walking if it may be
+/// indexed, without reading it
+fn file_size(path: &Path, root: &Path, filters: &Filters) -> Option<u64> {
+    let size = match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return None,
+    };
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    if !filters.options.include.is_empty() && !matches(&filters.include, &name, relative) {
+        return None;
+    }
+    if filters.options.extensions.is_empty() {
+        return Some(size);
+    }
+    let extension = path.extension().and_then(|ext| ext.to_str())?;
+    filters
+        .options
+        .extensions
+        .contains(&extension.to_lowercase())
+        .then_some(size)
+}
+
+/// The file to index at a path that was not skipped while walking, if any.
+/// The file is not read.
+fn found_file(path: &Path, root: &Path, filters: &Filters) -> Option<FoundFile> {
+    let size = file_size(path, root, filters)?;
+    let path_str = path.to_str()?;
+    Some(FoundFile {
+        path: String::from(path_str),
+        size,
+        media_only: filters.options.extensions.is_empty(),
+    })
+}
+
+/// Decides for single paths whether walking a location would return them, for
+/// files that change after the location was walked
+pub struct PathFilter {
+    filters: Filters,
+}
+
+impl PathFilter {
+    /// Fails if a pattern is invalid
+    pub fn new(options: WalkOptions) -> Result<PathFilter, globset::Error> {
+        Ok(PathFilter {
+            filters: Filters {
+                include: build_globset(&options.include)?,
+                exclude: build_globset(&options.exclude)?,
+                options,
+            },
+        })
+    }
+
+    /// The file at `path` if `walk_files` on `root` would return it.
+    /// `.photocatignore` files are read again on each call.
+    pub fn accepts(&self, root: &Path, path: &Path) -> Option<FoundFile> {
+        let relative = path.strip_prefix(root).ok()?;
+        let depth = relative.components().count();
+        if depth == 0 {
+            return found_file(path, root, &self.filters);
+        }
+        if matches!(self.filters.options.max_depth, Some(max_depth) if depth > max_depth) {
+            return None;
+        }
+        let mut ignores = HashMap::new();
+        // the file and every directory between it and the location must be kept
+        let mut below = root.to_path_buf();
+        for (i, component) in relative.components().enumerate() {
+            below.push(component);
+            if !keep_path(&below, i + 1 < depth, root, &self.filters, &mut ignores) {
+                return None;
+            }
+        }
+        found_file(path, root, &self.filters)
+    }
+}
+
+/// Walk all locations, returning the files and directories that are not
+/// skipped, with the location they were found in
+fn walk_entries(
+    locations: &[String],
+    options: WalkOptions,
+) -> Result<impl Iterator<Item = (Arc<Filters>, PathBuf, DirEntry)>, globset::Error> {
+    let filters = Arc::new(Filters {
+        include: build_globset(&options.include)?,
+        exclude: build_globset(&options.exclude)?,
+        options,
+    });
+    let locations: Vec<String> = locations.to_vec();
+    Ok(locations.into_iter().flat_map(move |location| {
+        let root = PathBuf::from(&location);
+        let mut walker = WalkDir::new(&root).follow_links(filters.options.follow_links);
+        if let Some(max_depth) = filters.options.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        let walk_filters = filters.clone();
+        let walk_root = root.clone();
+        let mut ignores = HashMap::new();
+        let filters = filters.clone();
+        walker
+            .into_iter()
+            .filter_entry(move |entry| keep_entry(entry, &walk_root, &walk_filters, &mut ignores))
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    error!("Cannot read {}", e);
+                    None
+                }
+            })
+            .map(move |entry| (filters.clone(), root.clone(), entry))
+    }))
+}
+
+/// Walk all locations, returning the files to index. Fails if a pattern is
+/// invalid.
+pub fn walk_files(
+    locations: &[String],
+    options: WalkOptions,
+) -> Result<impl Iterator<Item = FoundFile>, globset::Error> {
+    Ok(walk_entries(locations, options)?
+        .filter_map(|(filters, root, entry)| found_file(entry.path(), &root, &filters)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn test_tree(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("filewalk-{}", name));
+        for file in [
+            "a.jpg",
+            "notes.txt",
+            "2024/b.JPG",
+            "2024/@eaDir/b.jpg",
+            "2024/raw/c.nef",
+            "2024/raw/previews/c.jpg",
+            ".thumbnails/a.jpg",
+            "node_modules/x.jpg",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        dir
+    }
+
+    fn walk(dir: &Path, options: WalkOptions) -> Vec<String> {
+        let options = WalkOptions {
+            extensions: vec![String::from("jpg"), String::from("nef")],
+            ..options
+        };
+        let mut files: Vec<String> = walk_files(&[dir.to_str().unwrap().to_string()], options)
+            .unwrap()
+            .map(|f| {
+                Path::new(&f.path)
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_walk_all() {
+        let dir = test_tree("all");
+        assert_eq!(
+            walk(&dir, WalkOptions::default()),
+            vec![
+                ".thumbnails/a.jpg",
+                "2024/@eaDir/b.jpg",
+                "2024/b.JPG",
+                "2024/raw/c.nef",
+                "2024/raw/previews/c.jpg",
+                "a.jpg",
+                "node_modules/x.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_walk_options() {
+        let dir = test_tree("options");
+        let options = WalkOptions {
+            exclude: vec![String::from("@eaDir"), String::from("node_modules")],
+            skip_hidden: true,
+            max_depth: Some(3),
+            ..WalkOptions::default()
+        };
+        assert_eq!(
+            walk(&dir, options),
+            vec!["2024/b.JPG", "2024/raw/c.nef", "a.jpg"]
+        );
+
+        let options = WalkOptions {
+            include: vec![String::from("2024/**/*.nef")],
+            ..WalkOptions::default()
+        };
+        assert_eq!(walk(&dir, options), vec!["2024/raw/c.nef"]);
+    }
+
+    #[test]
+    fn test_walk_ignore_file() {
+        let dir = test_tree("ignore");
+        std::fs::write(
+            dir.join(IGNORE_FILE),
+            "# caches\n@eaDir/\n.thumbnails/\nnode_modules\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("2024/raw").join(IGNORE_FILE), "previews/\n").unwrap();
+        assert_eq!(
+            walk(&dir, WalkOptions::default()),
+            vec!["2024/b.JPG", "2024/raw/c.nef", "a.jpg"]
+        );
+    }
+
+    #[test]
+    fn test_walk_ignore_file_with_invalid_pattern() {
+        let dir = test_tree("ignore-invalid");
+        std::fs::write(dir.join(IGNORE_FILE), "[\nnode_modules\n@eaDir/\n").unwrap();
+        assert_eq!(
+            walk(&dir, WalkOptions::default()),
+            vec![
+                ".thumbnails/a.jpg",
+                "2024/b.JPG",
+                "2024/raw/c.nef",
+                "2024/raw/previews/c.jpg",
+                "a.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_walk_media_only() {
+        let dir = test_tree("detect");
+        std::fs::write(dir.join("IMG_0001"), [0xFF, 0xD8, 0xFF, 0xE1]).unwrap();
+        std::fs::write(dir.join("photo.jpeg"), [0xFF, 0xD8, 0xFF, 0xE0]).unwrap();
+        let mut files: Vec<FoundFile> = walk_files(
+            &[dir.to_str().unwrap().to_string()],
+            WalkOptions {
+                max_depth: Some(1),
+                ..WalkOptions::default()
+            },
+        )
+        .unwrap()
+        .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        // files are not read while walking
+        assert_eq!(files.len(), 4);
+        assert!(files.iter().all(|f| f.media_only));
+        assert_eq!(files[0].size, 4);
+        // empty files and text files are not indexed, names do not matter
+        let indexed: Vec<&str> = files
+            .iter()
+            .filter(|f| f.is_indexed())
+            .map(|f| Path::new(&f.path).file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(indexed, vec!["IMG_0001", "photo.jpeg"]);
+    }
+
+    #[test]
+    fn test_path_filter_agrees_with_walk() {
+        let dir = test_tree("filter");
+        std::fs::write(dir.join(IGNORE_FILE), "@eaDir/\n").unwrap();
+        let options = WalkOptions {
+            exclude: vec![String::from("node_modules")],
+            extensions: vec![String::from("jpg"), String::from("nef")],
+            skip_hidden: true,
+            max_depth: Some(3),
+            ..WalkOptions::default()
+        };
+        let walked = walk(&dir, options.clone());
+        let filter = PathFilter::new(options).unwrap();
+        for file in [
+            "a.jpg",
+            "notes.txt",
+            "2024/b.JPG",
+            "2024/@eaDir/b.jpg",
+            "2024/raw/c.nef",
+            "2024/raw/previews/c.jpg",
+            ".thumbnails/a.jpg",
+            "node_modules/x.jpg",
+        ] {
+            assert_eq!(
+                filter.accepts(&dir, &dir.join(file)).is_some(),
+                walked.contains(&String::from(file)),
+                "{}",
+                file
+            );
+        }
+        assert!(filter
+            .accepts(&dir.join("2024"), &dir.join("a.jpg"))
+            .is_none());
+        assert!(filter.accepts(&dir, &dir.join("missing.jpg")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_count_matches_processed() {
+        use crate::processing::{consume_concurrently, precount, ConcurrencyOptions};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // photos, other files and directories, and no extensions
+        let dir = test_tree("count");
+        std::fs::write(dir.join("2024/notes.md"), b"# notes").unwrap();
+        let locations = [dir.to_str().unwrap().to_string()];
+        let total = precount(
+            walk_files(&locations, WalkOptions::default()).unwrap(),
+            false,
+        );
+
+        async fn count(_file: FoundFile, processed: Arc<AtomicUsize>) {
+            processed.fetch_add(1, Ordering::SeqCst);
+        }
+        let processed = Arc::new(AtomicUsize::new(0));
+        consume_concurrently(
+            walk_files(&locations, WalkOptions::default()).unwrap(),
+            count,
+            &processed,
+            false,
+            Some(total),
+            ConcurrencyOptions::default(),
+        )
+        .await;
+        assert_eq!(total, 9);
+        assert_eq!(processed.load(Ordering::SeqCst), total);
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let options = WalkOptions {
+            exclude: vec![String::from("[")],
+            ..WalkOptions::default()
+        };
+        assert!(walk_files(&[String::from(".")], options).is_err());
+    }
+}
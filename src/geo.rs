@@ -0,0 +1,297 @@
+//! Locations of photos: filtering by distance and a density map.
+//!
+//! GPS coordinates are read from the metadata by `meta.sql` into the decimal
+//! degree columns `GPSLatitude` and `GPSLongitude` (negative for south and
+//! west) and `GPSAltitude` in metres (negative below sea level).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::fileindex::{IndexFile, MetaValue};
+use crate::summarystats::FileIndexSummarizer;
+
+/// Mean radius of the earth
+pub const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Photos taken within a distance of a point
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Near {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_km: f64,
+}
+
+impl FromStr for Near {
+    type Err = String;
+
+    /// Parse `lat,lon,radius_km`, e.g. `52.52,13.40,25`
+    fn from_str(s: &str) -> Result<Near, String> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| format!("invalid number in '{}': {}", s, e))?;
+        let [latitude, longitude, radius_km] = values[..] else {
+            return Err(format!("expected lat,lon,radius_km but got '{}'", s));
+        };
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(format!("coordinates out of range in '{}'", s));
+        }
+        if radius_km.is_nan() || radius_km < 0.0 {
+            return Err(format!("radius must not be negative in '{}'", s));
+        }
+        Ok(Near {
+            latitude,
+            longitude,
+            radius_km,
+        })
+    }
+}
+
+impl Near {
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        distance_km(self.latitude, self.longitude, latitude, longitude) <= self.radius_km
+    }
+}
+
+/// Great circle distance between two points in decimal degrees
+pub fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// Latitude and longitude of a file, if it has both
+pub fn file_location(f: &IndexFile) -> Option<(f64, f64)> {
+    let degrees = |name| match f.value(name) {
+        Some(MetaValue::Float(value)) => Some(value),
+        Some(MetaValue::Int(value)) => Some(value as f64),
+        _ => None,
+    };
+    Some((degrees("GPSLatitude")?, degrees("GPSLongitude")?))
+}
+
+/// Characters for increasing numbers of photos in a grid cell
+const DENSITY: &[char] = &['.', ':', '-', '=', '+', '*', '#', '%', '@'];
+
+/// Widest and highest map to draw; larger areas are drawn with coarser cells
+const MAX_CELLS: i64 = 100;
+
+/// Counts photos in cells of a latitude/longitude grid and draws them as a
+/// density map, north at the top
+pub struct GeoGridSummary {
+    cell_degrees: f64,
+    counts: HashMap<(i64, i64), usize>,
+    without_location: usize,
+}
+
+impl GeoGridSummary {
+    pub fn new(cell_degrees: f64) -> GeoGridSummary {
+        GeoGridSummary {
+            cell_degrees: if cell_degrees > 0.0 {
+                cell_degrees
+            } else {
+                1.0
+            },
+            counts: HashMap::new(),
+            without_location: 0,
+        }
+    }
+
+    fn cell(&self, latitude: f64, longitude: f64) -> (i64, i64) {
+        (
+            (latitude / self.cell_degrees).floor() as i64,
+            (longitude / self.cell_degrees).floor() as i64,
+        )
+    }
+
+    /// Cells merged so that the map is at most `MAX_CELLS` wide and high,
+    /// and the number of original cells per merged cell
+    fn merged_counts(&self) -> (HashMap<(i64, i64), usize>, i64) {
+        let span = |axis: fn(&(i64, i64)) -> i64| {
+            let min = self.counts.keys().map(axis).min().unwrap_or(0);
+            let max = self.counts.keys().map(axis).max().unwrap_or(0);
+            max - min
+        };
+        let factor = span(|c| c.0).max(span(|c| c.1)) / MAX_CELLS + 1;
+        let mut merged = HashMap::new();
+        for ((row, col), count) in &self.counts {
+            *merged
+                .entry((row.div_euclid(factor), col.div_euclid(factor)))
+                .or_insert(0) += count;
+        }
+        (merged, factor)
+    }
+}
+
+impl FileIndexSummarizer for GeoGridSummary {
+    fn add(&mut self, f: &IndexFile) {
+        match file_location(f) {
+            Some((latitude, longitude)) => {
+                *self
+                    .counts
+                    .entry(self.cell(latitude, longitude))
+                    .or_insert(0) += 1
+            }
+            None => self.without_location += 1,
+        }
+    }
+}
+
+impl fmt::Display for GeoGridSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.counts.is_empty() {
+            return writeln!(
+                f,
+                "No photos with GPS location ({} without)",
+                self.without_location
+            );
+        }
+        let (counts, factor) = self.merged_counts();
+        let degrees = self.cell_degrees * factor as f64;
+        let min_row = counts.keys().map(|c| c.0).min().unwrap();
+        let max_row = counts.keys().map(|c| c.0).max().unwrap();
+        let min_col = counts.keys().map(|c| c.1).min().unwrap();
+        let max_col = counts.keys().map(|c| c.1).max().unwrap();
+        let max_count = *counts.values().max().unwrap();
+        // logarithmic scale, so that a few busy places do not hide the rest
+        let symbol = |count: usize| {
+            let scale = (count as f64).ln_1p() / (max_count as f64).ln_1p();
+            DENSITY[((scale * (DENSITY.len() - 1) as f64).round() as usize).min(DENSITY.len() - 1)]
+        };
+
+        writeln!(
+            f,
+            "Photo locations, {} degree cells, north at the top",
+            degrees
+        )?;
+        for row in (min_row..=max_row).rev() {
+            let line: String = (min_col..=max_col)
+                .map(|col| counts.get(&(row, col)).map(|c| symbol(*c)).unwrap_or(' '))
+                .collect();
+            writeln!(
+                f,
+                "{:>8.2} |{}",
+                (row + 1) as f64 * degrees,
+                line.trim_end()
+            )?;
+        }
+        writeln!(
+            f,
+            "{:>8} +{}",
+            "",
+            "-".repeat((max_col - min_col + 1) as usize)
+        )?;
+        writeln!(
+            f,
+            "{:>8}  {:.2} to {:.2} east",
+            "",
+            min_col as f64 * degrees,
+            (max_col + 1) as f64 * degrees
+        )?;
+        writeln!(
+            f,
+            "{} photos with location, from '{}' for one to '{}' for {} per cell; {} without location",
+            counts.values().sum::<usize>(),
+            symbol(1),
+            DENSITY[DENSITY.len() - 1],
+            max_count,
+            self.without_location
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileindex::MetaVariable;
+    use chrono::Utc;
+
+    fn photo(location: Option<(f64, f64)>) -> IndexFile {
+        let meta = match location {
+            Some((latitude, longitude)) => vec![
+                MetaVariable {
+                    name: String::from("GPSLatitude"),
+                    value: MetaValue::Float(latitude),
+                },
+                MetaVariable {
+                    name: String::from("GPSLongitude"),
+                    value: MetaValue::Float(longitude),
+                },
+            ],
+            None => Vec::new(),
+        };
+        IndexFile {
+            filename: String::from("photo.jpg"),
+            url: String::from("file:///photo.jpg"),
+            sha256: String::new(),
+            created_at: Utc::now(),
+            modified_at: Utc::now(),
+            media_type: None,
+            meta,
+        }
+    }
+
+    #[test]
+    fn test_parse_near() {
+        assert_eq!(
+            "52.52, 13.40,25".parse::<Near>(),
+            Ok(Near {
+                latitude: 52.52,
+                longitude: 13.4,
+                radius_km: 25.0
+            })
+        );
+        assert!("52.52,13.40".parse::<Near>().is_err());
+        assert!("north,13.40,5".parse::<Near>().is_err());
+        assert!("95,13.40,5".parse::<Near>().is_err());
+        assert!("52.52,13.40,-1".parse::<Near>().is_err());
+    }
+
+    #[test]
+    fn test_distance() {
+        // Berlin to Paris is about 878 km
+        let d = distance_km(52.52, 13.405, 48.8566, 2.3522);
+        assert!((d - 878.0).abs() < 5.0, "{}", d);
+        assert_eq!(distance_km(10.0, 20.0, 10.0, 20.0), 0.0);
+        let near: Near = "52.52,13.405,10".parse().unwrap();
+        assert!(near.contains(52.55, 13.40));
+        assert!(!near.contains(48.8566, 2.3522));
+    }
+
+    #[test]
+    fn test_grid_summary() {
+        let mut grid = GeoGridSummary::new(1.0);
+        for _ in 0..10 {
+            grid.add(&photo(Some((52.5, 13.4))));
+        }
+        grid.add(&photo(Some((48.9, 2.3))));
+        grid.add(&photo(Some((-33.9, 151.2))));
+        grid.add(&photo(None));
+        assert_eq!(grid.counts[&(52, 13)], 10);
+        assert_eq!(grid.counts[&(-34, 151)], 1);
+        assert_eq!(grid.without_location, 1);
+
+        let map = grid.to_string();
+        assert!(map.contains('@'));
+        assert!(map.contains("12 photos with location"));
+        assert!(map.contains("1 without location"));
+        // the map is coarsened to fit the terminal
+        assert!(map.lines().all(|line| line.chars().count() < 120));
+
+        // also when the photos are spread from north to south
+        let mut grid = GeoGridSummary::new(0.1);
+        grid.add(&photo(Some((78.2, 15.6))));
+        grid.add(&photo(Some((-77.8, 166.7))));
+        grid.add(&photo(Some((0.0, 160.0))));
+        let (counts, factor) = grid.merged_counts();
+        assert_eq!(factor, 16);
+        let rows =
+            counts.keys().map(|c| c.0).max().unwrap() - counts.keys().map(|c| c.0).min().unwrap();
+        assert!(rows < MAX_CELLS, "{}", rows);
+        assert!(grid.to_string().lines().count() < MAX_CELLS as usize + 5);
+    }
+}
@@ -0,0 +1,306 @@
+//! Offline reverse geocoding: the country, region and nearest city of a GPS
+//! location, without network access.
+//!
+//! Places come from a gazetteer. A small one with capitals and large cities is
+//! built in; a GeoNames cities extract (e.g. `cities15000.txt` from
+//! https://download.geonames.org/export/dump/) gives finer results and can be
+//! set in the `[geocoding]` section of `photocat.toml`. The country and region
+//! are those of the nearest place, so near borders they can be wrong when the
+//! gazetteer is coarse.
+//!
+//! Places are kept in a k-d tree over points on the unit sphere, so that
+//! finding the nearest one takes logarithmic time and works across the date
+//! line and near the poles.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::geo::EARTH_RADIUS_KM;
+
+/// Places of the built-in gazetteer: name, latitude, longitude, country code,
+/// region and population, separated by tabs
+const BUILTIN_GAZETTEER: &str = include_str!("gazetteer.tsv");
+
+/// ISO 3166 country codes and names, separated by tabs
+const COUNTRIES: &str = include_str!("countries.tsv");
+
+/// A populated place
+#[derive(Debug, Clone, PartialEq)]
+pub struct Place {
+    pub name: String,
+    /// First-level administrative division, such as a state or province
+    pub region: Option<String>,
+    /// ISO 3166 alpha-2 code
+    pub country_code: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub population: u64,
+}
+
+/// Places with a spatial index for nearest-place lookups
+pub struct Gazetteer {
+    places: Vec<Place>,
+    /// Unit vectors of the places with their index, ordered as an implicit
+    /// k-d tree: the middle element of each range splits it on the axis
+    /// `depth % 3`
+    tree: Vec<([f64; 3], usize)>,
+    countries: HashMap<String, String>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Point on the unit sphere for a location in decimal degrees
+fn unit_vector(latitude: f64, longitude: f64) -> [f64; 3] {
+    let (lat, lon) = (latitude.to_radians(), longitude.to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+fn squared_distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    (0..3).map(|i| (a[i] - b[i]).powi(2)).sum()
+}
+
+/// Order points so that each range is split by its middle element
+fn build_tree(nodes: &mut [([f64; 3], usize)], depth: usize) {
+    if nodes.len() <= 1 {
+        return;
+    }
+    let axis = depth % 3;
+    let mid = nodes.len() / 2;
+    nodes.select_nth_unstable_by(mid, |a, b| a.0[axis].total_cmp(&b.0[axis]));
+    let (left, right) = nodes.split_at_mut(mid);
+    build_tree(left, depth + 1);
+    build_tree(&mut right[1..], depth + 1);
+}
+
+/// Update `best` with the index and squared distance of the point nearest to
+/// `target`, skipping subtrees that cannot contain a nearer one
+fn search_tree(
+    nodes: &[([f64; 3], usize)],
+    depth: usize,
+    target: &[f64; 3],
+    best: &mut Option<(usize, f64)>,
+) {
+    if nodes.is_empty() {
+        return;
+    }
+    let mid = nodes.len() / 2;
+    let (point, index) = &nodes[mid];
+    let distance = squared_distance(point, target);
+    if best.is_none_or(|(_, d)| distance < d) {
+        *best = Some((*index, distance));
+    }
+    let axis = depth % 3;
+    let offset = target[axis] - point[axis];
+    let (near, far) = if offset < 0.0 {
+        (&nodes[..mid], &nodes[mid + 1..])
+    } else {
+        (&nodes[mid + 1..], &nodes[..mid])
+    };
+    search_tree(near, depth + 1, target, best);
+    if best.is_none_or(|(_, d)| offset * offset < d) {
+        search_tree(far, depth + 1, target, best);
+    }
+}
+
+impl Gazetteer {
+    /// Index a list of places
+    pub fn new(places: Vec<Place>) -> Gazetteer {
+        let mut tree: Vec<([f64; 3], usize)> = places
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (unit_vector(p.latitude, p.longitude), i))
+            .collect();
+        build_tree(&mut tree, 0);
+        let countries = COUNTRIES
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(code, name)| (code.to_string(), name.to_string()))
+            .collect();
+        Gazetteer {
+            places,
+            tree,
+            countries,
+        }
+    }
+
+    /// The gazetteer embedded in photocat
+    pub fn builtin() -> Gazetteer {
+        let places = BUILTIN_GAZETTEER
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.starts_with('#') && !line.trim().is_empty())
+            .map(|(i, line)| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                parse_place(&fields, [0, 1, 2, 3, 4, 5])
+                    .unwrap_or_else(|| panic!("Invalid built-in gazetteer line {}", i + 1))
+            })
+            .collect();
+        Gazetteer::new(places)
+    }
+
+    /// Load a GeoNames cities file, optionally with the names of the regions
+    /// from `admin1CodesASCII.txt`
+    pub fn load_geonames(cities: &Path, admin1: Option<&Path>) -> io::Result<Gazetteer> {
+        // "US.CA" -> "California"
+        let mut regions = HashMap::new();
+        if let Some(admin1) = admin1 {
+            for line in std::fs::read_to_string(admin1)?.lines() {
+                let mut fields = line.split('\t');
+                if let (Some(code), Some(name)) = (fields.next(), fields.next()) {
+                    regions.insert(code.to_string(), name.to_string());
+                }
+            }
+        }
+
+        let mut places = Vec::new();
+        for (i, line) in std::fs::read_to_string(cities)?.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            // geonameid, name, asciiname, alternatenames, latitude, longitude,
+            // feature class, feature code, country code, cc2, admin1 code, ...,
+            // population
+            let fields: Vec<&str> = line.split('\t').collect();
+            let mut place = parse_place(&fields, [1, 4, 5, 8, 10, 14]).ok_or_else(|| {
+                invalid_data(format!(
+                    "{}:{}: not a GeoNames cities line",
+                    cities.display(),
+                    i + 1
+                ))
+            })?;
+            // without admin1 names the codes, e.g. "05", are not useful
+            place.region = place
+                .region
+                .and_then(|code| regions.get(&format!("{}.{}", place.country_code, code)))
+                .cloned();
+            places.push(place);
+        }
+        Ok(Gazetteer::new(places))
+    }
+
+    pub fn len(&self) -> usize {
+        self.places.len()
+    }
+
+    /// The place nearest to a location in decimal degrees, and its distance
+    pub fn nearest(&self, latitude: f64, longitude: f64) -> Option<(&Place, f64)> {
+        let mut best = None;
+        search_tree(&self.tree, 0, &unit_vector(latitude, longitude), &mut best);
+        best.map(|(index, squared_chord)| {
+            let angle = 2.0 * (squared_chord.sqrt() / 2.0).min(1.0).asin();
+            (&self.places[index], angle * EARTH_RADIUS_KM)
+        })
+    }
+
+    /// English name of a country, or the code if it is not known
+    pub fn country_name<'a>(&'a self, code: &'a str) -> &'a str {
+        self.countries.get(code).map(String::as_str).unwrap_or(code)
+    }
+}
+
+/// Place from the fields at the given positions of name, latitude, longitude,
+/// country code, region and population
+fn parse_place(fields: &[&str], columns: [usize; 6]) -> Option<Place> {
+    let [name, latitude, longitude, country_code, region, population] = columns;
+    let text = |i: usize| fields.get(i).map(|s| s.trim()).filter(|s| !s.is_empty());
+    Some(Place {
+        name: text(name)?.to_string(),
+        region: text(region).map(String::from),
+        country_code: text(country_code)?.to_string(),
+        latitude: text(latitude)?.parse().ok()?,
+        longitude: text(longitude)?.parse().ok()?,
+        population: text(population).and_then(|p| p.parse().ok()).unwrap_or(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::distance_km;
+    use crate::testutil::TempDir;
+
+    /// Nearest place by checking all of them
+    fn brute_force(gazetteer: &Gazetteer, latitude: f64, longitude: f64) -> f64 {
+        gazetteer
+            .places
+            .iter()
+            .map(|p| distance_km(latitude, longitude, p.latitude, p.longitude))
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    #[test]
+    fn test_builtin_gazetteer() {
+        let gazetteer = Gazetteer::builtin();
+        assert!(gazetteer.len() > 400);
+
+        let (place, distance) = gazetteer.nearest(52.5163, 13.3777).unwrap();
+        assert_eq!(place.name, "Berlin");
+        assert_eq!(gazetteer.country_name(&place.country_code), "Germany");
+        assert!(distance < 5.0, "{}", distance);
+
+        // across the date line
+        let (place, _) = gazetteer.nearest(-18.0, -179.9).unwrap();
+        assert_eq!(place.name, "Suva");
+
+        let (place, _) = gazetteer.nearest(37.80, -122.27).unwrap();
+        assert_eq!(place.name, "San Francisco");
+        assert_eq!(place.region.as_deref(), Some("California"));
+        assert_eq!(gazetteer.country_name("XX"), "XX");
+    }
+
+    #[test]
+    fn test_nearest_agrees_with_brute_force() {
+        let gazetteer = Gazetteer::builtin();
+        for lat in (-80..=80).step_by(10) {
+            for lon in (-180..180).step_by(15) {
+                let (lat, lon) = (lat as f64 + 0.3, lon as f64 + 0.7);
+                let (_, distance) = gazetteer.nearest(lat, lon).unwrap();
+                let expected = brute_force(&gazetteer, lat, lon);
+                assert!(
+                    (distance - expected).abs() < 0.01,
+                    "{},{}: {} != {}",
+                    lat,
+                    lon,
+                    distance,
+                    expected
+                );
+            }
+        }
+        assert!(Gazetteer::new(Vec::new()).nearest(0.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_load_geonames() {
+        let dir = TempDir::new("geocode");
+        let cities = dir.join("cities.txt");
+        let admin1 = dir.join("admin1CodesASCII.txt");
+        std::fs::write(
+            &cities,
+            "2950159\tBerlin\tBerlin\tBerlino\t52.52437\t13.41053\tP\tPPLC\tDE\t\t16\t00\t11000\t11000000\t3426354\t\t74\tEurope/Berlin\t2022-01-01\n\
+             2867714\tMunich\tMunich\t\t48.13743\t11.57549\tP\tPPLA\tDE\t\t02\t091\t09162\t09162000\t1260391\t\t524\tEurope/Berlin\t2023-10-12\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &admin1,
+            "DE.16\tBerlin\tBerlin\t2950157\nDE.02\tBavaria\tBavaria\t2951839\n",
+        )
+        .unwrap();
+
+        let gazetteer = Gazetteer::load_geonames(&cities, Some(&admin1)).unwrap();
+        assert_eq!(gazetteer.len(), 2);
+        let (place, _) = gazetteer.nearest(48.0, 11.0).unwrap();
+        assert_eq!(place.name, "Munich");
+        assert_eq!(place.region.as_deref(), Some("Bavaria"));
+        assert_eq!(place.population, 1260391);
+
+        let gazetteer = Gazetteer::load_geonames(&cities, None).unwrap();
+        assert_eq!(gazetteer.nearest(48.0, 11.0).unwrap().0.region, None);
+
+        std::fs::write(&cities, "not a cities file\n").unwrap();
+        assert!(Gazetteer::load_geonames(&cities, None).is_err());
+    }
+}
@@ -0,0 +1,594 @@
+//! Geotagging photos without GPS from the tracks of a GPS logger.
+//!
+//! Track points are read from GPX files. The time a photo was taken comes
+//! from the camera clock, which has no time zone and is often a little off,
+//! so it is corrected with a clock offset and the UTC offset the camera was
+//! set to before looking it up in the tracks. The position is interpolated
+//! between the track points before and after that time.
+//!
+//! Inferred positions are stored under `inferred_location` in the JSON
+//! metadata of each file and read into the `InferredLatitude`,
+//! `InferredLongitude`, `InferredAltitude` and `InferredTrack` columns by
+//! `meta.sql`. GPS coordinates from the camera are never changed.
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeDelta, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::Path;
+
+use crate::fileindex::{IndexFile, MetaValue};
+use crate::geo;
+
+/// Photos whose candidate positions from different tracks are farther apart
+/// than this are ambiguous
+pub const MAX_SPREAD_KM: f64 = 1.0;
+
+/// A point of a GPX track
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackPoint {
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub elevation: Option<f64>,
+}
+
+/// The track segments of a GPX file, each sorted by time
+#[derive(Debug, Clone)]
+pub struct Track {
+    /// File the track was read from
+    pub name: String,
+    pub segments: Vec<Vec<TrackPoint>>,
+}
+
+/// Why a GPX file cannot be used
+#[derive(Debug)]
+pub enum GpxError {
+    Read(String, std::io::Error),
+    Parse(String, String),
+}
+
+impl fmt::Display for GpxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GpxError::Read(path, e) => write!(f, "Cannot read {}: {}", path, e),
+            GpxError::Parse(path, e) => write!(f, "Cannot parse {}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for GpxError {}
+
+/// Latitude and longitude of a `trkpt` element
+fn coordinates(tag: &BytesStart) -> Result<(f64, f64), String> {
+    let mut latitude = None;
+    let mut longitude = None;
+    for attribute in tag.attributes() {
+        let attribute = attribute.map_err(|e| e.to_string())?;
+        let value = attribute.unescape_value().map_err(|e| e.to_string())?;
+        match attribute.key.local_name().as_ref() {
+            b"lat" => latitude = value.trim().parse::<f64>().ok(),
+            b"lon" => longitude = value.trim().parse::<f64>().ok(),
+            _ => {}
+        }
+    }
+    let coordinate = |value: Option<f64>, name| {
+        value.ok_or_else(|| format!("trkpt without {}: <{}>", name, String::from_utf8_lossy(tag)))
+    };
+    Ok((coordinate(latitude, "lat")?, coordinate(longitude, "lon")?))
+}
+
+impl Track {
+    /// Read the `trkpt` elements of a GPX document. Points without a time
+    /// cannot be matched to photos and are skipped. Only the `ele` and `time`
+    /// elements of the point itself are used, not those in its extensions.
+    pub fn parse(name: &str, xml: &str) -> Result<Track, String> {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+        let mut segments = Vec::new();
+        let mut segment = Vec::new();
+        // local names of the open elements, without namespace prefix
+        let mut open: Vec<Vec<u8>> = Vec::new();
+        let mut point: Option<(f64, f64)> = None;
+        let mut elevation = None;
+        let mut time = None;
+        let mut text = String::new();
+        loop {
+            let event = reader
+                .read_event()
+                .map_err(|e| format!("at byte {}: {}", reader.buffer_position(), e))?;
+            match event {
+                Event::Start(tag) => {
+                    if tag.local_name().as_ref() == b"trkpt" {
+                        point = Some(coordinates(&tag)?);
+                        elevation = None;
+                        time = None;
+                    }
+                    open.push(tag.local_name().as_ref().to_vec());
+                    text.clear();
+                }
+                Event::Text(t) => text.push_str(&t.unescape().map_err(|e| e.to_string())?),
+                Event::CData(t) => text.push_str(&String::from_utf8_lossy(&t)),
+                Event::End(_) => {
+                    let element = open.pop().unwrap_or_default();
+                    let in_point = open.last().is_some_and(|parent| parent == b"trkpt");
+                    match element.as_slice() {
+                        b"ele" if in_point => elevation = text.trim().parse().ok(),
+                        b"time" if in_point => {
+                            let text = text.trim();
+                            time = Some(
+                                DateTime::parse_from_rfc3339(text)
+                                    .map_err(|e| format!("invalid time '{}': {}", text, e))?
+                                    .with_timezone(&Utc),
+                            )
+                        }
+                        b"trkpt" => {
+                            if let (Some((latitude, longitude)), Some(time)) = (point.take(), time)
+                            {
+                                segment.push(TrackPoint {
+                                    time,
+                                    latitude,
+                                    longitude,
+                                    elevation,
+                                });
+                            }
+                        }
+                        b"trkseg" if !segment.is_empty() => {
+                            segments.push(std::mem::take(&mut segment))
+                        }
+                        _ => {}
+                    }
+                    text.clear();
+                }
+                Event::Eof => break,
+                // including points without children, which have no time
+                _ => {}
+            }
+        }
+        if !segment.is_empty() {
+            segments.push(segment);
+        }
+        for segment in &mut segments {
+            segment.sort_by_key(|p| p.time);
+        }
+        Ok(Track {
+            name: name.to_string(),
+            segments,
+        })
+    }
+
+    /// Read a GPX file
+    pub fn load(path: &Path) -> Result<Track, GpxError> {
+        let name = path.display().to_string();
+        let xml = std::fs::read_to_string(path).map_err(|e| GpxError::Read(name.clone(), e))?;
+        Track::parse(&name, &xml).map_err(|e| GpxError::Parse(name, e))
+    }
+
+    pub fn points(&self) -> usize {
+        self.segments.iter().map(Vec::len).sum()
+    }
+}
+
+/// How the camera clock relates to UTC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraClock {
+    /// Seconds the camera clock was ahead of the true time, negative if it was behind
+    pub offset_seconds: i64,
+    /// UTC offset of the time zone the camera clock was set to
+    pub timezone: FixedOffset,
+}
+
+impl Default for CameraClock {
+    fn default() -> Self {
+        CameraClock {
+            offset_seconds: 0,
+            timezone: FixedOffset::east_opt(0).unwrap(),
+        }
+    }
+}
+
+impl CameraClock {
+    /// UTC time of a camera time stamp
+    pub fn to_utc(&self, taken: NaiveDateTime) -> DateTime<Utc> {
+        let local = taken - TimeDelta::seconds(self.offset_seconds);
+        (local - self.timezone).and_utc()
+    }
+}
+
+/// Position of a photo on a track
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+    /// GPX file of the track
+    pub track: String,
+    /// Seconds to the nearest track point
+    pub gap_seconds: i64,
+}
+
+impl Position {
+    /// The position as stored in the JSON metadata of a photo taken at `time`
+    pub fn to_json(&self, time: &DateTime<Utc>) -> Value {
+        json!({
+            "latitude": self.latitude,
+            "longitude": self.longitude,
+            "altitude": self.altitude,
+            "track": self.track,
+            "time": time.to_rfc3339(),
+            "gap_seconds": self.gap_seconds,
+        })
+    }
+}
+
+/// Result of looking up a time in the tracks
+#[derive(Debug, Clone, PartialEq)]
+pub enum Match {
+    Located(Position),
+    /// No track has a point within the maximum gap
+    OutOfRange,
+    /// Tracks disagree by more than `MAX_SPREAD_KM`, nearest in time first
+    Ambiguous(Vec<Position>),
+}
+
+/// Position on a segment at a time: interpolated between the points around
+/// it if they are at most `max_gap` seconds apart, otherwise the nearest
+/// point within `max_gap` seconds
+fn segment_position(
+    segment: &[TrackPoint],
+    time: DateTime<Utc>,
+    max_gap: i64,
+) -> Option<(f64, f64, Option<f64>, i64)> {
+    let after = segment.partition_point(|p| p.time <= time);
+    let before = after.checked_sub(1).map(|i| &segment[i]);
+    let next = segment.get(after);
+    let seconds = |a: DateTime<Utc>, b: DateTime<Utc>| (b - a).num_seconds();
+
+    if let (Some(a), Some(b)) = (before, next) {
+        if seconds(a.time, b.time) <= max_gap {
+            let fraction = (time - a.time).num_milliseconds() as f64
+                / (b.time - a.time).num_milliseconds() as f64;
+            // the short way round across the date line
+            let mut d_lon = b.longitude - a.longitude;
+            if d_lon > 180.0 {
+                d_lon -= 360.0;
+            } else if d_lon < -180.0 {
+                d_lon += 360.0;
+            }
+            let mut longitude = a.longitude + fraction * d_lon;
+            if longitude > 180.0 {
+                longitude -= 360.0;
+            } else if longitude < -180.0 {
+                longitude += 360.0;
+            }
+            let altitude = match (a.elevation, b.elevation) {
+                (Some(x), Some(y)) => Some(x + fraction * (y - x)),
+                (x, y) => x.or(y),
+            };
+            return Some((
+                a.latitude + fraction * (b.latitude - a.latitude),
+                longitude,
+                altitude,
+                seconds(a.time, time).min(seconds(time, b.time)),
+            ));
+        }
+    }
+    [
+        before.map(|a| (a, seconds(a.time, time))),
+        next.map(|b| (b, seconds(time, b.time))),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|(_, gap)| *gap <= max_gap)
+    .min_by_key(|(_, gap)| *gap)
+    .map(|(p, gap)| (p.latitude, p.longitude, p.elevation, gap))
+}
+
+/// Find the position at a time in a set of tracks
+pub fn locate(tracks: &[Track], time: DateTime<Utc>, max_gap: i64) -> Match {
+    let mut candidates: Vec<Position> = tracks
+        .iter()
+        .flat_map(|track| {
+            track.segments.iter().filter_map(move |segment| {
+                segment_position(segment, time, max_gap).map(
+                    |(latitude, longitude, altitude, gap_seconds)| Position {
+                        latitude,
+                        longitude,
+                        altitude,
+                        track: track.name.clone(),
+                        gap_seconds,
+                    },
+                )
+            })
+        })
+        .collect();
+    candidates.sort_by_key(|p| p.gap_seconds);
+    let Some(best) = candidates.first() else {
+        return Match::OutOfRange;
+    };
+    let spread = candidates.iter().any(|p| {
+        geo::distance_km(best.latitude, best.longitude, p.latitude, p.longitude) > MAX_SPREAD_KM
+    });
+    if spread {
+        Match::Ambiguous(candidates)
+    } else {
+        Match::Located(candidates.swap_remove(0))
+    }
+}
+
+/// A photo and the UTC time it was taken
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhotoTime {
+    pub filename: String,
+    pub sha256: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Outcome of matching photos to tracks
+#[derive(Debug, Default)]
+pub struct GeotagReport {
+    pub matched: Vec<(PhotoTime, Position)>,
+    pub out_of_range: Vec<PhotoTime>,
+    pub ambiguous: Vec<(PhotoTime, Vec<Position>)>,
+    /// Photos skipped because they have GPS coordinates from the camera
+    pub with_gps: usize,
+    /// Photos skipped because they have no `DateTaken`
+    pub without_date: usize,
+}
+
+impl GeotagReport {
+    /// Match photos without GPS coordinates to the tracks
+    pub fn new(
+        files: &[IndexFile],
+        tracks: &[Track],
+        clock: &CameraClock,
+        max_gap: i64,
+    ) -> GeotagReport {
+        let mut report = GeotagReport::default();
+        for f in files {
+            if geo::file_location(f).is_some() {
+                report.with_gps += 1;
+                continue;
+            }
+            let Some(MetaValue::Date(taken)) = f.value("DateTaken") else {
+                report.without_date += 1;
+                continue;
+            };
+            let photo = PhotoTime {
+                filename: f.filename.clone(),
+                sha256: f.sha256.clone(),
+                // DateTaken is the camera time, read as if it was UTC
+                time: clock.to_utc(taken.naive_utc()),
+            };
+            match locate(tracks, photo.time, max_gap) {
+                Match::Located(position) => report.matched.push((photo, position)),
+                Match::OutOfRange => report.out_of_range.push(photo),
+                Match::Ambiguous(positions) => report.ambiguous.push((photo, positions)),
+            }
+        }
+        report
+    }
+}
+
+impl fmt::Display for GeotagReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Matched: {}", self.matched.len())?;
+        for (photo, p) in &self.matched {
+            writeln!(
+                f,
+                "  {}  {:.6},{:.6}  {}s from a point of {}",
+                photo.filename, p.latitude, p.longitude, p.gap_seconds, p.track
+            )?;
+        }
+        writeln!(f, "Out of range: {}", self.out_of_range.len())?;
+        for photo in &self.out_of_range {
+            writeln!(f, "  {}  taken {}", photo.filename, photo.time)?;
+        }
+        writeln!(f, "Ambiguous: {}", self.ambiguous.len())?;
+        for (photo, positions) in &self.ambiguous {
+            let candidates: Vec<String> = positions
+                .iter()
+                .map(|p| format!("{:.6},{:.6} ({})", p.latitude, p.longitude, p.track))
+                .collect();
+            writeln!(
+                f,
+                "  {}  taken {}: {}",
+                photo.filename,
+                photo.time,
+                candidates.join(" or ")
+            )?;
+        }
+        writeln!(
+            f,
+            "Skipped {} photos with GPS coordinates and {} without a date",
+            self.with_gps, self.without_date
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileindex::MetaVariable;
+    use crate::testutil::fixture;
+    use chrono::TimeZone;
+
+    fn utc(hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 6, 1, hour, minute, second)
+            .unwrap()
+    }
+
+    fn fixture_track() -> Track {
+        Track::load(Path::new(&fixture("track.gpx"))).unwrap()
+    }
+
+    fn photo(name: &str, meta: Vec<(&str, MetaValue)>) -> IndexFile {
+        IndexFile {
+            filename: String::from(name),
+            url: format!("file://{}", name),
+            sha256: String::new(),
+            created_at: Utc::now(),
+            modified_at: Utc::now(),
+            media_type: None,
+            meta: meta
+                .into_iter()
+                .map(|(name, value)| MetaVariable {
+                    name: String::from(name),
+                    value,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_gpx() {
+        let track = fixture_track();
+        assert_eq!(track.segments.len(), 2);
+        assert_eq!(track.points(), 5);
+        assert_eq!(
+            track.segments[0][0],
+            TrackPoint {
+                time: utc(10, 0, 0),
+                latitude: 52.5,
+                longitude: 13.4,
+                elevation: Some(34.0),
+            }
+        );
+        // the point without a time is skipped, the segment is sorted
+        assert_eq!(track.segments[1][0].time, utc(12, 0, 0));
+        assert_eq!(track.segments[1][1].elevation, None);
+
+        let track = Track::load(Path::new(&fixture("garmin.gpx"))).unwrap();
+        assert_eq!(
+            track.segments,
+            vec![vec![
+                TrackPoint {
+                    time: Utc.with_ymd_and_hms(2024, 6, 2, 8, 0, 0).unwrap(),
+                    latitude: 41.89,
+                    longitude: 12.48,
+                    elevation: Some(21.0),
+                },
+                TrackPoint {
+                    time: Utc.with_ymd_and_hms(2024, 6, 2, 8, 1, 0).unwrap(),
+                    latitude: 41.9,
+                    longitude: 12.47,
+                    elevation: Some(22.5),
+                }
+            ]]
+        );
+
+        assert!(Track::parse("bad.gpx", "<trkpt lat=\"x\" lon=\"1\">").is_err());
+        assert!(Track::parse("bad.gpx", "<gpx><trkpt lat=\"1\" lon=\"1\"></gpx>").is_err());
+        assert!(Track::parse("bad.gpx", "<gpx><trkpt").is_err());
+        assert_eq!(Track::parse("empty.gpx", "<gpx/>").unwrap().points(), 0);
+    }
+
+    #[test]
+    fn test_locate() {
+        let tracks = [fixture_track()];
+        // halfway between the first two points
+        let Match::Located(p) = locate(&tracks, utc(10, 0, 30), 300) else {
+            panic!("Expected a position");
+        };
+        assert!((p.latitude - 52.505).abs() < 1e-9);
+        assert!((p.longitude - 13.41).abs() < 1e-9);
+        assert_eq!(p.altitude, Some(36.0));
+        assert_eq!(p.gap_seconds, 30);
+        assert!(p.track.ends_with("track.gpx"));
+
+        // shortly after the end of a segment
+        let Match::Located(p) = locate(&tracks, utc(10, 3, 0), 300) else {
+            panic!("Expected a position");
+        };
+        assert_eq!((p.latitude, p.gap_seconds), (52.52, 60));
+        // between the segments
+        assert_eq!(locate(&tracks, utc(11, 0, 0), 300), Match::OutOfRange);
+        assert_eq!(locate(&tracks, utc(10, 3, 0), 30), Match::OutOfRange);
+
+        // a second logger somewhere else at the same time
+        let other = Track::parse(
+            "other.gpx",
+            "<gpx><trk><trkseg>\
+             <trkpt lat=\"48.85\" lon=\"2.35\"><time>2024-06-01T10:00:00Z</time></trkpt>\
+             </trkseg></trk></gpx>",
+        )
+        .unwrap();
+        let tracks = [fixture_track(), other];
+        let Match::Ambiguous(positions) = locate(&tracks, utc(10, 0, 40), 300) else {
+            panic!("Expected an ambiguous match");
+        };
+        assert_eq!(positions.len(), 2);
+        assert_eq!(positions[0].gap_seconds, 20);
+        assert_eq!(positions[1].track, "other.gpx");
+    }
+
+    #[test]
+    fn test_date_line() {
+        let track = Track::parse(
+            "pacific.gpx",
+            "<trkseg>\
+             <trkpt lat='-17' lon='179.9'><time>2024-06-01T10:00:00Z</time></trkpt>\
+             <trkpt lat='-17' lon='-179.9'><time>2024-06-01T10:02:00Z</time></trkpt>\
+             </trkseg>",
+        )
+        .unwrap();
+        let Match::Located(p) = locate(&[track], utc(10, 1, 30), 300) else {
+            panic!("Expected a position");
+        };
+        assert!((p.longitude + 179.95).abs() < 1e-9, "{}", p.longitude);
+    }
+
+    #[test]
+    fn test_camera_clock() {
+        let taken =
+            NaiveDateTime::parse_from_str("2024-06-01 12:01:40", "%Y-%m-%d %H:%M:%S").unwrap();
+        let clock = CameraClock {
+            offset_seconds: 100,
+            timezone: "+02:00".parse().unwrap(),
+        };
+        assert_eq!(clock.to_utc(taken), utc(10, 0, 0));
+        assert_eq!(CameraClock::default().to_utc(taken), utc(12, 1, 40));
+    }
+
+    #[test]
+    fn test_geotag_report() {
+        let clock = CameraClock {
+            offset_seconds: -30,
+            timezone: "-04:00".parse().unwrap(),
+        };
+        // the camera time as read from the index, 30 s behind New York summer time
+        let taken = |h, m, s| MetaValue::Date(utc(h, m, s));
+        let files = vec![
+            photo("matched.jpg", vec![("DateTaken", taken(6, 0, 30))]),
+            photo("late.jpg", vec![("DateTaken", taken(14, 0, 0))]),
+            photo("undated.jpg", Vec::new()),
+            photo(
+                "gps.jpg",
+                vec![
+                    ("DateTaken", taken(6, 0, 30)),
+                    ("GPSLatitude", MetaValue::Float(1.0)),
+                    ("GPSLongitude", MetaValue::Float(2.0)),
+                ],
+            ),
+        ];
+        let report = GeotagReport::new(&files, &[fixture_track()], &clock, 300);
+        assert_eq!(report.matched.len(), 1);
+        let (photo, position) = &report.matched[0];
+        assert_eq!(photo.filename, "matched.jpg");
+        assert_eq!(photo.time, utc(10, 1, 0));
+        assert_eq!((position.latitude, position.gap_seconds), (52.51, 0));
+        assert_eq!(report.out_of_range.len(), 1);
+        assert_eq!(report.with_gps, 1);
+        assert_eq!(report.without_date, 1);
+
+        let stored = position.to_json(&photo.time);
+        assert_eq!(stored["latitude"], 52.51);
+        assert_eq!(stored["time"], "2024-06-01T10:01:00+00:00");
+
+        let shown = report.to_string();
+        assert!(shown.contains("Matched: 1\n  matched.jpg  52.510000,13.420000"));
+        assert!(shown.contains("Out of range: 1\n  late.jpg"));
+        assert!(shown.contains("Skipped 1 photos with GPS coordinates and 1 without a date"));
+    }
+}
@@ -3,26 +3,38 @@ use duckdb::types::FromSql;
 /// Module to maintain the main index database, which is
 /// a duckdb file. It stores an entry for each file, giving its
 /// name / URL and sha256.
-use duckdb::{params_from_iter, types::ValueRef, Connection, Error, ToSql};
+use duckdb::{params, params_from_iter, types::ValueRef, Connection, OptionalExt, ToSql};
 use itertools::Itertools;
 use log::{debug, error, info, warn};
-use once_cell::sync::OnceCell;
 use serde_json::json;
-use shlex::try_quote;
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Once};
 
+use crate::dryrun::{self, DryRunReport};
 use crate::fileindex::{self, IndexFile, MetaValue, MetaVariable};
-use crate::jsonmeta;
+use crate::filewalk::FoundFile;
+use crate::geo::{self, Near};
+use crate::geocode::Gazetteer;
+use crate::gpx::{CameraClock, GeotagReport, Track};
+use crate::indexerror::IndexError;
+use crate::indexpipeline::{self, IndexOptions};
+use crate::jsonmeta::{self, MergeRules};
+use crate::libraryconfig::{self, GeocodingConfig, LibraryConfig, MetaStore};
+use crate::metacmd::{self, ExtractorError, ExtractorOptions, MetaCommandError, NamedExtractor};
+use crate::schema;
+use crate::sidecar;
+use crate::summarystats::{SummaryOptions, SummaryStats};
 use crate::variablemapping::{self, apply_mappings};
 
 use duckdb::Result;
 use std::fs::File;
-use std::io::{BufReader, Write};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Write};
 
-static DBPATH: OnceCell<Arc<String>> = OnceCell::new();
-static DB: OnceCell<Arc<Mutex<Connection>>> = OnceCell::new();
-static MAPPINGS: OnceCell<Arc<variablemapping::Mappings>> = OnceCell::new();
+/// Name of the index database in a library folder
+pub const DB_FILE: &str = "photocat.db";
 
 /// Helper to split a SQL string into statements and run
 fn run_sql(conn: &Connection, sql_str: &str) -> Result<usize, duckdb::Error> {
@@ -36,450 +48,1584 @@ fn run_sql(conn: &Connection, sql_str: &str) -> Result<usize, duckdb::Error> {
     Ok(result)
 }
 
-/// Set up the database connection
-/// This initializes the global singleton DB and DBPATH variables
-///
-/// The DB path contains the following:
+/// Why a library folder cannot be opened
+#[derive(Debug)]
+pub enum OpenError {
+    /// The library path does not exist or is not a directory
+    NotADirectory(String),
+    /// The folder has no index database, see `Library::init`
+    NotALibrary(String),
+    /// The library folder cannot be created
+    Create(std::io::Error),
+    /// The library was written by a newer version of photocat
+    NewerSchema(i64),
+    /// A schema migration cannot update the files of the library
+    Migration(i64, std::io::Error),
+    /// `photocat.toml` cannot be read
+    Config(std::io::Error),
+    /// Sidecar files cannot be imported into the database
+    Import(std::io::Error),
+    /// The index database cannot be opened or set up
+    Database(duckdb::Error),
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenError::NotADirectory(path) => write!(f, "{} is not a directory", path),
+            OpenError::NotALibrary(path) => write!(
+                f,
+                "{} is not a photocat library, create it with photocat -l {} init",
+                path, path
+            ),
+            OpenError::Create(e) => write!(f, "Cannot create library folder: {}", e),
+            OpenError::Migration(version, e) => write!(
+                f,
+                "Cannot upgrade library files to schema version {}: {}",
+                version, e
+            ),
+            OpenError::NewerSchema(version) => write!(
+                f,
+                "Library has schema version {}, but this version of photocat only supports up to {}",
+                version,
+                schema::latest_version()
+            ),
+            OpenError::Config(e) => write!(f, "Cannot load photocat.toml: {}", e),
+            OpenError::Import(e) => write!(f, "Cannot import metadata files into the database: {}", e),
+            OpenError::Database(e) => write!(f, "Cannot open database: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<duckdb::Error> for OpenError {
+    fn from(e: duckdb::Error) -> Self {
+        OpenError::Database(e)
+    }
+}
+
+/// Filters to select files from the index. Empty filters select all files.
+#[derive(Debug, Clone, Default)]
+pub struct QueryFilter {
+    /// Comma-separated SHA256 values
+    pub sha256s: Option<String>,
+    /// Part of the file name
+    pub filename: Option<String>,
+    /// Part of the URL
+    pub url: Option<String>,
+    /// Maximum number of files
+    pub limit: Option<usize>,
+    pub min_date: Option<DateTime<Utc>>,
+    pub max_date: Option<DateTime<Utc>>,
+    /// Only files with a GPS location within a distance of a point
+    pub near: Option<Near>,
+    /// Only files whose metadata variables have these values
+    pub meta_equals: Vec<MetaEquals>,
+    /// Geocode the files first, for callers that output the place variables
+    /// even when no filter needs them
+    pub places: bool,
+}
+
+/// A metadata variable with a given value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaEquals {
+    pub variable: String,
+    pub value: String,
+}
+
+impl FromStr for MetaEquals {
+    type Err = String;
+
+    /// Parse `Variable=value`, e.g. `Country=Germany`
+    fn from_str(s: &str) -> Result<MetaEquals, String> {
+        match s.split_once('=') {
+            Some((variable, value)) if !variable.trim().is_empty() => Ok(MetaEquals {
+                variable: variable.trim().to_string(),
+                value: value.trim().to_string(),
+            }),
+            _ => Err(format!("expected VARIABLE=VALUE but got '{}'", s)),
+        }
+    }
+}
+
+/// An open library folder, which contains the following:
 /// - a DuckDB file named photocat.db
 /// - JSON files with metadata for each indexed entry (if these were created when indexing)
+/// - optionally `mapping.toml` and `photocat.toml`
 ///
-/// Since we rely on the JSON module in duckdb, we load and try to install.
-pub fn init_connection(path: &str) {
-    DBPATH
-        .set(Arc::new(String::from(path)))
-        .expect("Cannot initialize DB path");
-    DB.set(Arc::new(Mutex::new(
-        Connection::open(std::path::Path::new(path).join("photocat.db"))
-            .expect("Failed to open DuckDB connection"),
-    )))
-    .expect("Cannot (re)initialize database connection.");
-    let conn = DB.get().unwrap().lock().unwrap();
-
-    let mappings = variablemapping::load_mappings(
-        std::path::Path::new(path)
-            .join("mapping.toml")
-            .to_str()
-            .unwrap(),
-    );
-    if let Ok(mappings) = mappings {
-        info!("Loaded {} mappings from data folder.", mappings.len());
-        MAPPINGS
-            .set(Arc::new(mappings))
-            .expect("Cannot initialize mappings.");
-    }
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS fileindex (
-            filename TEXT NOT NULL,
-            url TEXT NOT NULL,
-            sha256 TEXT NOT NULL,
-            created_at TIMESTAMP NOT NULL,
-            modified_at TIMESTAMP NOT NULL
-            ); ",
-        [],
-    )
-    .expect("Failed to create fileindex table");
-    conn.execute(
-        "CREATE INDEX IF NOT EXISTS idx_sha256 ON fileindex (sha256);",
-        [],
-    )
-    .expect("Failed to create index on sha256");
-    let can_load_json = conn.execute("LOAD JSON;", []);
-    if can_load_json.is_err() {
-        warn!(
-            "Cannot load the JSON module for DuckDB, trying to install: {:?}",
-            can_load_json.err()
+/// Each library has its own connection, so several libraries can be open at
+/// once. Clones share the connection of the library they were cloned from.
+#[derive(Clone)]
+pub struct Library {
+    path: Arc<PathBuf>,
+    conn: Arc<Mutex<Connection>>,
+    mappings: Option<Arc<variablemapping::Mappings>>,
+    config: Arc<LibraryConfig>,
+    /// Set once the meta table has places from the gazetteer
+    geocoded: Arc<Once>,
+}
+
+impl Library {
+    /// Create a library folder with an empty index, or open it if it exists
+    pub fn init(path: &str) -> Result<Library, OpenError> {
+        std::fs::create_dir_all(Path::new(path).join(sidecar::SIDECAR_DIR))
+            .map_err(OpenError::Create)?;
+        Connection::open(Path::new(path).join(DB_FILE))?;
+        Library::open(path)
+    }
+
+    /// Open a library folder, upgrading its schema if it was created by an
+    /// older version.
+    ///
+    /// Since we rely on the JSON module in duckdb, we load and try to install.
+    pub fn open(path: &str) -> Result<Library, OpenError> {
+        let path = Path::new(path);
+        if !path.is_dir() {
+            return Err(OpenError::NotADirectory(path.display().to_string()));
+        }
+        if !path.join(DB_FILE).is_file() {
+            return Err(OpenError::NotALibrary(path.display().to_string()));
+        }
+        let mut conn = Connection::open(path.join(DB_FILE))?;
+        let version = schema::schema_version(&conn)?;
+        if version > schema::latest_version() {
+            return Err(OpenError::NewerSchema(version));
+        }
+        // before migrating, since tables have JSON columns
+        let can_load_json = conn.execute("LOAD JSON;", []);
+        if can_load_json.is_err() {
+            warn!(
+                "Cannot load the JSON module for DuckDB, trying to install: {:?}",
+                can_load_json.err()
+            );
+            conn.execute("INSTALL 'JSON';", [])?;
+            conn.execute("LOAD JSON;", [])?;
+        }
+        schema::migrate(&mut conn, path)?;
+
+        let mappings = variablemapping::load_mappings(path.join("mapping.toml").to_str().unwrap());
+        let mappings = match mappings {
+            Ok(mappings) => {
+                info!("Loaded {} mappings from data folder.", mappings.len());
+                Some(Arc::new(mappings))
+            }
+            Err(_) => None,
+        };
+
+        let config_path = path.join("photocat.toml");
+        let config = if config_path.exists() {
+            libraryconfig::load_config(config_path.to_str().unwrap()).map_err(OpenError::Config)?
+        } else {
+            LibraryConfig::default()
+        };
+        info!(
+            "Loaded {} metadata extractors from data folder.",
+            config.extractor.len()
         );
-        conn.execute("INSTALL 'JSON';", [])
-            .expect("Cannot install JSON module for DuckDB");
-        conn.execute("LOAD JSON;", [])
-            .expect("Cannot load JSON module in DuckDB");
+        if config.storage.meta == MetaStore::Database {
+            let imported = import_sidecars(&mut conn, path)?;
+            if imported > 0 {
+                info!("Imported metadata of {} files from sidecar files", imported);
+            }
+        }
+
+        {
+            // run JSON ingestion
+            let (source, row) = match config.storage.meta {
+                MetaStore::Sidecar => (
+                    format!(
+                        "read_json_auto('{}', ignore_errors=true, union_by_name=true)",
+                        path.join(sidecar::SIDECAR_GLOB).to_str().unwrap()
+                    ),
+                    "to_json(read_json_auto)",
+                ),
+                MetaStore::Database => (String::from("rawmeta"), "metadata"),
+            };
+            let sql_str = include_str!("meta.sql")
+                .replace("{{metasource}}", &source)
+                .replace("{{metajson}}", &meta_json_sql(&config.extractor, row));
+            if let Err(e) = run_sql(&conn, &sql_str) {
+                error!("Failed to run meta SQL command {}", e);
+            }
+        }
+
+        Ok(Library {
+            path: Arc::new(path.to_path_buf()),
+            conn: Arc::new(Mutex::new(conn)),
+            mappings,
+            config: Arc::new(config),
+            geocoded: Arc::new(Once::new()),
+        })
     }
-    {
-        // run JSON ingestion
-        let sql_str = include_str!("meta.sql").replace("{{datapath}}", path);
-        if let Err(e) = run_sql(&conn, &sql_str) {
-            error!("Failed to run meta SQL command {}", e);
+
+    /// Fill the Country, CountryCode, Region and City variables of files
+    /// with a location, unless geocoding is turned off in `photocat.toml`.
+    /// Loading the gazetteer takes a while, so this is done once, the first
+    /// time a query or summary uses one of these variables.
+    pub fn geocode(&self) {
+        if !self.config.geocoding.enabled {
+            return;
         }
+        self.geocoded.call_once(|| {
+            let gazetteer = load_gazetteer(&self.path, &self.config.geocoding);
+            let max_distance_km = self
+                .config
+                .geocoding
+                .max_distance_km
+                .unwrap_or(DEFAULT_GEOCODING_DISTANCE_KM);
+            match geocode_meta(&self.conn.lock().unwrap(), &gazetteer, max_distance_km) {
+                Ok(count) => debug!("Geocoded {} files", count),
+                Err(e) => error!("Failed to geocode locations {}", e),
+            }
+        });
     }
-}
 
-/// Add a single file to the index
-///
-/// Args:
-/// path: local path to the file
-/// meta_cmd: Command to produce metadata
-/// meta_merge: set to true to merge metadata objects, false to overwrite
-pub fn index_file(
-    path: String,
-    meta_cmd: Option<String>,
-    meta_merge: bool,
-) -> Result<(), std::io::Error> {
-    let fileinfo = fileindex::IndexFile::new(path.as_str()).unwrap();
-
-    // this bit blocks the DuckDB connection
+    /// The library folder
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Version of the database schema, see `schema::MIGRATIONS`
+    pub fn schema_version(&self) -> Result<i64> {
+        schema::schema_version(&self.conn.lock().unwrap())
+    }
+
+    /// Return the library configuration
+    pub fn config(&self) -> &LibraryConfig {
+        &self.config
+    }
+
+    /// Set up the metadata extractors to run when indexing, the extractors
+    /// configured in `photocat.toml` or the metadata command
+    pub fn meta_extractors(
+        &self,
+        options: &ExtractorOptions,
+    ) -> std::result::Result<Vec<NamedExtractor>, ExtractorError> {
+        metacmd::named_extractors(&self.config.extractor, options)
+    }
+
+    /// Index files: hash them, run the metadata extractors and add them to the index
+    pub async fn index<I>(&self, files: I, options: IndexOptions)
+    where
+        I: IntoIterator<Item = FoundFile>,
     {
-        // Get the DuckDB connection
-        let conn = DB.get().unwrap().lock().unwrap();
-
-        // Insert the fileinfo into the database
-        let mut stmt = conn.prepare("INSERT INTO fileindex \
-                                                (filename, url, sha256, created_at, modified_at) \
-                                                SELECT ?, ?, ?, ?, ? \
-                                                WHERE NOT EXISTS (SELECT 1 FROM fileindex WHERE sha256 = ?)")
-                                                .expect("Failed to prepare statement");
-        let inserted = stmt
-            .execute(&[
-                &fileinfo.filename,
-                &fileinfo.url,
-                &fileinfo.sha256,
-                &fileinfo.created_at.to_string(),
-                &fileinfo.modified_at.to_string(),
-                &fileinfo.sha256,
-            ])
-            .expect("Failed to insert fileinfo into database");
-        debug!(
-            "Inserted {} rows for {} / {}",
-            inserted, fileinfo.filename, fileinfo.sha256
-        );
+        indexpipeline::index_files(self, files, options).await
     }
 
-    if let Some(meta_cmd) = meta_cmd {
-        // Quote the json_path for shell execution
-        let quoted_file_path: String = try_quote(&fileinfo.filename).unwrap().to_string();
-        // Run the meta_cmd in the shell
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(format!("cat {} | {}", quoted_file_path, meta_cmd))
-            .output()
-            .expect("Failed to execute meta_cmd");
-
-        if output.status.success() {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let json = serde_json::from_str(&stdout);
-            let mut json_val;
-            if json.is_err() {
-                json_val = serde_json::Value::Null;
-                error!(
-                    "Cannot parse output for {} {}: {} / {}",
-                    meta_cmd, quoted_file_path, stdout, stderr
-                );
-            } else {
-                json_val = json.unwrap();
+    /// Hash files and compare them with the index, to show what indexing
+    /// would do without changing anything
+    pub async fn dry_run<I>(&self, files: I, options: IndexOptions) -> Result<DryRunReport>
+    where
+        I: IntoIterator<Item = FoundFile>,
+    {
+        let scanned = dryrun::scan(files, &options).await;
+        let indexed = self.indexed_files()?;
+        Ok(dryrun::classify(scanned, &indexed, |filename, url| {
+            fileindex::calculate_sha256_of_file(filename, url).ok()
+        }))
+    }
+
+    /// Summarize the files selected by a filter
+    pub fn summarize(
+        &self,
+        filter: &QueryFilter,
+        options: &SummaryOptions,
+    ) -> Result<SummaryStats> {
+        if options.count.iter().flatten().any(|v| is_geocoded(v)) {
+            self.geocode();
+        }
+        let mut summary = SummaryStats::new(options);
+        self.query(filter, |record| summary.add(&record))?;
+        Ok(summary)
+    }
+
+    /// Count the values of a metadata variable in the files selected by a
+    /// filter, None if no file has the variable
+    pub fn value_counts(
+        &self,
+        filter: &QueryFilter,
+        variable: &str,
+    ) -> Result<Option<HashMap<String, usize>>> {
+        if is_geocoded(variable) {
+            self.geocode();
+        }
+        let mut value_counts: HashMap<String, usize> = HashMap::new();
+        let mut found_variable = false;
+        self.query(filter, |record| {
+            for v in record.meta {
+                if v.name == variable {
+                    found_variable = true;
+                    if v.value != MetaValue::Null {
+                        *value_counts.entry(v.value.to_string()).or_insert(0) += 1;
+                    }
+                }
+            }
+        })?;
+        Ok(found_variable.then_some(value_counts))
+    }
+
+    /// Path of the JSON metadata file for a file hash
+    fn json_path(&self, sha256: &str) -> PathBuf {
+        sidecar::sidecar_path(&self.path, sha256)
+    }
+
+    /// Read the stored JSON metadata for a file hash, from a sidecar file or
+    /// the `rawmeta` table depending on the library configuration
+    fn stored_meta(&self, sha256: &str) -> std::io::Result<serde_json::Value> {
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        match self.config.storage.meta {
+            MetaStore::Sidecar => {
+                let file = File::open(self.json_path(sha256))?;
+                serde_json::from_reader(BufReader::new(file)).map_err(invalid)
+            }
+            MetaStore::Database => {
+                let conn = self.conn.lock().unwrap();
+                let metadata: String = conn
+                    .query_row(
+                        "SELECT metadata FROM rawmeta WHERE sha256 = ?",
+                        params![sha256],
+                        |row| row.get(0),
+                    )
+                    .map_err(|e| match e {
+                        duckdb::Error::QueryReturnedNoRows => {
+                            std::io::Error::new(std::io::ErrorKind::NotFound, e)
+                        }
+                        e => std::io::Error::other(e),
+                    })?;
+                serde_json::from_str(&metadata).map_err(invalid)
             }
-            // Create path of JSON file as DBPATH/fileinfo.sha256.json
-            let json_path = std::path::Path::new(DBPATH.get().unwrap().as_str())
-                .join(format!("{}.json", fileinfo.sha256.as_str()));
-
-            // merge if requested
-            if meta_merge {
-                if let Ok(file) = File::open(&json_path) {
-                    let reader = BufReader::new(file);
-                    // TODO log error when we cannot read the current value
-                    let current_json = serde_json::from_reader(reader);
-                    if current_json.is_err() {
-                        error!(
-                            "Cannot parse current JSON for {}: {:?}",
-                            fileinfo.sha256,
-                            current_json.err().unwrap()
-                        );
-                    } else {
-                        let mut current_json_val = current_json.unwrap();
-                        jsonmeta::merge(&mut current_json_val, json_val);
-                        json_val = current_json_val;
+        }
+    }
+
+    /// Read the change log of the metadata for a file hash, from the file
+    /// next to its sidecar or the `metahistory` table. Empty if there is none.
+    fn stored_history(&self, sha256: &str) -> std::io::Result<Vec<serde_json::Value>> {
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        match self.config.storage.meta {
+            MetaStore::Sidecar => read_history(&sidecar::history_path(&self.path, sha256)),
+            MetaStore::Database => {
+                let conn = self.conn.lock().unwrap();
+                let history: Option<String> = conn
+                    .query_row(
+                        "SELECT history FROM metahistory WHERE sha256 = ?",
+                        params![sha256],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(std::io::Error::other)?;
+                match history {
+                    Some(history) => serde_json::from_str(&history).map_err(invalid),
+                    None => Ok(Vec::new()),
+                }
+            }
+        }
+    }
+
+    /// The change log of a file with new entries appended, or None if it is
+    /// unchanged
+    fn updated_history(
+        &self,
+        sha256: &str,
+        entries: Vec<serde_json::Value>,
+    ) -> std::io::Result<Option<Vec<serde_json::Value>>> {
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        let mut history = self.stored_history(sha256)?;
+        for entry in entries {
+            jsonmeta::append_history(&mut history, entry);
+        }
+        Ok(Some(history))
+    }
+
+    /// Write the metadata stored in the database as sidecar files below a
+    /// folder, in the same layout as libraries that store them in files,
+    /// together with their change logs. Returns the number of files written.
+    pub fn export_sidecars(&self, folder: &Path) -> std::io::Result<usize> {
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT rawmeta.sha256, metadata, history FROM rawmeta \
+                 LEFT JOIN metahistory ON rawmeta.sha256 = metahistory.sha256 \
+                 ORDER BY rawmeta.sha256",
+            )
+            .map_err(std::io::Error::other)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })
+            .map_err(std::io::Error::other)?;
+        let mut written = 0;
+        for row in rows {
+            let (sha256, metadata, history) = row.map_err(std::io::Error::other)?;
+            let json_val: serde_json::Value = serde_json::from_str(&metadata).map_err(invalid)?;
+            write_json(&sidecar::sidecar_path(folder, &sha256), &json_val)?;
+            if let Some(history) = history {
+                let history: Vec<serde_json::Value> =
+                    serde_json::from_str(&history).map_err(invalid)?;
+                write_history(&sidecar::history_path(folder, &sha256), &history)?;
+            }
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// Second stage of indexing a file: update its JSON metadata
+    ///
+    /// Args:
+    /// record: the hashed file, nothing is done if it could not be read
+    /// extractors: Commands to produce metadata, each is run if it applies to the file
+    /// merge_rules: how to merge with existing metadata, None to overwrite
+    ///
+    /// When a metadata command fails, the failure is recorded under the
+    /// `meta_error` key of its metadata, and added to the errors of the record.
+    pub fn extract_metadata(
+        &self,
+        record: &mut IndexRecord,
+        extractors: &[NamedExtractor],
+        merge_rules: Option<&MergeRules>,
+    ) {
+        let fileinfo = match record.fileinfo {
+            Some(ref fileinfo) => fileinfo,
+            None => return,
+        };
+        let errors = &mut record.errors;
+
+        let extractors: Vec<&NamedExtractor> = extractors
+            .iter()
+            .filter(|e| e.applies_to(&fileinfo.filename))
+            .collect();
+        if !extractors.is_empty() {
+            let mut json_val = match self.stored_meta(&fileinfo.sha256) {
+                Ok(json_val) => json_val,
+                Err(e) => {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        error!("Cannot read current JSON for {}: {}", fileinfo.sha256, e);
                     }
+                    json!({})
                 }
+            };
+
+            let mut entries = Vec::new();
+            for extractor in extractors {
+                let namespace = extractor.name.as_deref();
+                let current = jsonmeta::get_namespace(&json_val, namespace);
+                let updated = run_extractor(
+                    extractor,
+                    &fileinfo.filename,
+                    current.clone(),
+                    merge_rules,
+                    errors,
+                );
+                entries.extend(jsonmeta::history_entry(
+                    &current,
+                    &updated,
+                    json!({
+                        "timestamp": Utc::now().to_rfc3339(),
+                        "extractor": namespace,
+                        "command": extractor.extractor.command_line(),
+                    }),
+                ));
+                jsonmeta::set_namespace(&mut json_val, namespace, updated);
             }
-            // flatten single element arrays (such as the ones returned by exiftool)
-            // to pass an array, assign it inside a top-level object
-            while let serde_json::Value::Array(ref mut arr) = json_val {
-                if arr.len() == 1 {
-                    json_val = arr.remove(0);
-                } else {
-                    break;
+            json_val.as_object_mut().unwrap().insert(
+                String::from(jsonmeta::SHA256_KEY),
+                serde_json::Value::String(fileinfo.sha256.clone()),
+            );
+            let history = match self.updated_history(&fileinfo.sha256, entries) {
+                Ok(history) => history,
+                Err(e) => {
+                    error!("Cannot read history for {}: {}", fileinfo.sha256, e);
+                    None
+                }
+            };
+            match self.config.storage.meta {
+                MetaStore::Sidecar => {
+                    // <library>/meta/ab/cd/<sha256>.json
+                    let json_path = self.json_path(&fileinfo.sha256);
+                    let history_path = sidecar::history_path(&self.path, &fileinfo.sha256);
+                    let written = write_json(&json_path, &json_val).map_err(|e| (json_path, e));
+                    let written = written.and_then(|_| match history {
+                        Some(ref history) => {
+                            write_history(&history_path, history).map_err(|e| (history_path, e))
+                        }
+                        None => Ok(()),
+                    });
+                    if let Err((path, error)) = written {
+                        errors.push(IndexError::WriteJson {
+                            path: path.display().to_string(),
+                            error,
+                        });
+                    }
+                }
+                // written to rawmeta and metahistory together with the file
+                // by write_records
+                MetaStore::Database => {
+                    record.meta = Some(json_val);
+                    record.history = history;
                 }
             }
-            match json_val {
-                serde_json::Value::Object(ref mut obj) => {
-                    obj.insert(
-                        String::from("sha256"),
-                        serde_json::Value::String(fileinfo.sha256),
+        }
+    }
+
+    /// Last stage of indexing: add a batch of files to the index and replace
+    /// their recorded errors, in a single transaction
+    pub fn write_records(&self, records: &[IndexRecord]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO fileindex \
+                 (filename, url, sha256, created_at, modified_at, media_type) \
+                 SELECT ?, ?, ?, ?, ?, ? \
+                 WHERE NOT EXISTS (SELECT 1 FROM fileindex WHERE sha256 = ?)",
+            )?;
+            // files indexed before media types were recorded
+            let mut update_media_type = tx.prepare(
+                "UPDATE fileindex SET media_type = ? WHERE sha256 = ? AND media_type IS NULL",
+            )?;
+            let mut delete_meta = tx.prepare("DELETE FROM rawmeta WHERE sha256 = ?")?;
+            let mut insert_meta =
+                tx.prepare("INSERT INTO rawmeta (sha256, metadata, updated_at) VALUES (?, ?, ?)")?;
+            let mut delete_history = tx.prepare("DELETE FROM metahistory WHERE sha256 = ?")?;
+            let mut insert_history = tx.prepare(
+                "INSERT INTO metahistory (sha256, history, updated_at) VALUES (?, ?, ?)",
+            )?;
+            let mut delete_errors = tx.prepare("DELETE FROM index_errors WHERE filename = ?")?;
+            let mut insert_error = tx.prepare(
+                "INSERT INTO index_errors (filename, stage, message, created_at) \
+                 VALUES (?, ?, ?, ?)",
+            )?;
+            let now = Utc::now().to_string();
+            for record in records {
+                if let Some(ref fileinfo) = record.fileinfo {
+                    let inserted = insert.execute(params![
+                        &fileinfo.filename,
+                        &fileinfo.url,
+                        &fileinfo.sha256,
+                        &fileinfo.created_at.to_string(),
+                        &fileinfo.modified_at.to_string(),
+                        &fileinfo.media_type,
+                        &fileinfo.sha256,
+                    ])?;
+                    update_media_type.execute(params![&fileinfo.media_type, &fileinfo.sha256])?;
+                    if let Some(ref meta) = record.meta {
+                        delete_meta.execute(params![&fileinfo.sha256])?;
+                        insert_meta.execute(params![&fileinfo.sha256, &meta.to_string(), &now])?;
+                    }
+                    if let Some(ref history) = record.history {
+                        let history = serde_json::Value::from(history.clone()).to_string();
+                        delete_history.execute(params![&fileinfo.sha256])?;
+                        insert_history.execute(params![&fileinfo.sha256, &history, &now])?;
+                    }
+                    debug!(
+                        "Inserted {} rows for {} / {}",
+                        inserted, fileinfo.filename, fileinfo.sha256
                     );
                 }
-                _ => {
-                    json_val = json!({
-                        "sha256": fileinfo.sha256,
-                        "data": json_val,
-                    });
+                delete_errors.execute(params![&record.filename])?;
+                for e in &record.errors {
+                    insert_error.execute(params![
+                        &record.filename,
+                        e.stage().as_str(),
+                        e.to_string(),
+                        &now,
+                    ])?;
                 }
             }
-            // Write json_val into file at json_path
-            let mut file = File::create(&json_path)?;
-            file.write_all(json_val.to_string().as_bytes())?;
-        } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            error!("Failed to execute meta_cmd: {}", stderr);
         }
+        tx.commit()
     }
 
-    Ok(())
-}
+    /// Remove files from the index, together with their recorded errors.
+    /// `files` are removed by their exact path; `folders` also remove all
+    /// files below them, for directories that were deleted or moved away.
+    /// Their metadata and history are kept. Returns the number of files
+    /// removed.
+    pub fn remove_files(&self, files: &[String], folders: &[String]) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut removed = 0;
+        {
+            // starts_with(filename, NULL) matches nothing
+            let removals = files.iter().map(|path| (path, None)).chain(
+                folders
+                    .iter()
+                    .map(|path| (path, Some(format!("{}/", path.trim_end_matches('/'))))),
+            );
+            let mut delete_files =
+                tx.prepare("DELETE FROM fileindex WHERE filename = ? OR starts_with(filename, ?)")?;
+            let mut delete_errors = tx.prepare(
+                "DELETE FROM index_errors WHERE filename = ? OR starts_with(filename, ?)",
+            )?;
+            for (path, below) in removals {
+                removed += delete_files.execute(params![path, &below])?;
+                delete_errors.execute(params![path, &below])?;
+            }
+        }
+        tx.commit()?;
+        Ok(removed)
+    }
 
-/// Return true if we have a metadata table
-pub fn has_meta() -> bool {
-    let conn = DB.get().expect("Database not initialized");
-    let conn = conn.lock().unwrap();
-    let table_exists: bool = conn
-        .query_row(
-            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'meta')",
-            [],
-            |row| row.get(0),
-        )
-        .unwrap();
-    table_exists
-}
+    /// All files in the index, to compare with files on disk
+    pub fn indexed_files(&self) -> Result<Vec<dryrun::IndexedFile>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT filename, url, sha256 FROM fileindex")?;
+        let files = stmt.query_map([], |row| {
+            Ok(dryrun::IndexedFile {
+                filename: row.get(0)?,
+                url: row.get(1)?,
+                sha256: row.get(2)?,
+            })
+        })?;
+        files.collect()
+    }
 
-// return dictionary of columns and types in meta table
-pub fn get_meta_columns() -> Result<Vec<(i32, String, String)>> {
-    assert!(has_meta(), "No metadata table present");
-    let conn = DB.get().expect("Database not initialized");
-    let conn = conn.lock().unwrap();
-    let mut columns = Vec::new();
-    let mut stmt = conn.prepare("PRAGMA table_info(meta)")?;
-    let rows = stmt.query_map([], |row| {
-        let cid: i32 = row.get(0)?;
-        let name: String = row.get(1)?;
-        let type_: String = row.get(2)?;
-        Ok((cid, name, type_))
-    })?;
-    for row in rows {
-        let (cid, name, type_) = row?;
-        columns.push((cid, name, type_));
-    }
-    Ok(columns.into_iter().sorted_by_key(|x| x.0).collect())
-}
+    /// Files for which errors were recorded when they were last indexed
+    pub fn failed_files(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT DISTINCT filename FROM index_errors ORDER BY filename")?;
+        let files = stmt.query_map([], |row| row.get(0))?;
+        files.collect()
+    }
 
-/// Create vector of file index entries from the database based on the provided filters.
-///
-/// # Arguments
-///
-/// * `sha256s` - Optional string containing comma-separated SHA256 values to filter by.
-/// * `filename` - Optional string containing the filename to filter by.
-/// * `url` - Optional string containing the URL to filter by.
-/// * `limit` - Optional limit on the number of results to retrieve.
-///
-pub fn query_fileindex(
-    sha256s: &Option<String>,
-    filename: &Option<String>,
-    url: &Option<String>,
-    limit: &Option<usize>,
-    min_date: &Option<chrono::DateTime<Utc>>,
-    max_date: &Option<chrono::DateTime<Utc>>,
-    mut callback: impl FnMut(IndexFile),
-) -> Result<(), Error> {
-    let has_meta = has_meta();
-    let meta_columns = get_meta_columns();
-    let mut query;
-    if has_meta {
-        query =
-            String::from("SELECT filename, url, fileindex.sha256, created_at, modified_at, meta.* FROM fileindex JOIN meta ON (fileindex.sha256 = meta.sha256)");
-    } else {
-        query =
-            String::from("SELECT filename, url, sha256, created_at, modified_at FROM fileindex");
-    }
-
-    let mut params: Vec<Box<dyn ToSql>> = Vec::new();
-    let mut has_params = false;
-
-    let sha256_vec: Vec<&str> = sha256s
-        .as_ref()
-        .map(|s| s.split(',').collect())
-        .unwrap_or_else(Vec::new);
-    if !sha256_vec.is_empty() {
-        let placeholders = sha256_vec.iter().map(|_| "?").collect::<Vec<_>>().join(",");
-        query.push_str(" WHERE sha256 IN (");
-        query.push_str(&placeholders);
-        query.push_str(")");
-        for p in sha256_vec.iter().map(|s| Box::new(String::from(*s))) {
-            params.push(p);
-        }
-        has_params = true;
-    }
-
-    let filename_format_string: String;
-    if let Some(filename) = filename {
-        if has_params {
-            query.push_str(" AND filename LIKE ?");
-        } else {
-            query.push_str(" WHERE filename LIKE ?");
+    /// Number of files with errors for each stage, for errors recorded since a given time
+    pub fn index_error_summary(&self, since: &DateTime<Utc>) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT stage, COUNT(DISTINCT filename) FROM index_errors \
+             WHERE created_at >= CAST(? AS TIMESTAMP) GROUP BY stage ORDER BY stage",
+        )?;
+        let counts = stmt.query_map(params![since.to_string()], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        counts.collect()
+    }
+
+    /// Return the formatted change log of the JSON metadata for a file hash
+    pub fn meta_history(&self, sha256: &str) -> std::io::Result<String> {
+        Ok(jsonmeta::format_history(&self.stored_history(sha256)?))
+    }
+
+    /// Match the files selected by a filter to GPS tracks, and store the
+    /// inferred locations unless `store` is false
+    pub fn geotag(
+        &self,
+        filter: &QueryFilter,
+        tracks: &[Track],
+        clock: &CameraClock,
+        max_gap: i64,
+        store: bool,
+    ) -> Result<GeotagReport> {
+        let mut files = Vec::new();
+        self.query(filter, |f| files.push(f))?;
+        let report = GeotagReport::new(&files, tracks, clock, max_gap);
+        if store {
+            for (photo, position) in &report.matched {
+                if let Err(e) =
+                    self.set_inferred_location(&photo.sha256, position.to_json(&photo.time))
+                {
+                    error!("Cannot store location of {}: {}", photo.filename, e);
+                }
+            }
         }
-        filename_format_string = format!("%{}%", &filename);
-        params.push(Box::new(String::from(&filename_format_string)));
-        has_params = true;
+        Ok(report)
     }
 
-    let url_format_string: String;
-    if let Some(url) = url {
-        if has_params {
-            query.push_str(" AND url LIKE ?");
-        } else {
-            query.push_str(" WHERE url LIKE ?");
+    /// Store the location of a file inferred from GPS tracks, replacing the
+    /// previously inferred one. The metadata from the camera are not changed.
+    pub fn set_inferred_location(
+        &self,
+        sha256: &str,
+        location: serde_json::Value,
+    ) -> std::io::Result<()> {
+        let mut json_val = match self.stored_meta(sha256) {
+            Ok(json_val) if json_val.is_object() => json_val,
+            Ok(_) => json!({}),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => json!({}),
+            Err(e) => return Err(e),
+        };
+        let key = jsonmeta::INFERRED_LOCATION_KEY;
+        let current = json_val
+            .get(key)
+            .cloned()
+            .unwrap_or(serde_json::Value::Null);
+        let entries = jsonmeta::history_entry(
+            &current,
+            &location,
+            json!({
+                "timestamp": Utc::now().to_rfc3339(),
+                "extractor": key,
+                "command": "geotag",
+            }),
+        );
+        let history = self.updated_history(sha256, entries.into_iter().collect())?;
+        let obj = json_val.as_object_mut().unwrap();
+        obj.insert(String::from(key), location);
+        obj.insert(
+            String::from(jsonmeta::SHA256_KEY),
+            serde_json::Value::String(sha256.to_string()),
+        );
+        match self.config.storage.meta {
+            MetaStore::Sidecar => {
+                write_json(&self.json_path(sha256), &json_val)?;
+                match history {
+                    Some(history) => {
+                        write_history(&sidecar::history_path(&self.path, sha256), &history)
+                    }
+                    None => Ok(()),
+                }
+            }
+            MetaStore::Database => {
+                let now = Utc::now().to_string();
+                let mut conn = self.conn.lock().unwrap();
+                let tx = conn.transaction().map_err(std::io::Error::other)?;
+                tx.execute("DELETE FROM rawmeta WHERE sha256 = ?", params![sha256])
+                    .map_err(std::io::Error::other)?;
+                tx.execute(
+                    "INSERT INTO rawmeta (sha256, metadata, updated_at) VALUES (?, ?, ?)",
+                    params![sha256, &json_val.to_string(), &now],
+                )
+                .map_err(std::io::Error::other)?;
+                if let Some(history) = history {
+                    tx.execute("DELETE FROM metahistory WHERE sha256 = ?", params![sha256])
+                        .map_err(std::io::Error::other)?;
+                    tx.execute(
+                        "INSERT INTO metahistory (sha256, history, updated_at) VALUES (?, ?, ?)",
+                        params![sha256, &serde_json::Value::from(history).to_string(), &now],
+                    )
+                    .map_err(std::io::Error::other)?;
+                }
+                tx.commit().map_err(std::io::Error::other)
+            }
         }
-        url_format_string = format!("%{}%", &url);
-        params.push(Box::new(String::from(&url_format_string)));
-        has_params = true;
     }
 
-    let min_date_str = if let Some(min_date) = min_date {
-        format!(
-            " created_at >= CAST('{}' AS TIMESTAMP) AND modified_at >= CAST('{}' AS TIMESTAMP)",
-            min_date.to_rfc3339(),
-            min_date.to_rfc3339()
-        )
-    } else {
-        String::new()
-    };
-    if !min_date_str.is_empty() {
-        if has_params {
-            query.push_str(" AND");
-        } else {
-            query.push_str(" WHERE");
+    /// Return true if we have a metadata table
+    pub fn has_meta(&self) -> bool {
+        let conn = self.conn.lock().unwrap();
+        let table_exists: bool = conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'meta')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        table_exists
+    }
+
+    // return dictionary of columns and types in meta table
+    pub fn meta_columns(&self) -> Result<Vec<(i32, String, String)>> {
+        assert!(self.has_meta(), "No metadata table present");
+        let conn = self.conn.lock().unwrap();
+        let mut columns = Vec::new();
+        let mut stmt = conn.prepare("PRAGMA table_info(meta)")?;
+        let rows = stmt.query_map([], |row| {
+            let cid: i32 = row.get(0)?;
+            let name: String = row.get(1)?;
+            let type_: String = row.get(2)?;
+            Ok((cid, name, type_))
+        })?;
+        for row in rows {
+            let (cid, name, type_) = row?;
+            columns.push((cid, name, type_));
         }
-        query.push_str(&min_date_str);
-        has_params = true;
+        Ok(columns.into_iter().sorted_by_key(|x| x.0).collect())
     }
 
-    let max_date_str = if let Some(max_date) = max_date {
-        format!(
-            " created_at <= CAST('{}' AS TIMESTAMP) AND modified_at <= CAST('{}' AS TIMESTAMP)",
-            max_date.to_rfc3339(),
-            max_date.to_rfc3339()
-        )
-    } else {
-        String::new()
-    };
-    if !max_date_str.is_empty() {
-        if has_params {
-            query.push_str(" AND");
+    /// Pass the file index entries selected by the filter to a callback, oldest first.
+    pub fn query(&self, filter: &QueryFilter, mut callback: impl FnMut(IndexFile)) -> Result<()> {
+        let QueryFilter {
+            sha256s,
+            filename,
+            url,
+            limit,
+            min_date,
+            max_date,
+            near,
+            meta_equals,
+            places,
+        } = filter;
+        if *places || meta_equals.iter().any(|m| is_geocoded(&m.variable)) {
+            self.geocode();
+        }
+        let has_meta = self.has_meta();
+        let meta_columns = self.meta_columns();
+        let mut query;
+        if has_meta {
+            query =
+                String::from("SELECT filename, url, fileindex.sha256, created_at, modified_at, media_type, meta.* FROM fileindex JOIN meta ON (fileindex.sha256 = meta.sha256)");
         } else {
-            query.push_str(" WHERE");
+            query = String::from(
+                "SELECT filename, url, sha256, created_at, modified_at, media_type FROM fileindex",
+            );
         }
-        query.push_str(&max_date_str);
-        #[allow(unused_assignments)]
-        {
+
+        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+        let mut has_params = false;
+
+        let sha256_vec: Vec<&str> = sha256s
+            .as_ref()
+            .map(|s| s.split(',').collect())
+            .unwrap_or_else(Vec::new);
+        if !sha256_vec.is_empty() {
+            let placeholders = sha256_vec.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+            query.push_str(" WHERE sha256 IN (");
+            query.push_str(&placeholders);
+            query.push(')');
+            for p in sha256_vec.iter().map(|s| Box::new(String::from(*s))) {
+                params.push(p);
+            }
+            has_params = true;
+        }
+
+        let filename_format_string: String;
+        if let Some(filename) = filename {
+            if has_params {
+                query.push_str(" AND filename LIKE ?");
+            } else {
+                query.push_str(" WHERE filename LIKE ?");
+            }
+            filename_format_string = format!("%{}%", &filename);
+            params.push(Box::new(String::from(&filename_format_string)));
+            has_params = true;
+        }
+
+        let url_format_string: String;
+        if let Some(url) = url {
+            if has_params {
+                query.push_str(" AND url LIKE ?");
+            } else {
+                query.push_str(" WHERE url LIKE ?");
+            }
+            url_format_string = format!("%{}%", &url);
+            params.push(Box::new(String::from(&url_format_string)));
+            has_params = true;
+        }
+
+        let min_date_str = if let Some(min_date) = min_date {
+            format!(
+                " created_at >= CAST('{}' AS TIMESTAMP) AND modified_at >= CAST('{}' AS TIMESTAMP)",
+                min_date.to_rfc3339(),
+                min_date.to_rfc3339()
+            )
+        } else {
+            String::new()
+        };
+        if !min_date_str.is_empty() {
+            if has_params {
+                query.push_str(" AND");
+            } else {
+                query.push_str(" WHERE");
+            }
+            query.push_str(&min_date_str);
+            has_params = true;
+        }
+
+        let max_date_str = if let Some(max_date) = max_date {
+            format!(
+                " created_at <= CAST('{}' AS TIMESTAMP) AND modified_at <= CAST('{}' AS TIMESTAMP)",
+                max_date.to_rfc3339(),
+                max_date.to_rfc3339()
+            )
+        } else {
+            String::new()
+        };
+        if !max_date_str.is_empty() {
+            if has_params {
+                query.push_str(" AND");
+            } else {
+                query.push_str(" WHERE");
+            }
+            query.push_str(&max_date_str);
+            has_params = true;
+        }
+
+        if let Some(near) = near {
+            if has_params {
+                query.push_str(" AND");
+            } else {
+                query.push_str(" WHERE");
+            }
+            if has_meta {
+                // haversine distance, see geo::distance_km
+                query.push_str(
+                    " 2 * ? * asin(sqrt(\
+                     pow(sin(radians(GPSLatitude - ?) / 2), 2) + \
+                     cos(radians(?)) * cos(radians(GPSLatitude)) * \
+                     pow(sin(radians(GPSLongitude - ?) / 2), 2))) <= ?",
+                );
+                params.push(Box::new(geo::EARTH_RADIUS_KM));
+                params.push(Box::new(near.latitude));
+                params.push(Box::new(near.latitude));
+                params.push(Box::new(near.longitude));
+                params.push(Box::new(near.radius_km));
+            } else {
+                // no metadata, so no locations
+                query.push_str(" FALSE");
+            }
             has_params = true;
         }
+
+        for MetaEquals { variable, value } in meta_equals {
+            if has_params {
+                query.push_str(" AND");
+            } else {
+                query.push_str(" WHERE");
+            }
+            let column = match (has_meta, &meta_columns) {
+                (true, Ok(columns)) => columns
+                    .iter()
+                    .find(|(_, name, _)| name.eq_ignore_ascii_case(variable)),
+                _ => None,
+            };
+            match column {
+                Some((_, name, _)) => {
+                    query.push_str(&format!(" CAST(meta.\"{}\" AS TEXT) = ?", name));
+                    params.push(Box::new(value.clone()));
+                }
+                None => {
+                    warn!("Unknown metadata variable {}, no files match", variable);
+                    query.push_str(" FALSE");
+                }
+            }
+            has_params = true;
+        }
+
+        let limit_str: String;
+        if let Some(limit) = limit {
+            limit_str = format!("LIMIT {limit}");
+            query.push_str(&limit_str);
+        }
+
+        query.push_str(" ORDER BY CREATED_AT");
+
+        {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&query)?;
+            debug!("{:?}", query);
+
+            // Convert the params to a slice of references
+            let params_refs: Vec<&dyn ToSql> = params.iter().map(|p| &**p).collect();
+
+            let indexfile_iter = stmt.query_map(params_from_iter(params_refs), |row| {
+                Ok({
+                    let filename: String = row.get(0).expect("Failed to get filename");
+                    let url: String = row.get(1).expect("Failed to get url");
+                    let sha256: String = row.get(2).expect("Failed to get sha256");
+                    let created_at: DateTime<chrono::Utc> =
+                        row.get(3).expect("Failed to get created_at");
+                    let modified_at: DateTime<chrono::Utc> =
+                        row.get(4).expect("Failed to get modified_at");
+                    let media_type: Option<String> = row.get(5).expect("Failed to get media_type");
+                    let mut meta = Vec::new();
+
+                    if let Ok(ref meta_columns) = meta_columns {
+                        for (idx, col) in (6..).zip(meta_columns.iter()) {
+                            let value = row.get_ref_unwrap(idx);
+                            match value {
+                                ValueRef::Null => {
+                                    meta.push(MetaVariable {
+                                        name: col.1.clone(),
+                                        value: MetaValue::Null,
+                                    });
+                                }
+                                ValueRef::Boolean(b) => {
+                                    meta.push(MetaVariable {
+                                        name: col.1.clone(),
+                                        value: MetaValue::Bool(b),
+                                    });
+                                }
+                                ValueRef::Double(_) | ValueRef::Float(_) => {
+                                    meta.push(MetaVariable {
+                                        name: col.1.clone(),
+                                        value: MetaValue::Float(f64::column_result(value).unwrap()),
+                                    });
+                                }
+                                ValueRef::Int(_) | ValueRef::BigInt(_) => {
+                                    meta.push(MetaVariable {
+                                        name: col.1.clone(),
+                                        value: MetaValue::Int(i64::column_result(value).unwrap()),
+                                    });
+                                }
+                                ValueRef::UInt(_) | ValueRef::UBigInt(_) => {
+                                    meta.push(MetaVariable {
+                                        name: col.1.clone(),
+                                        value: MetaValue::UInt(u64::column_result(value).unwrap()),
+                                    });
+                                }
+                                ValueRef::Text(s) => {
+                                    let decoded_string = String::from_utf8_lossy(s).to_string();
+                                    meta.push(MetaVariable {
+                                        name: col.1.clone(),
+                                        value: MetaValue::String(decoded_string),
+                                    });
+                                }
+                                ValueRef::Timestamp(_, _)
+                                | ValueRef::Date32(_)
+                                | ValueRef::Time64(_, _) => {
+                                    let d = DateTime::<Utc>::column_result(value).unwrap();
+                                    meta.push(MetaVariable {
+                                        name: col.1.clone(),
+                                        value: MetaValue::Date(d),
+                                    });
+                                }
+                                _ => {
+                                    error!(
+                                        "Unexpected value type in meta column {}: {:?}",
+                                        col.1, value
+                                    );
+                                }
+                            };
+                        }
+                    }
+
+                    if let Some(ref mappings) = self.mappings {
+                        apply_mappings(mappings, &mut meta);
+                    }
+
+                    fileindex::IndexFile {
+                        filename,
+                        url,
+                        sha256,
+                        created_at,
+                        modified_at,
+                        media_type,
+                        meta,
+                    }
+                })
+            });
+
+            for indexfile in indexfile_iter? {
+                callback(indexfile?);
+            }
+        }
+        Ok(())
     }
+}
 
-    let limit_str: String;
-    if let Some(limit) = limit {
-        limit_str = format!("LIMIT {limit}");
-        query.push_str(&limit_str);
+/// SQL expression that combines the metadata from the default metadata command
+/// and from all named extractors into one JSON object. Where several extractors
+/// give the same key, the default command wins, then extractors in the order they
+/// are configured.
+fn meta_json_sql(extractors: &[libraryconfig::ExtractorConfig], row: &str) -> String {
+    let mut sql = String::from(row);
+    for extractor in extractors {
+        let namespace = format!(
+            "$.{}.\"{}\"",
+            jsonmeta::EXTRACTORS_KEY,
+            extractor.name.replace('\'', "''").replace('"', "")
+        );
+        sql = format!(
+            "json_merge_patch(COALESCE(json_extract({}, '{}'), '{{}}'), {})",
+            row, namespace, sql
+        );
+    }
+    sql
+}
+
+/// Photos farther than this from any place of the gazetteer are not geocoded
+const DEFAULT_GEOCODING_DISTANCE_KM: f64 = 200.0;
+
+/// Variables of the meta table filled by `geocode_meta`
+const GEOCODED_VARIABLES: [&str; 4] = ["Country", "CountryCode", "Region", "City"];
+
+fn is_geocoded(variable: &str) -> bool {
+    GEOCODED_VARIABLES
+        .iter()
+        .any(|v| v.eq_ignore_ascii_case(variable.trim()))
+}
+
+/// The configured GeoNames gazetteer, or the built-in one if there is none
+/// or it cannot be read
+fn load_gazetteer(library: &Path, config: &GeocodingConfig) -> Gazetteer {
+    let Some(ref cities) = config.cities else {
+        return Gazetteer::builtin();
+    };
+    let admin1 = config.admin1.as_ref().map(|admin1| library.join(admin1));
+    match Gazetteer::load_geonames(&library.join(cities), admin1.as_deref()) {
+        Ok(gazetteer) => {
+            info!(
+                "Loaded {} places for geocoding from {}",
+                gazetteer.len(),
+                cities
+            );
+            gazetteer
+        }
+        Err(e) => {
+            warn!(
+                "Cannot load gazetteer {}, using the built-in one: {}",
+                cities, e
+            );
+            Gazetteer::builtin()
+        }
     }
+}
 
-    query.push_str(" ORDER BY CREATED_AT");
+/// Fill the Country, CountryCode, Region and City columns of the meta table
+/// from the GPS location of each file, or the location inferred from GPS
+/// tracks if it has none. Returns the number of files geocoded.
+fn geocode_meta(conn: &Connection, gazetteer: &Gazetteer, max_distance_km: f64) -> Result<usize> {
+    let locations = {
+        let mut stmt = conn.prepare(
+            "SELECT sha256, \
+             CASE WHEN has_gps THEN GPSLatitude ELSE InferredLatitude END, \
+             CASE WHEN has_gps THEN GPSLongitude ELSE InferredLongitude END \
+             FROM (SELECT *, GPSLatitude IS NOT NULL AND GPSLongitude IS NOT NULL AS has_gps FROM meta) \
+             WHERE has_gps OR (InferredLatitude IS NOT NULL AND InferredLongitude IS NOT NULL)",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, f64>(1)?,
+                row.get::<_, f64>(2)?,
+            ))
+        })?;
+        rows.collect::<Result<Vec<_>>>()?
+    };
+    if locations.is_empty() {
+        return Ok(0);
+    }
 
+    conn.execute_batch(
+        "CREATE OR REPLACE TEMP TABLE geocoded \
+         (sha256 TEXT, Country TEXT, CountryCode TEXT, Region TEXT, City TEXT)",
+    )?;
+    let mut count = 0;
     {
-        let conn = DB.get().expect("Database not initialized");
-        let conn = conn.lock().unwrap();
-        let mut stmt = conn.prepare(&query)?;
-        debug!("{:?}", query);
-
-        // Convert the params to a slice of references
-        let params_refs: Vec<&dyn ToSql> = params.iter().map(|p| &**p).collect();
-
-        let indexfile_iter = stmt.query_map(params_from_iter(params_refs), |row| {
-            Ok({
-                let filename: String = row.get(0).expect("Failed to get filename");
-                let url: String = row.get(1).expect("Failed to get url");
-                let sha256: String = row.get(2).expect("Failed to get sha256");
-                let created_at: DateTime<chrono::Utc> =
-                    row.get(3).expect("Failed to get created_at");
-                let modified_at: DateTime<chrono::Utc> =
-                    row.get(4).expect("Failed to get modified_at");
-                let mut meta = Vec::new();
-
-                if let Ok(ref meta_columns) = meta_columns {
-                    let mut idx = 5;
-                    for col in meta_columns {
-                        let value = row.get_ref_unwrap(idx);
-                        match value {
-                            ValueRef::Null => {
-                                meta.push(MetaVariable {
-                                    name: col.1.clone(),
-                                    value: MetaValue::Null,
-                                });
-                            }
-                            ValueRef::Boolean(b) => {
-                                meta.push(MetaVariable {
-                                    name: col.1.clone(),
-                                    value: MetaValue::Bool(b),
-                                });
-                            }
-                            ValueRef::Double(_) | ValueRef::Float(_) => {
-                                meta.push(MetaVariable {
-                                    name: col.1.clone(),
-                                    value: MetaValue::Float(f64::column_result(value).unwrap()),
-                                });
-                            }
-                            ValueRef::Int(_) | ValueRef::BigInt(_) => {
-                                meta.push(MetaVariable {
-                                    name: col.1.clone(),
-                                    value: MetaValue::Int(i64::column_result(value).unwrap()),
-                                });
-                            }
-                            ValueRef::UInt(_) | ValueRef::UBigInt(_) => {
-                                meta.push(MetaVariable {
-                                    name: col.1.clone(),
-                                    value: MetaValue::UInt(u64::column_result(value).unwrap()),
-                                });
-                            }
-                            ValueRef::Text(s) => {
-                                let decoded_string = String::from_utf8_lossy(s).to_string();
-                                meta.push(MetaVariable {
-                                    name: col.1.clone(),
-                                    value: MetaValue::String(decoded_string),
-                                });
-                            }
-                            ValueRef::Timestamp(_, _)
-                            | ValueRef::Date32(_)
-                            | ValueRef::Time64(_, _) => {
-                                let d = DateTime::<Utc>::column_result(value).unwrap();
-                                meta.push(MetaVariable {
-                                    name: col.1.clone(),
-                                    value: MetaValue::Date(d),
-                                });
-                            }
-                            _ => {
-                                error!(
-                                    "Unexpected value type in meta column {}: {:?}",
-                                    col.1, value
-                                );
-                            }
-                        };
-                        idx += 1;
-                    }
-                }
+        let mut appender = conn.appender("geocoded")?;
+        for (sha256, latitude, longitude) in &locations {
+            let Some((place, distance)) = gazetteer.nearest(*latitude, *longitude) else {
+                continue;
+            };
+            if distance > max_distance_km {
+                continue;
+            }
+            appender.append_row(params![
+                sha256,
+                gazetteer.country_name(&place.country_code),
+                place.country_code,
+                place.region,
+                place.name
+            ])?;
+            count += 1;
+        }
+        appender.flush()?;
+    }
+    conn.execute_batch(
+        "UPDATE meta SET Country = geocoded.Country, CountryCode = geocoded.CountryCode, \
+         Region = geocoded.Region, City = geocoded.City \
+         FROM geocoded WHERE meta.sha256 = geocoded.sha256;\
+         DROP TABLE geocoded;",
+    )?;
+    Ok(count)
+}
 
-                if let Some(mappings) = MAPPINGS.get() {
-                    apply_mappings(mappings, &mut meta);
-                }
+/// A file that was hashed and had its metadata extracted, to be added to
+/// the database with `write_records`
+#[derive(Debug)]
+pub struct IndexRecord {
+    /// Canonical path of the file, or the path as given if it cannot be resolved
+    pub filename: String,
+    /// None if the file cannot be read
+    pub fileinfo: Option<IndexFile>,
+    /// Errors while indexing, replacing those recorded for earlier runs
+    pub errors: Vec<IndexError>,
+    /// Metadata to store in the database, if the library keeps them there
+    pub meta: Option<serde_json::Value>,
+    /// Change log of the metadata to store in the database, if it changed
+    pub history: Option<Vec<serde_json::Value>>,
+}
 
-                fileindex::IndexFile {
-                    filename,
-                    url,
-                    sha256,
-                    created_at,
-                    modified_at,
-                    meta,
+/// First stage of indexing a file: read its file system metadata and hash it.
+/// None if the file is only indexed as a photo or video and it is neither.
+pub fn hash_file(file: &FoundFile) -> Option<IndexRecord> {
+    let filename = std::fs::canonicalize(&file.path)
+        .ok()
+        .and_then(|p| p.to_str().map(String::from))
+        .unwrap_or_else(|| file.path.clone());
+    let record = match fileindex::IndexFile::new(&filename) {
+        Ok(fileinfo) if file.media_only && fileinfo.media_type.is_none() => {
+            debug!("Skipping {}, it is not a photo or video", filename);
+            return None;
+        }
+        Ok(fileinfo) => IndexRecord {
+            filename,
+            fileinfo: Some(fileinfo),
+            errors: Vec::new(),
+            meta: None,
+            history: None,
+        },
+        Err(e) => IndexRecord {
+            filename,
+            fileinfo: None,
+            errors: vec![e],
+            meta: None,
+            history: None,
+        },
+    };
+    Some(record)
+}
+
+/// Run an extractor on a file and combine the output with the current metadata
+/// of this extractor. When the extractor fails, the failure is recorded under
+/// `meta_error` and the current metadata are kept. Failures are also added to
+/// `errors`.
+fn run_extractor(
+    extractor: &NamedExtractor,
+    filename: &str,
+    current: serde_json::Value,
+    merge_rules: Option<&MergeRules>,
+    errors: &mut Vec<IndexError>,
+) -> serde_json::Value {
+    let meta_cmd = &extractor.extractor;
+    match meta_cmd.run(filename) {
+        Ok(output) => {
+            let json_val = match serde_json::from_str(&output.stdout) {
+                Ok(json_val) => json_val,
+                Err(e) => {
+                    error!(
+                        "Cannot parse output for {} {}: {} / {}",
+                        meta_cmd.command_line(),
+                        filename,
+                        output.stdout,
+                        output.stderr
+                    );
+                    errors.push(IndexError::InvalidJson {
+                        command: meta_cmd.command_line(),
+                        error: e,
+                    });
+                    serde_json::Value::Null
                 }
-            })
-        });
+            };
+            jsonmeta::update(current, json_val, merge_rules)
+        }
+        Err(e) => {
+            error!(
+                "Failed to execute {} for {}: {}",
+                meta_cmd.command_line(),
+                filename,
+                e
+            );
+            let exit_code = match e {
+                MetaCommandError::Failed { ref status, .. } => status.code(),
+                _ => None,
+            };
+            let stderr = match e {
+                MetaCommandError::Failed { ref stderr, .. }
+                | MetaCommandError::NoOutput { ref stderr } => stderr.clone(),
+                _ => String::new(),
+            };
+            let recorded = jsonmeta::record_error(
+                current,
+                json!({
+                    "command": meta_cmd.command_line(),
+                    "message": e.to_string(),
+                    "exit_code": exit_code,
+                    "stderr": stderr,
+                    "timestamp": Utc::now().to_rfc3339(),
+                }),
+            );
+            errors.push(IndexError::MetaCommand {
+                command: meta_cmd.command_line(),
+                error: e,
+            });
+            recorded
+        }
+    }
+}
+
+/// Sidecars that are not in the `rawmeta` table, or were written after their
+/// row, are imported with their change logs. Libraries that switch to
+/// database storage keep their metadata this way. Returns the number of
+/// files imported.
+fn import_sidecars(conn: &mut Connection, library: &Path) -> std::result::Result<usize, OpenError> {
+    let sidecars = sidecar::list_sidecars(library).map_err(OpenError::Import)?;
+    if sidecars.is_empty() {
+        return Ok(0);
+    }
+    let updated: HashMap<String, i64> = {
+        let mut stmt = conn.prepare("SELECT sha256, epoch_ms(updated_at) FROM rawmeta")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_>>()?
+    };
+    let modified_ms = |path: &Path| -> std::io::Result<i64> {
+        let modified = std::fs::metadata(path)?.modified()?;
+        Ok(DateTime::<Utc>::from(modified).timestamp_millis())
+    };
 
-        for indexfile in indexfile_iter? {
-            callback(indexfile?);
+    let tx = conn.transaction()?;
+    let mut imported = 0;
+    {
+        let mut delete_meta = tx.prepare("DELETE FROM rawmeta WHERE sha256 = ?")?;
+        let mut insert_meta =
+            tx.prepare("INSERT INTO rawmeta (sha256, metadata, updated_at) VALUES (?, ?, ?)")?;
+        let mut delete_history = tx.prepare("DELETE FROM metahistory WHERE sha256 = ?")?;
+        let mut insert_history =
+            tx.prepare("INSERT INTO metahistory (sha256, history, updated_at) VALUES (?, ?, ?)")?;
+        let now = Utc::now().to_string();
+        for (sha256, path) in &sidecars {
+            let modified = modified_ms(path).map_err(OpenError::Import)?;
+            if updated
+                .get(sha256)
+                .is_some_and(|updated| *updated >= modified)
+            {
+                continue;
+            }
+            let metadata = std::fs::read_to_string(path).map_err(OpenError::Import)?;
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(&metadata) {
+                warn!("Not importing {}: {}", path.display(), e);
+                continue;
+            }
+            let history =
+                read_history(&sidecar::history_path(library, sha256)).map_err(OpenError::Import)?;
+            delete_meta.execute(params![sha256])?;
+            insert_meta.execute(params![sha256, metadata, &now])?;
+            if !history.is_empty() {
+                let history = serde_json::Value::from(history).to_string();
+                delete_history.execute(params![sha256])?;
+                insert_history.execute(params![sha256, history, &now])?;
+            }
+            imported += 1;
         }
     }
+    tx.commit()?;
+    Ok(imported)
+}
+
+/// Read a change log file, one JSON version per line. Empty if there is none.
+fn read_history(path: &Path) -> std::io::Result<Vec<serde_json::Value>> {
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    BufReader::new(file)
+        .lines()
+        .map(|line| serde_json::from_str(&line?).map_err(invalid))
+        .collect()
+}
+
+/// Write a change log with one JSON entry per line
+fn write_history(path: &Path, history: &[serde_json::Value]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    for entry in history {
+        writeln!(file, "{}", entry)?;
+    }
     Ok(())
 }
+
+/// Write JSON metadata for a file
+fn write_json(json_path: &std::path::Path, json_val: &serde_json::Value) -> std::io::Result<()> {
+    if let Some(parent) = json_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(json_path)?;
+    file.write_all(json_val.to_string().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{hash_exif_jpg, TempDir};
+
+    #[test]
+    fn test_database_meta_store() {
+        let dir = TempDir::new("rawmeta");
+        std::fs::write(dir.join("photocat.toml"), "[storage]\nmeta = 'database'\n").unwrap();
+        let library = Library::init(dir.to_str().unwrap()).unwrap();
+
+        let mut record = hash_exif_jpg();
+        let sha256 = record.fileinfo.as_ref().unwrap().sha256.clone();
+        record.meta = Some(json!({"Make": "Canon", "sha256": sha256}));
+        record.history = Some(vec![json!({"version": 1, "initial": true})]);
+        library
+            .write_records(std::slice::from_ref(&record))
+            .unwrap();
+
+        assert_eq!(library.stored_meta(&sha256).unwrap()["Make"], "Canon");
+        assert_eq!(library.stored_history(&sha256).unwrap().len(), 1);
+        assert!(library.stored_history("0000").unwrap().is_empty());
+        assert!(!library.json_path(&sha256).exists());
+        let missing = library.stored_meta("0000").unwrap_err();
+        assert_eq!(missing.kind(), std::io::ErrorKind::NotFound);
+
+        let export = dir.join("export");
+        assert_eq!(library.export_sidecars(&export).unwrap(), 1);
+        let exported = std::fs::read_to_string(sidecar::sidecar_path(&export, &sha256)).unwrap();
+        assert!(exported.contains("Canon"));
+        assert!(sidecar::history_path(&export, &sha256).is_file());
+    }
+
+    #[test]
+    fn test_hash_file_media_only() {
+        let dir = TempDir::new("hash-media-only");
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, "not a photo").unwrap();
+        let mut file = FoundFile::from(notes.to_str().unwrap().to_string());
+        assert!(hash_file(&file).unwrap().fileinfo.is_some());
+        // skipped when no extensions were given
+        file.media_only = true;
+        assert!(hash_file(&file).is_none());
+        let photo = FoundFile {
+            media_only: true,
+            ..FoundFile::from(crate::testutil::fixture("exif.jpg"))
+        };
+        let record = hash_file(&photo).unwrap();
+        assert_eq!(
+            record.fileinfo.unwrap().media_type.as_deref(),
+            Some("image/jpeg")
+        );
+    }
+
+    #[test]
+    fn test_write_records_is_atomic() {
+        let dir = TempDir::new("rawmeta-atomic");
+        std::fs::write(dir.join("photocat.toml"), "[storage]\nmeta = 'database'\n").unwrap();
+        let library = Library::init(dir.to_str().unwrap()).unwrap();
+        let mut record = hash_exif_jpg();
+        record.meta = Some(json!({"Make": "Canon"}));
+
+        // a failing metadata insert also undoes the fileindex insert
+        library
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DROP TABLE rawmeta", [])
+            .unwrap();
+        assert!(library
+            .write_records(std::slice::from_ref(&record))
+            .is_err());
+        assert!(library.indexed_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_switch_to_database_storage() {
+        let dir = TempDir::new("rawmeta-switch");
+        let library = Library::init(dir.to_str().unwrap()).unwrap();
+        // indexed with sidecar storage, metadata only goes to the sidecars
+        let record = hash_exif_jpg();
+        let sha256 = record.fileinfo.as_ref().unwrap().sha256.clone();
+        write_json(&library.json_path(&sha256), &json!({"Make": "Canon"})).unwrap();
+        write_history(
+            &sidecar::history_path(&dir, &sha256),
+            &[json!({"version": 1, "initial": true})],
+        )
+        .unwrap();
+        library
+            .write_records(std::slice::from_ref(&record))
+            .unwrap();
+        drop(library);
+
+        std::fs::write(dir.join("photocat.toml"), "[storage]\nmeta = 'database'\n").unwrap();
+        let library = Library::open(dir.to_str().unwrap()).unwrap();
+        assert_eq!(library.stored_meta(&sha256).unwrap()["Make"], "Canon");
+        assert_eq!(library.stored_history(&sha256).unwrap().len(), 1);
+        drop(library);
+
+        // imported rows are newer than the sidecars, which are left alone
+        let mut conn = Connection::open(dir.join(DB_FILE)).unwrap();
+        assert_eq!(import_sidecars(&mut conn, &dir).unwrap(), 0);
+        assert!(sidecar::sidecar_path(&dir, &sha256).is_file());
+    }
+
+    #[test]
+    fn test_parse_meta_equals() {
+        assert_eq!(
+            "City = Rio de Janeiro".parse::<MetaEquals>(),
+            Ok(MetaEquals {
+                variable: String::from("City"),
+                value: String::from("Rio de Janeiro"),
+            })
+        );
+        assert_eq!("Region=".parse::<MetaEquals>().unwrap().value, "");
+        assert!("Country".parse::<MetaEquals>().is_err());
+        assert!("=Italy".parse::<MetaEquals>().is_err());
+    }
+
+    #[test]
+    fn test_geocoded_meta() {
+        let dir = TempDir::new("geocode-db");
+        std::fs::write(dir.join("photocat.toml"), "[storage]\nmeta = 'database'\n").unwrap();
+        let library = Library::init(dir.to_str().unwrap()).unwrap();
+        let mut record = hash_exif_jpg();
+        let sha256 = record.fileinfo.as_ref().unwrap().sha256.clone();
+        record.meta = Some(json!({
+            "sha256": sha256,
+            "GPSLatitude": "48 deg 51' 36.00\" N",
+            "GPSLongitude": "2 deg 20' 24.00\" E"
+        }));
+        library
+            .write_records(std::slice::from_ref(&record))
+            .unwrap();
+        drop(library);
+
+        // the meta table is built when the library is opened, and places
+        // are only looked up once a query needs them
+        let library = Library::open(dir.to_str().unwrap()).unwrap();
+        let mut files = Vec::new();
+        library
+            .query(&QueryFilter::default(), |f| files.push(f))
+            .unwrap();
+        assert!(matches!(files[0].value("City"), Some(MetaValue::Null)));
+        let filter = QueryFilter {
+            places: true,
+            ..QueryFilter::default()
+        };
+        let mut files = Vec::new();
+        library.query(&filter, |f| files.push(f)).unwrap();
+        assert!(
+            matches!(files[0].value("City"), Some(MetaValue::String(ref city)) if city == "Paris")
+        );
+        let select = |variable: &str, value: &str| {
+            let filter = QueryFilter {
+                meta_equals: vec![format!("{}={}", variable, value).parse().unwrap()],
+                ..QueryFilter::default()
+            };
+            let mut files = Vec::new();
+            library.query(&filter, |f| files.push(f)).unwrap();
+            files
+        };
+        let files = select("Country", "France");
+        assert_eq!(files.len(), 1);
+        assert!(
+            matches!(files[0].value("City"), Some(MetaValue::String(ref city)) if city == "Paris")
+        );
+        assert_eq!(select("country", "France").len(), 1);
+        let counts = library
+            .value_counts(&QueryFilter::default(), "Country")
+            .unwrap()
+            .unwrap();
+        assert_eq!(counts, HashMap::from([(String::from("France"), 1)]));
+        assert!(library
+            .value_counts(&QueryFilter::default(), "NoSuchVariable")
+            .unwrap()
+            .is_none());
+        assert!(select("Country", "Italy").is_empty());
+        assert!(select("NoSuchVariable", "France").is_empty());
+        drop(library);
+
+        // a gazetteer that cannot be read falls back to the built-in one
+        std::fs::write(
+            dir.join("photocat.toml"),
+            "[storage]\nmeta = 'database'\n[geocoding]\ncities = 'missing.txt'\n",
+        )
+        .unwrap();
+        let library = Library::open(dir.to_str().unwrap()).unwrap();
+        let filter = QueryFilter {
+            meta_equals: vec!["City=Paris".parse().unwrap()],
+            ..QueryFilter::default()
+        };
+        let mut files = Vec::new();
+        library.query(&filter, |f| files.push(f)).unwrap();
+        assert_eq!(files.len(), 1);
+    }
+}
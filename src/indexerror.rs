@@ -0,0 +1,137 @@
+use std::fmt;
+use std::io;
+
+use crate::metacmd::MetaCommandError;
+
+/// Step of indexing a single file at which an error occurred
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IndexStage {
+    /// Reading the file name and file system metadata
+    Stat,
+    /// Hashing the file contents
+    Hash,
+    /// Running a metadata command
+    Meta,
+    /// Reading the output of a metadata command
+    Parse,
+    /// Adding the file to the database
+    Db,
+}
+
+impl IndexStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexStage::Stat => "stat",
+            IndexStage::Hash => "hash",
+            IndexStage::Meta => "meta",
+            IndexStage::Parse => "parse",
+            IndexStage::Db => "db",
+        }
+    }
+}
+
+impl fmt::Display for IndexStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// An error while indexing a single file, recorded in the `index_errors` table
+#[derive(Debug)]
+pub enum IndexError {
+    /// The path cannot be resolved
+    Canonicalize(io::Error),
+    /// The path is not valid UTF-8 or cannot be turned into a URL
+    InvalidPath(String),
+    /// The path is not a regular file
+    NotAFile(String),
+    /// File system metadata cannot be read
+    Metadata(io::Error),
+    /// The file cannot be read for hashing
+    Hash(io::Error),
+    /// A metadata command failed
+    MetaCommand {
+        command: String,
+        error: MetaCommandError,
+    },
+    /// A metadata command did not produce valid JSON
+    InvalidJson {
+        command: String,
+        error: serde_json::Error,
+    },
+    /// The JSON metadata file cannot be written
+    WriteJson { path: String, error: io::Error },
+    /// The file cannot be added to the database
+    Database(duckdb::Error),
+}
+
+impl IndexError {
+    /// The stage at which this error occurred
+    pub fn stage(&self) -> IndexStage {
+        match self {
+            IndexError::Canonicalize(_)
+            | IndexError::InvalidPath(_)
+            | IndexError::NotAFile(_)
+            | IndexError::Metadata(_) => IndexStage::Stat,
+            IndexError::Hash(_) => IndexStage::Hash,
+            IndexError::MetaCommand { .. } | IndexError::WriteJson { .. } => IndexStage::Meta,
+            IndexError::InvalidJson { .. } => IndexStage::Parse,
+            IndexError::Database(_) => IndexStage::Db,
+        }
+    }
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::Canonicalize(e) => write!(f, "Cannot resolve path: {}", e),
+            IndexError::InvalidPath(path) => write!(f, "Invalid path: {}", path),
+            IndexError::NotAFile(path) => write!(f, "Not a file: {}", path),
+            IndexError::Metadata(e) => write!(f, "Cannot read file metadata: {}", e),
+            IndexError::Hash(e) => write!(f, "Cannot hash file: {}", e),
+            IndexError::MetaCommand { command, error } => write!(f, "{}: {}", command, error),
+            IndexError::InvalidJson { command, error } => {
+                write!(f, "Invalid JSON from {}: {}", command, error)
+            }
+            IndexError::WriteJson { path, error } => write!(f, "Cannot write {}: {}", path, error),
+            IndexError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl From<duckdb::Error> for IndexError {
+    fn from(e: duckdb::Error) -> Self {
+        IndexError::Database(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage() {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, "not found");
+        assert_eq!(
+            IndexError::Canonicalize(not_found()).stage(),
+            IndexStage::Stat
+        );
+        assert_eq!(IndexError::Hash(not_found()).stage(), IndexStage::Hash);
+        let error = IndexError::MetaCommand {
+            command: String::from("exiftool -j -"),
+            error: MetaCommandError::Io(not_found()),
+        };
+        assert_eq!(error.stage(), IndexStage::Meta);
+        assert_eq!(error.stage().as_str(), "meta");
+        let error = IndexError::InvalidJson {
+            command: String::from("exiftool -j -"),
+            error: serde_json::from_str::<serde_json::Value>("{").unwrap_err(),
+        };
+        assert_eq!(error.stage(), IndexStage::Parse);
+        assert!(error
+            .to_string()
+            .starts_with("Invalid JSON from exiftool -j -"));
+    }
+}
@@ -0,0 +1,276 @@
+//! Indexing as a pipeline of stages with their own concurrency limits.
+//!
+//! Hashing is limited by disk throughput and metadata extraction by the
+//! number of metadata commands that can run at once, so each stage has its
+//! own limit. Indexed files are sent over a bounded channel to a single
+//! writer thread, which adds them to the database in batched transactions.
+
+use log::{debug, error};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+use crate::filewalk::FoundFile;
+use crate::indexdb::{self, IndexRecord, Library};
+use crate::indexerror::IndexError;
+use crate::jsonmeta::MergeRules;
+use crate::metacmd::NamedExtractor;
+use crate::processing;
+
+/// Longest time an indexed file waits in a partial batch before it is written
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Collects items sent from many tasks on a thread, and passes them on in
+/// batches of up to `batch_size`. Partial batches are passed on after
+/// `FLUSH_INTERVAL` so that progress is saved while files are slow to index.
+pub struct BatchWriter<T> {
+    sender: SyncSender<T>,
+    handle: JoinHandle<()>,
+}
+
+impl<T: Send + 'static> BatchWriter<T> {
+    pub fn start(batch_size: usize, mut write: impl FnMut(Vec<T>) + Send + 'static) -> Self {
+        let batch_size = batch_size.max(1);
+        // senders block when the writer falls behind by more than a batch
+        let (sender, receiver) = mpsc::sync_channel::<T>(batch_size);
+        let handle = thread::spawn(move || {
+            let mut batch = Vec::with_capacity(batch_size);
+            let mut deadline = Instant::now() + FLUSH_INTERVAL;
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let done = match receiver.recv_timeout(timeout) {
+                    Ok(item) => {
+                        batch.push(item);
+                        false
+                    }
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => true,
+                };
+                if batch.len() >= batch_size || Instant::now() >= deadline || done {
+                    if !batch.is_empty() {
+                        write(std::mem::replace(
+                            &mut batch,
+                            Vec::with_capacity(batch_size),
+                        ));
+                    }
+                    deadline = Instant::now() + FLUSH_INTERVAL;
+                }
+                if done {
+                    break;
+                }
+            }
+        });
+        BatchWriter { sender, handle }
+    }
+
+    /// A sender for tasks to pass items to the writer
+    pub fn sender(&self) -> SyncSender<T> {
+        self.sender.clone()
+    }
+
+    /// Wait until all items sent so far have been written. All senders
+    /// handed out must have been dropped.
+    pub fn finish(self) {
+        drop(self.sender);
+        if self.handle.join().is_err() {
+            error!("Index writer stopped unexpectedly");
+        }
+    }
+}
+
+/// Start the writer that adds indexed files to the database of a library
+fn start_index_writer(library: Library, batch_size: usize) -> BatchWriter<IndexRecord> {
+    BatchWriter::start(batch_size, move |records| write_records(&library, records))
+}
+
+/// Write a batch of indexed files. If the transaction fails, files are written
+/// one at a time so that a single bad file does not lose the whole batch.
+fn write_records(library: &Library, records: Vec<IndexRecord>) {
+    debug!("Writing {} indexed files", records.len());
+    if let Err(e) = library.write_records(&records) {
+        debug!(
+            "Batch of {} files failed ({}), retrying each",
+            records.len(),
+            e
+        );
+        for record in &records {
+            if let Err(e) = library.write_records(std::slice::from_ref(record)) {
+                error!(
+                    "Error indexing {}: {}",
+                    record.filename,
+                    IndexError::from(e)
+                );
+            }
+        }
+    }
+}
+
+/// How to index files
+#[derive(Default, Clone)]
+pub struct IndexOptions {
+    /// Commands to produce metadata, each is run if it applies to a file
+    pub extractors: Vec<NamedExtractor>,
+    /// How to merge with existing metadata, None to overwrite
+    pub merge_rules: Option<MergeRules>,
+    /// Maximum number of files in progress (hash and meta concurrency combined by default)
+    pub concurrency: Option<usize>,
+    /// Number of files to hash at once (number of CPUs by default)
+    pub hash_concurrency: Option<usize>,
+    /// Number of metadata commands to run at once (number of CPUs by default)
+    pub meta_concurrency: Option<usize>,
+    /// Number of files to add to the database in each transaction (500 by default)
+    pub db_batch_size: Option<usize>,
+    /// Show a progress bar
+    pub progress: bool,
+    /// Number of files to show progress against, if known
+    pub total: Option<usize>,
+}
+
+/// Index files into a library, waiting until all of them are written
+pub async fn index_files<I>(library: &Library, files: I, options: IndexOptions)
+where
+    I: IntoIterator<Item = FoundFile>,
+{
+    let parallelism = std::thread::available_parallelism().unwrap().get();
+    let hash_concurrency = options.hash_concurrency.unwrap_or(parallelism).max(1);
+    let meta_concurrency = options.meta_concurrency.unwrap_or(parallelism).max(1);
+    let writer = start_index_writer(library.clone(), options.db_batch_size.unwrap_or(500));
+    let pipeline = IndexPipeline {
+        library: library.clone(),
+        extractors: Arc::new(options.extractors),
+        merge_rules: options.merge_rules.map(Arc::new),
+        hash_limit: Arc::new(processing::AdaptiveLimiter::new(hash_concurrency)),
+        meta_limit: Arc::new(Semaphore::new(meta_concurrency)),
+        writer: writer.sender(),
+    };
+    // by default enough files in flight to keep every stage busy, the
+    // stages limit themselves
+    let hash_limit = pipeline.hash_limit.clone();
+    processing::consume_concurrently(
+        files,
+        index_file,
+        &pipeline,
+        options.progress,
+        options.total,
+        processing::ConcurrencyOptions {
+            concurrency: Some(
+                options
+                    .concurrency
+                    .unwrap_or(hash_concurrency + meta_concurrency),
+            ),
+            throttle: Some(hash_limit),
+            item_bytes: Some(file_size),
+        },
+    )
+    .await;
+    drop(pipeline);
+    writer.finish();
+}
+
+/// Size of a file in bytes, to show throughput. Taken from when the file was
+/// found, so that the executor is not blocked on the file system.
+pub fn file_size(file: &FoundFile) -> u64 {
+    file.size
+}
+
+/// Shared state of the indexing stages, cloned for each file
+#[derive(Clone)]
+struct IndexPipeline {
+    library: Library,
+    extractors: Arc<Vec<NamedExtractor>>,
+    merge_rules: Option<Arc<MergeRules>>,
+    /// Reading files is throttled when the storage slows down
+    hash_limit: Arc<processing::AdaptiveLimiter>,
+    meta_limit: Arc<Semaphore>,
+    writer: SyncSender<IndexRecord>,
+}
+
+/// Index a single file: hash it, extract its metadata, then pass it to the writer
+async fn index_file(file: FoundFile, pipeline: IndexPipeline) {
+    let size = file.size;
+    let record = pipeline
+        .hash_limit
+        .run(
+            size,
+            tokio::task::spawn_blocking(move || indexdb::hash_file(&file)),
+        )
+        .await;
+    let mut record = match record {
+        Ok(Some(record)) => record,
+        // not a photo or video
+        Ok(None) => return,
+        Err(err) => {
+            error!("Error processing file: {:?}", err);
+            return;
+        }
+    };
+    if record.fileinfo.is_none() {
+        for e in &record.errors {
+            error!("Error indexing {}: {}", record.filename, e);
+        }
+    } else if !pipeline.extractors.is_empty() {
+        let _permit = pipeline.meta_limit.acquire().await.unwrap();
+        let stage = pipeline.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            stage.library.extract_metadata(
+                &mut record,
+                &stage.extractors,
+                stage.merge_rules.as_deref(),
+            );
+            record
+        })
+        .await;
+        record = match result {
+            Ok(record) => record,
+            Err(err) => {
+                error!("Error processing file: {:?}", err);
+                return;
+            }
+        };
+    }
+    // sending blocks while the writer is busy, so wait on a blocking thread
+    let writer = pipeline.writer.clone();
+    let result = tokio::task::spawn_blocking(move || writer.send(record).is_ok()).await;
+    if !matches!(result, Ok(true)) {
+        error!("Index writer stopped, file not indexed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_batch_writer() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let written = batches.clone();
+        let writer = BatchWriter::start(3, move |batch: Vec<usize>| {
+            written.lock().unwrap().push(batch);
+        });
+        let senders: Vec<_> = (0..2).map(|_| writer.sender()).collect();
+        let handles: Vec<_> = senders
+            .into_iter()
+            .enumerate()
+            .map(|(i, sender)| {
+                thread::spawn(move || {
+                    for n in 0..4 {
+                        sender.send(i * 10 + n).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        writer.finish();
+
+        let batches = batches.lock().unwrap();
+        assert!(batches.iter().all(|b| !b.is_empty() && b.len() <= 3));
+        let mut items: Vec<usize> = batches.iter().flatten().copied().collect();
+        items.sort();
+        assert_eq!(items, vec![0, 1, 2, 3, 10, 11, 12, 13]);
+    }
+}
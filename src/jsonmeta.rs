@@ -1,23 +1,374 @@
-use serde_json::Value;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
 
-pub fn merge(a: &mut Value, b: Value) {
+/// Key under which the output of named extractors is stored, one entry per extractor
+pub const EXTRACTORS_KEY: &str = "extractors";
+/// Key under which a failed extractor run is recorded
+pub const META_ERROR_KEY: &str = "meta_error";
+/// Key holding the file hash at the top level of each JSON metadata file
+pub const SHA256_KEY: &str = "sha256";
+/// Key holding the location inferred from GPS tracks, see `gpx`
+pub const INFERRED_LOCATION_KEY: &str = "inferred_location";
+
+/// Top-level keys that are not part of the metadata from the default metadata command
+const RESERVED_KEYS: &[&str] = &[EXTRACTORS_KEY, SHA256_KEY, INFERRED_LOCATION_KEY];
+
+/// How a new value is combined with the existing value when merging
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// Keep the existing value if there is one
+    KeepExisting,
+    /// Replace the existing value, including objects
+    Overwrite,
+    /// Merge objects, append only array items that are not present yet, replace other values
+    ArrayUnion,
+    /// Merge objects, replace arrays and other values
+    ArrayReplace,
+    /// Merge objects, append all array items, replace other values
+    ArrayAppend,
+}
+
+/// Merge strategies to use for the whole document and for individual JSON
+/// paths such as `Rating` or `format.tags`. The strategy of a path also
+/// applies to everything below it, unless there is a rule for a longer path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeRules {
+    pub default: MergeStrategy,
+    pub paths: BTreeMap<String, MergeStrategy>,
+}
+
+impl Default for MergeRules {
+    fn default() -> MergeRules {
+        MergeRules::new(MergeStrategy::ArrayUnion)
+    }
+}
+
+impl MergeRules {
+    pub fn new(default: MergeStrategy) -> MergeRules {
+        MergeRules {
+            default,
+            paths: BTreeMap::new(),
+        }
+    }
+
+    /// The strategy for a path, from the rule for the longest matching path
+    fn strategy(&self, path: &[String]) -> MergeStrategy {
+        (0..=path.len())
+            .rev()
+            .find_map(|len| self.paths.get(&path[..len].join(".")))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// True if there are rules for paths below this one
+    fn has_rules_below(&self, path: &[String]) -> bool {
+        if path.is_empty() {
+            return !self.paths.is_empty();
+        }
+        let prefix = format!("{}.", path.join("."));
+        self.paths.keys().any(|p| p.starts_with(&prefix))
+    }
+}
+
+/// Merge b into a using the given rules
+pub fn merge_with(a: &mut Value, b: Value, rules: &MergeRules) {
+    merge_path(a, b, rules, &mut Vec::new());
+}
+
+fn merge_path(a: &mut Value, b: Value, rules: &MergeRules, path: &mut Vec<String>) {
+    let strategy = rules.strategy(path);
     match (a, b) {
-        (a @ &mut Value::Object(_), Value::Object(b)) => {
+        (a @ &mut Value::Object(_), Value::Object(b))
+            if strategy != MergeStrategy::Overwrite || rules.has_rules_below(path) =>
+        {
             let a = a.as_object_mut().unwrap();
             for (k, v) in b {
-                merge(a.entry(k).or_insert(Value::Null), v);
+                path.push(k.clone());
+                merge_path(a.entry(k).or_insert(Value::Null), v, rules, path);
+                path.pop();
             }
         }
-        (a @ &mut Value::Array(_), b @ Value::Array(_)) => {
+        (a, _) if strategy == MergeStrategy::KeepExisting && !a.is_null() => {}
+        (a @ &mut Value::Array(_), Value::Array(b))
+            if strategy == MergeStrategy::ArrayUnion || strategy == MergeStrategy::ArrayAppend =>
+        {
             let a = a.as_array_mut().unwrap();
-            for item in b.as_array().unwrap() {
-                a.push(item.clone());
+            for item in b {
+                if strategy == MergeStrategy::ArrayAppend || !a.contains(&item) {
+                    a.push(item);
+                }
             }
         }
         (a, b) => *a = b,
     }
 }
 
+/// Get the metadata of one extractor from a JSON metadata file.
+///
+/// Metadata from the default metadata command are stored at the top level
+/// (namespace `None`), named extractors store theirs in
+/// `extractors.<name>`.
+pub fn get_namespace(meta: &Value, namespace: Option<&str>) -> Value {
+    match namespace {
+        None => {
+            let mut top_level = meta.clone();
+            if let Value::Object(ref mut obj) = top_level {
+                for key in RESERVED_KEYS {
+                    obj.remove(*key);
+                }
+            }
+            top_level
+        }
+        Some(name) => meta
+            .get(EXTRACTORS_KEY)
+            .and_then(|e| e.get(name))
+            .cloned()
+            .unwrap_or(Value::Null),
+    }
+}
+
+/// Replace the metadata of one extractor, keeping the metadata of all others
+pub fn set_namespace(meta: &mut Value, namespace: Option<&str>, value: Value) {
+    if !meta.is_object() {
+        *meta = Value::Object(Map::new());
+    }
+    let obj = meta.as_object_mut().unwrap();
+    match namespace {
+        None => {
+            let reserved: Vec<(&str, Option<Value>)> = RESERVED_KEYS
+                .iter()
+                .map(|key| (*key, obj.remove(*key)))
+                .collect();
+            *obj = match value {
+                Value::Object(value) => value,
+                _ => Map::new(),
+            };
+            for (key, value) in reserved {
+                if let Some(value) = value {
+                    obj.insert(String::from(key), value);
+                }
+            }
+        }
+        Some(name) => {
+            let extractors = obj
+                .entry(EXTRACTORS_KEY)
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !extractors.is_object() {
+                *extractors = Value::Object(Map::new());
+            }
+            extractors
+                .as_object_mut()
+                .unwrap()
+                .insert(String::from(name), value);
+        }
+    }
+}
+
+/// Combine new output of an extractor with its current metadata.
+///
+/// Single element arrays (such as the ones returned by exiftool) are
+/// flattened, and values that are not objects are stored under `data`.
+/// Any previously recorded error is cleared. The new output replaces the
+/// current metadata unless merge rules are given.
+pub fn update(current: Value, new: Value, merge_rules: Option<&MergeRules>) -> Value {
+    let mut new = new;
+    // to pass an array, assign it inside a top-level object
+    while let Value::Array(ref mut arr) = new {
+        if arr.len() == 1 {
+            new = arr.remove(0);
+        } else {
+            break;
+        }
+    }
+    let mut updated = match merge_rules {
+        Some(rules) if current.is_object() => {
+            let mut current = current;
+            merge_with(&mut current, new, rules);
+            current
+        }
+        _ => new,
+    };
+    match updated {
+        Value::Object(ref mut obj) => {
+            obj.remove(META_ERROR_KEY);
+            updated
+        }
+        _ => json!({ "data": updated }),
+    }
+}
+
+/// Record a failed extractor run, keeping the current metadata
+pub fn record_error(current: Value, error: Value) -> Value {
+    let mut current = if current.is_object() {
+        current
+    } else {
+        json!({})
+    };
+    current
+        .as_object_mut()
+        .unwrap()
+        .insert(String::from(META_ERROR_KEY), error);
+    current
+}
+
+/// Compare the metadata before and after an update.
+///
+/// Returns None if nothing changed, otherwise an object listing the keys
+/// that were `added` or `removed` with their values, and the keys whose
+/// value `changed` with the `old` and `new` values.
+pub fn diff(old: &Value, new: &Value) -> Option<Value> {
+    if old == new {
+        return None;
+    }
+    let empty = Map::new();
+    let (old_obj, new_obj) = match (old, new) {
+        (Value::Object(a), Value::Object(b)) => (a, b),
+        (Value::Object(a), _) => (a, &empty),
+        (_, Value::Object(b)) => (&empty, b),
+        _ => {
+            return Some(json!({
+                "added": {},
+                "removed": {},
+                "changed": { "data": { "old": old, "new": new } },
+            }))
+        }
+    };
+
+    let mut added = Map::new();
+    let mut removed = Map::new();
+    let mut changed = Map::new();
+    for (k, v) in new_obj {
+        match old_obj.get(k) {
+            None => {
+                added.insert(k.clone(), v.clone());
+            }
+            Some(old_v) if old_v != v => {
+                changed.insert(k.clone(), json!({ "old": old_v, "new": v }));
+            }
+            _ => {}
+        }
+    }
+    for (k, v) in old_obj {
+        if !new_obj.contains_key(k) {
+            removed.insert(k.clone(), v.clone());
+        }
+    }
+    Some(json!({
+        "added": added,
+        "removed": removed,
+        "changed": changed,
+    }))
+}
+
+/// Most versions kept in the change log of a file, older ones are dropped
+pub const MAX_HISTORY_VERSIONS: usize = 20;
+
+/// Make a change log entry for an update of the metadata of one extractor.
+///
+/// `entry` describes the run (e.g. timestamp, extractor and command line) and
+/// is stored together with a snapshot of the `new` metadata. Returns None if
+/// the metadata did not change. The first version is marked as `initial`.
+pub fn history_entry(old: &Value, new: &Value, entry: Value) -> Option<Value> {
+    if old == new {
+        return None;
+    }
+    let is_initial = match old {
+        Value::Null => true,
+        Value::Object(obj) => obj.is_empty(),
+        _ => false,
+    };
+    let mut entry = match entry {
+        Value::Object(entry) => entry,
+        _ => Map::new(),
+    };
+    if is_initial {
+        entry.insert(String::from("initial"), Value::Bool(true));
+    }
+    entry.insert(String::from("metadata"), new.clone());
+    Some(Value::Object(entry))
+}
+
+/// Append an entry to a change log, numbering it after the last version and
+/// dropping the oldest versions beyond `MAX_HISTORY_VERSIONS`
+pub fn append_history(history: &mut Vec<Value>, entry: Value) {
+    let last_version = history
+        .last()
+        .and_then(|e| e.get("version"))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    let mut entry = entry;
+    if let Value::Object(ref mut obj) = entry {
+        obj.insert(String::from("version"), json!(last_version + 1));
+    }
+    history.push(entry);
+    if history.len() > MAX_HISTORY_VERSIONS {
+        history.drain(..history.len() - MAX_HISTORY_VERSIONS);
+    }
+}
+
+/// Format a change log for display, listing the keys that were added,
+/// removed or changed since the previous snapshot of the same extractor
+pub fn format_history(history: &[Value]) -> String {
+    if history.is_empty() {
+        return String::from("No history recorded.\n");
+    }
+    let mut output = String::new();
+    for (i, entry) in history.iter().enumerate() {
+        let field = |name: &str| match entry.get(name) {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Null) | None => String::from("-"),
+            Some(v) => v.to_string(),
+        };
+        output.push_str(&format!(
+            "version {} at {} by {} ({})\n",
+            field("version"),
+            field("timestamp"),
+            field("extractor"),
+            field("command")
+        ));
+        let previous = history[..i]
+            .iter()
+            .rev()
+            .find(|e| e.get("extractor") == entry.get("extractor"))
+            .and_then(|e| e.get("metadata"));
+        let changes = match (entry.get("metadata"), previous) {
+            (Some(metadata), Some(previous)) => diff(previous, metadata),
+            _ => None,
+        };
+        if entry.get("initial").and_then(|x| x.as_bool()) == Some(true) {
+            output.push_str("  initial metadata\n");
+        } else if let Some(changes) = changes {
+            let section = |name: &str| {
+                changes
+                    .get(name)
+                    .and_then(|x| x.as_object())
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            for (k, v) in section("added") {
+                output.push_str(&format!("  + {}: {}\n", k, v));
+            }
+            for (k, v) in section("removed") {
+                output.push_str(&format!("  - {}: {}\n", k, v));
+            }
+            for (k, v) in section("changed") {
+                output.push_str(&format!(
+                    "  ~ {}: {} -> {}\n",
+                    k,
+                    v.get("old").unwrap_or(&Value::Null),
+                    v.get("new").unwrap_or(&Value::Null)
+                ));
+            }
+        } else {
+            output.push_str("  earlier versions were not kept\n");
+        }
+    }
+    output
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -42,7 +393,7 @@ mod tests {
             "array":[ "K" ]
         });
 
-        merge(&mut a, b);
+        merge_with(&mut a, b, &MergeRules::new(MergeStrategy::ArrayAppend));
 
         let expected = json!({
             "string": "This is another string",
@@ -69,7 +420,7 @@ mod tests {
 
         let b = json!({});
 
-        merge(&mut a, b);
+        merge_with(&mut a, b, &MergeRules::new(MergeStrategy::ArrayAppend));
 
         let expected = json!({
             "string": "This is a string",
@@ -98,7 +449,7 @@ mod tests {
             "array": []
         });
 
-        merge(&mut a, b);
+        merge_with(&mut a, b, &MergeRules::new(MergeStrategy::ArrayAppend));
 
         let expected = json!({
             "string": "This is a string",
@@ -111,4 +462,193 @@ mod tests {
 
         assert_eq!(a, expected);
     }
+
+    #[test]
+    fn test_namespaces() {
+        let mut meta = json!({
+            "sha256": "abc",
+            "Lens": "85mm f/1.8",
+            "inferred_location": { "latitude": 52.5 },
+        });
+
+        set_namespace(&mut meta, Some("ffprobe"), json!({ "duration": 3.5 }));
+        set_namespace(&mut meta, Some("faces"), json!({ "names": ["A"] }));
+        set_namespace(&mut meta, None, json!({ "Lens": "35mm f/1.8" }));
+
+        let expected = json!({
+            "sha256": "abc",
+            "Lens": "35mm f/1.8",
+            "inferred_location": { "latitude": 52.5 },
+            "extractors": {
+                "ffprobe": { "duration": 3.5 },
+                "faces": { "names": ["A"] },
+            },
+        });
+        assert_eq!(meta, expected);
+
+        assert_eq!(get_namespace(&meta, None), json!({ "Lens": "35mm f/1.8" }));
+        assert_eq!(
+            get_namespace(&meta, Some("ffprobe")),
+            json!({ "duration": 3.5 })
+        );
+        assert_eq!(get_namespace(&meta, Some("missing")), Value::Null);
+    }
+
+    #[test]
+    fn test_update() {
+        let current = json!({
+            "Keywords": ["a"],
+            "Rating": 5,
+            "meta_error": { "message": "failed" },
+        });
+
+        let replaced = update(current.clone(), json!([{ "Keywords": ["b"] }]), None);
+        assert_eq!(replaced, json!({ "Keywords": ["b"] }));
+
+        let merged = update(
+            current.clone(),
+            json!([{ "Keywords": ["b"] }]),
+            Some(&MergeRules::default()),
+        );
+        assert_eq!(merged, json!({ "Keywords": ["a", "b"], "Rating": 5 }));
+
+        let wrapped = update(current, json!("text output"), None);
+        assert_eq!(wrapped, json!({ "data": "text output" }));
+    }
+
+    #[test]
+    fn test_record_error() {
+        let current = json!({ "Rating": 5 });
+        let recorded = record_error(current, json!({ "message": "failed" }));
+        assert_eq!(
+            recorded,
+            json!({ "Rating": 5, "meta_error": { "message": "failed" } })
+        );
+        let recorded = record_error(Value::Null, json!({ "message": "failed" }));
+        assert_eq!(recorded, json!({ "meta_error": { "message": "failed" } }));
+    }
+
+    #[test]
+    fn test_merge_union() {
+        let mut a = json!({
+            "Keywords": ["a", "b"],
+            "dict": { "list": [1, 2] },
+        });
+        let b = json!({
+            "Keywords": ["b", "c"],
+            "dict": { "list": [2, 3] },
+        });
+        merge_with(&mut a, b.clone(), &MergeRules::default());
+        merge_with(&mut a, b, &MergeRules::default());
+        let expected = json!({
+            "Keywords": ["a", "b", "c"],
+            "dict": { "list": [1, 2, 3] },
+        });
+        assert_eq!(a, expected);
+    }
+
+    #[test]
+    fn test_merge_rules() {
+        let existing = json!({
+            "Rating": 5,
+            "Title": "Edited by hand",
+            "Keywords": ["a", "b"],
+            "format": { "tags": { "a": 1 }, "size": 10 },
+        });
+        let new = json!({
+            "Rating": 0,
+            "Title": "From camera",
+            "Keywords": ["b", "c"],
+            "format": { "tags": { "b": 2 }, "size": 20 },
+            "Lens": "85mm",
+        });
+
+        // keep existing Rating, union Keywords, overwrite everything else
+        let rules = MergeRules {
+            default: MergeStrategy::Overwrite,
+            paths: BTreeMap::from([
+                (String::from("Rating"), MergeStrategy::KeepExisting),
+                (String::from("Keywords"), MergeStrategy::ArrayUnion),
+            ]),
+        };
+        let mut a = existing.clone();
+        merge_with(&mut a, new.clone(), &rules);
+        let expected = json!({
+            "Rating": 5,
+            "Title": "From camera",
+            "Keywords": ["a", "b", "c"],
+            "format": { "tags": { "b": 2 }, "size": 20 },
+            "Lens": "85mm",
+        });
+        assert_eq!(a, expected);
+
+        // keep existing values, but replace arrays and merge objects below format
+        let rules = MergeRules {
+            default: MergeStrategy::KeepExisting,
+            paths: BTreeMap::from([
+                (String::from("Keywords"), MergeStrategy::ArrayReplace),
+                (String::from("format.size"), MergeStrategy::Overwrite),
+            ]),
+        };
+        let mut a = existing;
+        merge_with(&mut a, new, &rules);
+        let expected = json!({
+            "Rating": 5,
+            "Title": "Edited by hand",
+            "Keywords": ["b", "c"],
+            "format": { "tags": { "a": 1, "b": 2 }, "size": 20 },
+            "Lens": "85mm",
+        });
+        assert_eq!(a, expected);
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = json!({ "Lens": "85mm", "Rating": 3, "Keywords": ["a"] });
+        let new = json!({ "Lens": "85mm", "Rating": 5, "Title": "Sunset" });
+        let expected = json!({
+            "added": { "Title": "Sunset" },
+            "removed": { "Keywords": ["a"] },
+            "changed": { "Rating": { "old": 3, "new": 5 } },
+        });
+        assert_eq!(diff(&old, &new), Some(expected));
+        assert_eq!(diff(&old, &old), None);
+    }
+
+    #[test]
+    fn test_history() {
+        let v1 = json!({ "Rating": 3 });
+        let v2 = json!({ "Rating": 5 });
+        let entry = json!({ "timestamp": "2024-07-01T00:00:00+00:00", "extractor": null });
+
+        let mut history = Vec::new();
+        for (old, new) in [(&Value::Null, &v1), (&v1, &v1), (&v1, &v2)] {
+            // unchanged metadata do not create a new version
+            if let Some(entry) = history_entry(old, new, entry.clone()) {
+                append_history(&mut history, entry);
+            }
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0]["version"], 1);
+        assert_eq!(history[0]["initial"], true);
+        assert_eq!(history[1]["version"], 2);
+        assert_eq!(history[1]["metadata"], v2);
+
+        let formatted = format_history(&history);
+        assert!(formatted.contains("version 2 at 2024-07-01T00:00:00+00:00"));
+        assert!(formatted.contains("  ~ Rating: 3 -> 5"));
+        assert_eq!(format_history(&[]), "No history recorded.\n");
+    }
+
+    #[test]
+    fn test_history_is_capped() {
+        let mut history = Vec::new();
+        for rating in 0..MAX_HISTORY_VERSIONS + 5 {
+            let entry = history_entry(&json!({}), &json!({ "Rating": rating }), json!({}));
+            append_history(&mut history, entry.unwrap());
+        }
+        assert_eq!(history.len(), MAX_HISTORY_VERSIONS);
+        assert_eq!(history[0]["version"], 6);
+        assert_eq!(history.last().unwrap()["version"], MAX_HISTORY_VERSIONS + 5);
+    }
 }
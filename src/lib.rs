@@ -0,0 +1,51 @@
+//! Photocat collects metadata of photos and videos into a library folder, and
+//! queries and summarizes them.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use photocat::summarystats::SummaryOptions;
+//! use photocat::filewalk::FoundFile;
+//! use photocat::{IndexOptions, Library, QueryFilter};
+//!
+//! let library = Library::open("./data")?;
+//! library
+//!     .index(vec![FoundFile::from(String::from("photo.jpg"))], IndexOptions::default())
+//!     .await;
+//! library.query(&QueryFilter::default(), |file| println!("{}", file.filename))?;
+//! println!(
+//!     "{}",
+//!     library.summarize(&QueryFilter::default(), &SummaryOptions::default())?
+//! );
+//! # Ok(())
+//! # }
+//! ```
+
+pub(crate) mod datesummary;
+pub mod dryrun;
+pub(crate) mod exiftoolpool;
+pub mod fileindex;
+pub mod filewalk;
+pub mod geo;
+pub(crate) mod geocode;
+pub mod gpx;
+pub mod indexdb;
+pub(crate) mod indexerror;
+pub mod indexpipeline;
+pub mod jsonmeta;
+pub mod libraryconfig;
+pub mod mappingsuggest;
+pub(crate) mod mediatype;
+pub mod metacmd;
+pub(crate) mod nativeexif;
+pub mod processing;
+pub(crate) mod schema;
+pub(crate) mod sidecar;
+pub mod summarystats;
+#[cfg(test)]
+mod testutil;
+pub(crate) mod valuecountsummary;
+pub mod variablemapping;
+pub mod watch;
+
+pub use indexdb::{Library, OpenError, QueryFilter};
+pub use indexpipeline::IndexOptions;
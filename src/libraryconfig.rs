@@ -0,0 +1,558 @@
+use chrono::FixedOffset;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use crate::dryrun::OutputFormat;
+use crate::indexdb::QueryFilter;
+use crate::jsonmeta::{MergeRules, MergeStrategy};
+use crate::metacmd::MetaBackend;
+use crate::summarystats::SummaryOptions;
+
+/// Library configuration, read from `photocat.toml` in the library folder
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// Defaults for command line options
+    #[serde(default)]
+    pub defaults: Defaults,
+    /// Named filters, selected with `--filter <name>`
+    #[serde(default)]
+    pub filter: BTreeMap<String, FilterPreset>,
+    /// Named summaries, selected with `--summary <name>`
+    #[serde(default)]
+    pub summary: BTreeMap<String, SummaryPreset>,
+    /// Named metadata extractors to run for each file
+    #[serde(default)]
+    pub extractor: Vec<ExtractorConfig>,
+    /// How metadata are merged with `--meta-merge true`
+    #[serde(default)]
+    pub merge: MergeConfig,
+    /// Where metadata are stored
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// Country, region and city of GPS locations
+    #[serde(default)]
+    pub geocoding: GeocodingConfig,
+}
+
+/// A setting in `photocat.toml` that cannot be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// There is no preset of this kind (`filter` or `summary`) and name
+    UnknownPreset { kind: &'static str, name: String },
+    /// A value cannot be parsed, e.g. `filter.summer.near`
+    InvalidValue { option: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::UnknownPreset { kind, name } => {
+                write!(f, "No {} preset named {} in photocat.toml", kind, name)
+            }
+            ConfigError::InvalidValue { option, message } => {
+                write!(f, "Invalid {} in photocat.toml: {}", option, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl LibraryConfig {
+    /// The filters of a named filter preset
+    pub fn filter_preset(&self, name: &str) -> Result<QueryFilter, ConfigError> {
+        let preset = self
+            .filter
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownPreset {
+                kind: "filter",
+                name: name.to_string(),
+            })?;
+        let invalid = |option: &str, message: String| ConfigError::InvalidValue {
+            option: format!("filter.{}.{}", name, option),
+            message,
+        };
+        let date = |option: &str, date: &Option<String>| {
+            date.as_deref()
+                .map(dateparser::parse)
+                .transpose()
+                .map_err(|e| invalid(option, e.to_string()))
+        };
+        Ok(QueryFilter {
+            sha256s: preset.sha.clone(),
+            filename: if preset.filename.is_empty() {
+                None
+            } else {
+                Some(preset.filename.join(","))
+            },
+            url: preset.url.clone(),
+            limit: preset.limit,
+            min_date: date("min-date", &preset.min_date)?,
+            max_date: date("max-date", &preset.max_date)?,
+            near: preset
+                .near
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(|e| invalid("near", e))?,
+            meta_equals: preset
+                .where_
+                .iter()
+                .map(|condition| condition.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| invalid("where", e))?,
+            places: false,
+        })
+    }
+
+    /// Check the values that are only parsed when they are used, so that
+    /// mistakes are found when the library is opened
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.defaults.timezone_offset()?;
+        for name in self.filter.keys() {
+            self.filter_preset(name)?;
+        }
+        Ok(())
+    }
+
+    /// The summaries of a named summary preset
+    pub fn summary_preset(&self, name: &str) -> Result<SummaryOptions, ConfigError> {
+        let preset = self
+            .summary
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownPreset {
+                kind: "summary",
+                name: name.to_string(),
+            })?;
+        Ok(SummaryOptions {
+            months_per_row: preset.wrap,
+            count: preset
+                .count
+                .iter()
+                .map(|variables| variables.split(',').map(String::from).collect())
+                .collect(),
+            grid: preset.grid,
+        })
+    }
+}
+
+/// Defaults for command line options, named like the options. Options given
+/// on the command line take precedence.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Defaults {
+    pub concurrency: Option<usize>,
+    pub hash_concurrency: Option<usize>,
+    pub meta_concurrency: Option<usize>,
+    pub db_batch_size: Option<usize>,
+    pub watch_delay: Option<u64>,
+    pub meta_cmd: Option<String>,
+    pub meta_timeout: Option<u64>,
+    pub meta_backend: Option<MetaBackend>,
+    pub extractor: Option<Vec<String>>,
+    pub meta_merge: Option<bool>,
+    pub allowed_extensions: Option<Vec<String>>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub max_depth: Option<usize>,
+    pub follow_links: Option<bool>,
+    pub skip_hidden: Option<bool>,
+    pub output_format: Option<OutputFormat>,
+    pub clock_offset: Option<i64>,
+    /// UTC offset such as `+02:00`
+    pub timezone: Option<String>,
+    pub max_gap: Option<i64>,
+}
+
+/// Where the effective value of an option comes from
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ValueSource {
+    Config,
+    BuiltIn,
+}
+
+impl Defaults {
+    /// Values used when neither the command line nor `photocat.toml` set an option
+    pub fn built_in() -> Defaults {
+        Defaults {
+            db_batch_size: Some(500),
+            watch_delay: Some(2),
+            meta_cmd: Some(String::from("exiftool -b -j -")),
+            meta_timeout: Some(60),
+            meta_backend: Some(MetaBackend::Command),
+            meta_merge: Some(false),
+            follow_links: Some(false),
+            skip_hidden: Some(false),
+            output_format: Some(OutputFormat::Table),
+            clock_offset: Some(0),
+            timezone: Some(String::from("+00:00")),
+            max_gap: Some(300),
+            ..Defaults::default()
+        }
+    }
+
+    /// The `timezone` option as a UTC offset
+    pub fn timezone_offset(&self) -> Result<Option<FixedOffset>, ConfigError> {
+        self.timezone
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(|e: chrono::ParseError| ConfigError::InvalidValue {
+                option: String::from("timezone"),
+                message: e.to_string(),
+            })
+    }
+
+    /// Effective value of each option and where it comes from, sorted by
+    /// option name. Options without a value are Null.
+    pub fn effective(&self) -> Vec<(String, Value, ValueSource)> {
+        let configured = serde_json::to_value(self).unwrap();
+        let built_in = serde_json::to_value(Defaults::built_in()).unwrap();
+        let (Value::Object(configured), Value::Object(built_in)) = (configured, built_in) else {
+            unreachable!("Defaults serialize to objects");
+        };
+        configured
+            .into_iter()
+            .map(|(name, value)| {
+                if value.is_null() {
+                    let value = built_in.get(&name).cloned().unwrap_or(Value::Null);
+                    (name, value, ValueSource::BuiltIn)
+                } else {
+                    (name, value, ValueSource::Config)
+                }
+            })
+            .collect()
+    }
+}
+
+/// A named set of filters for show, summarize and suggest-mappings
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FilterPreset {
+    /// Only files whose name contains one of these
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filename: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Comma-separated SHA256 hashes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_date: Option<String>,
+    /// `lat,lon,radius_km`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub near: Option<String>,
+    /// `Variable=value` conditions that must all hold
+    #[serde(rename = "where", default, skip_serializing_if = "Vec::is_empty")]
+    pub where_: Vec<String>,
+}
+
+/// A named set of summaries for summarize
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SummaryPreset {
+    /// Months per row of the date summary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wrap: Option<usize>,
+    /// Variables to count, comma-separated for combinations
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub count: Vec<String>,
+    /// Degrees per cell of the location map
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grid: Option<f64>,
+}
+
+/// Where the JSON metadata of each file are kept
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetaStore {
+    /// A sidecar file per hash below `meta/` in the library folder
+    #[default]
+    Sidecar,
+    /// The `rawmeta` table of the index database
+    Database,
+}
+
+/// Storage of the library
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Where metadata are stored, `sidecar` (default) or `database`
+    #[serde(default)]
+    pub meta: MetaStore,
+}
+
+/// Offline reverse geocoding, see `geocode`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GeocodingConfig {
+    /// Fill the Country, CountryCode, Region and City columns
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// GeoNames cities file, relative to the library folder. Defaults to the
+    /// built-in gazetteer
+    pub cities: Option<String>,
+    /// GeoNames `admin1CodesASCII.txt` for the names of regions
+    pub admin1: Option<String>,
+    /// Photos farther than this from any city are not geocoded, defaults to 200
+    pub max_distance_km: Option<f64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for GeocodingConfig {
+    fn default() -> Self {
+        GeocodingConfig {
+            enabled: true,
+            cities: None,
+            admin1: None,
+            max_distance_km: None,
+        }
+    }
+}
+
+/// Merge strategies for the metadata of each extractor
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MergeConfig {
+    /// Strategy for paths without a rule, defaults to `array-union`
+    pub default: Option<MergeStrategy>,
+    /// Strategies for dot-separated JSON paths, e.g. `Rating = 'keep-existing'`
+    #[serde(default)]
+    pub paths: BTreeMap<String, MergeStrategy>,
+}
+
+impl MergeConfig {
+    /// Merge rules from this configuration, optionally with a different default strategy
+    pub fn rules(&self, default: Option<MergeStrategy>) -> MergeRules {
+        MergeRules {
+            default: default
+                .or(self.default)
+                .unwrap_or(MergeRules::default().default),
+            paths: self.paths.clone(),
+        }
+    }
+}
+
+/// A metadata command whose output is stored under its own name in the
+/// JSON metadata of each file
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExtractorConfig {
+    pub name: String,
+    /// Command line, see `--meta-cmd`. Not needed for the native backend
+    #[serde(default)]
+    pub command: String,
+    /// Only run for files with these extensions (all files if empty)
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Timeout in seconds, defaults to `--meta-timeout`
+    pub timeout: Option<u64>,
+    /// How to run the command, defaults to `--meta-backend`
+    pub backend: Option<MetaBackend>,
+}
+
+/// Load library configuration from a file
+pub fn load_config(filename: &str) -> io::Result<LibraryConfig> {
+    let file_contents = std::fs::read_to_string(filename)?;
+    let config: LibraryConfig = toml::from_str(&file_contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    for (i, extractor) in config.extractor.iter().enumerate() {
+        if extractor.name.is_empty()
+            || config.extractor[..i]
+                .iter()
+                .any(|e| e.name == extractor.name)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Extractor names must be unique and non-empty: '{}'",
+                    extractor.name
+                ),
+            ));
+        }
+    }
+    config
+        .validate()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    static TEST_CONFIG_STRING: &str = "[[extractor]]\n\
+                                       name = 'exiftool'\n\
+                                       command = 'exiftool -j -b -'\n\
+                                       backend = 'exiftool-pool'\n\
+                                       \n\
+                                       [[extractor]]\n\
+                                       name = 'ffprobe'\n\
+                                       command = 'ffprobe -v quiet -print_format json -show_format {path}'\n\
+                                       extensions = ['mov', 'mp4']\n\
+                                       timeout = 120\n\
+                                       \n\
+                                       [merge]\n\
+                                       default = 'overwrite'\n\
+                                       \n\
+                                       [merge.paths]\n\
+                                       Rating = 'keep-existing'\n\
+                                       Keywords = 'array-union'\n";
+
+    #[test]
+    fn test_load_config() {
+        let config: LibraryConfig = toml::from_str(TEST_CONFIG_STRING).unwrap();
+        assert_eq!(config.extractor.len(), 2);
+        assert_eq!(config.extractor[0].name, "exiftool");
+        assert_eq!(config.extractor[0].command, "exiftool -j -b -");
+        assert!(config.extractor[0].extensions.is_empty());
+        assert_eq!(config.extractor[0].backend, Some(MetaBackend::ExiftoolPool));
+        assert_eq!(config.extractor[1].name, "ffprobe");
+        assert_eq!(config.extractor[1].extensions, vec!["mov", "mp4"]);
+        assert_eq!(config.extractor[1].timeout, Some(120));
+        assert_eq!(config.extractor[1].backend, None);
+
+        let rules = config.merge.rules(None);
+        assert_eq!(rules.default, MergeStrategy::Overwrite);
+        assert_eq!(rules.paths["Rating"], MergeStrategy::KeepExisting);
+        assert_eq!(rules.paths["Keywords"], MergeStrategy::ArrayUnion);
+        let rules = config.merge.rules(Some(MergeStrategy::ArrayAppend));
+        assert_eq!(rules.default, MergeStrategy::ArrayAppend);
+        assert_eq!(rules.paths.len(), 2);
+        assert_eq!(config.storage.meta, MetaStore::Sidecar);
+
+        let config: LibraryConfig = toml::from_str("[storage]\nmeta = 'database'\n").unwrap();
+        assert_eq!(config.storage.meta, MetaStore::Database);
+        assert!(config.geocoding.enabled);
+
+        let config: LibraryConfig =
+            toml::from_str("[geocoding]\ncities = 'cities15000.txt'\nmax-distance-km = 50\n")
+                .unwrap();
+        assert!(config.geocoding.enabled);
+        assert_eq!(config.geocoding.cities.as_deref(), Some("cities15000.txt"));
+        assert_eq!(config.geocoding.max_distance_km, Some(50.0));
+    }
+
+    #[test]
+    fn test_defaults_and_presets() {
+        let config: LibraryConfig = toml::from_str(
+            "[defaults]\n\
+             meta-backend = 'exiftool-pool'\n\
+             allowed-extensions = ['jpg', 'nef']\n\
+             concurrency = 4\n\
+             \n\
+             [filter.summer]\n\
+             min-date = '2024-06-01'\n\
+             max-date = '2024-09-01'\n\
+             where = ['Country=Italy']\n\
+             \n\
+             [summary.gear]\n\
+             wrap = 6\n\
+             count = ['Lens', 'media_type,Model']\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.defaults.meta_backend,
+            Some(MetaBackend::ExiftoolPool)
+        );
+        assert_eq!(
+            config.filter["summer"].min_date.as_deref(),
+            Some("2024-06-01")
+        );
+        assert_eq!(config.filter["summer"].where_, vec!["Country=Italy"]);
+        assert_eq!(config.summary["gear"].count.len(), 2);
+
+        let effective = config.defaults.effective();
+        let find = |name: &str| effective.iter().find(|(n, _, _)| n == name).unwrap();
+        assert_eq!(find("concurrency").1, serde_json::json!(4));
+        assert_eq!(find("concurrency").2, ValueSource::Config);
+        assert_eq!(find("meta-backend").1, serde_json::json!("exiftool-pool"));
+        assert_eq!(find("meta-cmd").1, serde_json::json!("exiftool -b -j -"));
+        assert_eq!(find("meta-cmd").2, ValueSource::BuiltIn);
+        assert!(find("max-depth").1.is_null());
+
+        assert!(toml::from_str::<LibraryConfig>("[defaults]\nmeta-cmmd = 'x'\n").is_err());
+    }
+
+    #[test]
+    fn test_presets() {
+        let config: LibraryConfig = toml::from_str(
+            "[filter.rome]\n\
+             filename = ['IMG_', 'DSC_']\n\
+             near = '41.9,12.5,20'\n\
+             where = ['Model=X100V']\n\
+             [filter.nowhere]\n\
+             near = 'rome'\n\
+             [summary.gear]\n\
+             count = ['Lens', 'media_type,Model']\n",
+        )
+        .unwrap();
+        let filter = config.filter_preset("rome").unwrap();
+        assert_eq!(filter.filename.as_deref(), Some("IMG_,DSC_"));
+        assert_eq!(filter.near.unwrap().radius_km, 20.0);
+        assert_eq!(filter.meta_equals[0].variable, "Model");
+        assert!(matches!(
+            config.filter_preset("nowhere"),
+            Err(ConfigError::InvalidValue { option, .. }) if option == "filter.nowhere.near"
+        ));
+        assert_eq!(
+            config.filter_preset("gear").unwrap_err().to_string(),
+            "No filter preset named gear in photocat.toml"
+        );
+        assert_eq!(config.summary_preset("gear").unwrap().count[1].len(), 2);
+        assert!(config.summary_preset("rome").is_err());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: LibraryConfig =
+            toml::from_str("[defaults]\ntimezone = '-05:00'\noutput-format = 'json'\n").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.defaults.timezone_offset(),
+            Ok(FixedOffset::west_opt(5 * 3600))
+        );
+        assert_eq!(config.defaults.output_format, Some(OutputFormat::Json));
+
+        let config: LibraryConfig = toml::from_str("[defaults]\ntimezone = 'CET'\n").unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidValue { option, .. }) if option == "timezone"
+        ));
+        assert!(toml::from_str::<LibraryConfig>("[defaults]\noutput-format = 'xml'\n").is_err());
+        let config: LibraryConfig = toml::from_str("[filter.x]\nwhere = ['Country']\n").unwrap();
+        assert!(config.validate().is_err());
+        // reported when the library is opened, not when the preset is used
+        let config: LibraryConfig = toml::from_str("[filter.x]\nnear = '41.9,12.5'\n").unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidValue { option, .. }) if option == "filter.x.near"
+        ));
+    }
+
+    #[test]
+    fn test_load_config_duplicate_names() {
+        let dir = TempDir::new("libraryconfig");
+        let path = dir.join("photocat.toml");
+        std::fs::write(
+            &path,
+            "[[extractor]]\nname = 'a'\ncommand = 'x'\n[[extractor]]\nname = 'a'\ncommand = 'y'\n",
+        )
+        .unwrap();
+        assert!(load_config(path.to_str().unwrap()).is_err());
+    }
+}
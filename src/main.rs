@@ -1,263 +1,1043 @@
-#![warn(unused_extern_crates, unused_crate_dependencies)]
+#![warn(unused_extern_crates)]
 
-use clap::{Parser, ValueEnum};
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::{Parser, Subcommand};
 use csv::Writer;
-use dateparser;
-use fileindex::IndexFile;
-use indexdb::query_fileindex;
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
+use photocat::dryrun::OutputFormat;
+use photocat::fileindex::IndexFile;
+use photocat::filewalk::{self, FoundFile, WalkOptions};
+use photocat::geo::Near;
+use photocat::gpx::{CameraClock, Track};
+use photocat::indexdb::MetaEquals;
+use photocat::indexpipeline::IndexOptions;
+use photocat::jsonmeta::MergeStrategy;
+use photocat::libraryconfig::{
+    ConfigError, Defaults, ExtractorConfig, FilterPreset, GeocodingConfig, LibraryConfig,
+    MergeConfig, MetaStore, StorageConfig, SummaryPreset, ValueSource,
+};
+use photocat::metacmd::{ExtractorOptions, MetaBackend};
+use photocat::summarystats::SummaryOptions;
+use photocat::{mappingsuggest, processing, variablemapping, watch, Library, QueryFilter};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use std::io;
-use summarystats::SummaryStats;
-use tokio_macros as _;
-use walkdir::WalkDir;
-
-mod datesummary;
-mod fileindex;
-mod indexdb;
-mod jsonmeta;
-mod processing;
-mod summarystats;
-mod valuecountsummary;
-mod variablemapping;
-
-fn default_extensions() -> Vec<String> {
-    vec![
-        String::from("jpg"),
-        String::from("heic"),
-        String::from("mov"),
-        String::from("png"),
-        String::from("raw"),
-        String::from("tiff"),
-        String::from("arw"),
-        String::from("nef"),
-        String::from("dng"),
-    ]
-}
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 /// Index photo library
-struct Args {
+struct Cli {
     /// Path to the library data folder
     #[arg(required = true, short('l'))]
     library: String,
 
-    /// Limit concurrency
-    #[arg(long, short('c'))]
-    concurrency: Option<usize>,
+    #[command(subcommand)]
+    command: Command,
+}
 
-    /// Action to perform
-    #[arg(required = true)]
-    action: Action,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create the library folder and its index database
+    Init,
+    /// Index files into the library
+    Index(IndexArgs),
+    /// List matching files on the file system
+    List(ListArgs),
+    /// Show entries in database in CSV format
+    Show(FilterArgs),
+    /// Summarize entries to terminal
+    Summarize(SummarizeArgs),
+    /// List metadata columns available
+    MetaColumns,
+    /// Suggest mapping.toml entries for near-duplicate values of a metadata variable
+    SuggestMappings(SuggestMappingsArgs),
+    /// Show the change log of the metadata for file hashes
+    MetaHistory(MetaHistoryArgs),
+    /// Write metadata stored in the database as sidecar JSON files
+    Export(ExportArgs),
+    /// Infer the location of photos without GPS from the tracks of a GPS logger
+    Geotag(GeotagArgs),
+    /// Show the library configuration from photocat.toml
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the effective configuration, and whether option defaults come
+    /// from photocat.toml or are built in
+    Show,
+}
 
+/// Which files to find on the file system
+#[derive(clap::Args, Debug)]
+struct WalkArgs {
     /// Path to photo file(s) location
     #[arg()]
     photo_location: Vec<String>,
 
-    /// Limit number of results when listing
+    /// Only use files with these extensions, instead of detecting photos and videos
+    /// from their contents
+    #[arg(long)]
+    allowed_extensions: Vec<String>,
+
+    /// Only use files matching these glob patterns (name or path below the photo location)
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Skip files and directories matching these glob patterns, in addition to .photocatignore
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Maximum directory depth below each photo location
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Follow symbolic links to directories
+    #[arg(long, overrides_with = "no_follow_links")]
+    follow_links: bool,
+
+    /// Do not follow symbolic links, even if photocat.toml sets follow-links
+    #[arg(long, overrides_with = "follow_links")]
+    no_follow_links: bool,
+
+    /// Skip hidden files and directories
+    #[arg(long, overrides_with = "no_skip_hidden")]
+    skip_hidden: bool,
+
+    /// Include hidden files and directories, even if photocat.toml sets skip-hidden
+    #[arg(long, overrides_with = "skip_hidden")]
+    no_skip_hidden: bool,
+}
+
+/// Which files to select from the index
+#[derive(clap::Args, Debug)]
+struct FilterArgs {
+    /// Start from a filter preset in photocat.toml, other filters given here override it
+    #[arg(long)]
+    filter: Option<String>,
+
+    /// Only files whose name contains this
+    #[arg()]
+    filename: Vec<String>,
+
+    /// Limit number of results
     #[arg(short('N'))]
-    list_limit: Option<usize>,
+    limit: Option<usize>,
 
-    /// Url matching for list
+    /// Only files whose URL contains this
     #[arg(short('u'))]
-    list_url: Option<String>,
+    url: Option<String>,
 
-    /// SHA256 matching for list
+    /// Only files with these comma-separated SHA256 hashes
     #[arg(short('s'))]
-    list_sha: Option<String>,
+    sha: Option<String>,
 
     /// Minimum date for search
-    #[arg(short('d'))]
-    min_date: Option<String>,
+    #[arg(short('d'), value_parser = parse_date)]
+    min_date: Option<DateTime<Utc>>,
 
     /// Maximum date for search
-    #[arg(short('D'))]
-    max_date: Option<String>,
+    #[arg(short('D'), value_parser = parse_date)]
+    max_date: Option<DateTime<Utc>>,
+
+    /// Only files with a GPS location within RADIUS_KM of a point
+    #[arg(long, value_name = "LAT,LON,RADIUS_KM")]
+    near: Option<Near>,
+
+    /// Only files whose metadata variable has a value, e.g. Country=Italy. Repeat to combine
+    #[arg(long = "where", value_name = "VARIABLE=VALUE")]
+    where_: Vec<MetaEquals>,
+}
+
+/// How to run the metadata commands when indexing
+#[derive(clap::Args, Debug)]
+struct MetaArgs {
+    /// Command that produces JSON output to run for each file. The file is passed
+    /// on stdin, or as an argument in place of {path} [default: exiftool -b -j -]
+    #[arg(long)]
+    meta_cmd: Option<String>,
 
-    /// Command that produces JSON output to run for each file
-    #[arg(long, default_value_t = String::from("exiftool -b -j -"))]
-    meta_cmd: String,
+    /// Timeout in seconds for running the metadata command on a single file [default: 60]
+    #[arg(long)]
+    meta_timeout: Option<u64>,
+
+    /// How to run the metadata command [default: command]
+    #[arg(long, value_enum)]
+    meta_backend: Option<MetaBackend>,
+
+    /// Only run these extractors from photocat.toml when indexing (all by default)
+    #[arg(long)]
+    extractor: Vec<String>,
 
     /// when running the json metadata command, should we try to merge with existing data?
     #[arg(long)]
     meta_merge: Option<bool>,
 
-    /// Summary parameters
+    /// Merge strategy for paths without a rule in photocat.toml (default array-union)
+    #[arg(long, value_enum)]
+    merge_strategy: Option<MergeStrategy>,
+}
+
+#[derive(clap::Args, Debug)]
+struct IndexArgs {
+    #[command(flatten)]
+    walk: WalkArgs,
+
+    #[command(flatten)]
+    meta: MetaArgs,
+
+    /// Maximum number of files to process at once (number of CPUs by default). Fewer
+    /// are processed at once while files take longer than before
+    #[arg(long, short('c'))]
+    concurrency: Option<usize>,
+
+    /// Number of files to hash at once (--concurrency by default)
     #[arg(long)]
-    summary_options: Option<String>,
+    hash_concurrency: Option<usize>,
 
-    #[arg(long, default_values_t = default_extensions())]
-    allowed_extensions: Vec<String>,
+    /// Number of metadata commands to run at once (--concurrency by default).
+    /// Also the number of exiftool processes for the exiftool-pool backend
+    #[arg(long)]
+    meta_concurrency: Option<usize>,
+
+    /// Number of indexed files to add to the database in each transaction [default: 500]
+    #[arg(long)]
+    db_batch_size: Option<usize>,
+
+    /// Only index files for which errors were recorded when they were last indexed
+    #[arg(long)]
+    retry_failed: bool,
+
+    /// Show which files would be added, moved or updated without indexing them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Output format for --dry-run [default: table]
+    #[arg(long, value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// After indexing, keep indexing files that are added, changed, moved or deleted
+    /// below the photo locations until interrupted
+    #[arg(long, conflicts_with_all = ["retry_failed", "dry_run"])]
+    watch: bool,
+
+    /// With --watch, seconds a file must be unchanged before it is indexed [default: 2]
+    #[arg(long, value_name = "SECONDS")]
+    watch_delay: Option<u64>,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-enum Action {
-    /// Index files into the library
-    Index,
-    /// List matching files on the file system
-    List,
-    /// Show entries in database in CSV format
-    Show,
-    /// Summarize entries to terminal
-    Summarize,
-    /// List metadata columns available
-    MetaColumns,
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+    #[command(flatten)]
+    walk: WalkArgs,
+
+    /// Maximum number of files to process at once (number of CPUs by default)
+    #[arg(long, short('c'))]
+    concurrency: Option<usize>,
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+/// Which summaries to show in addition to the date summary
+#[derive(clap::Args, Debug)]
+struct SummaryArgs {
+    /// Show the summaries of a summary preset in photocat.toml, in addition to those given here
+    #[arg(long)]
+    summary: Option<String>,
 
-    let args = Args::parse();
-    debug!("{:?}", args);
+    /// Wrap the date summary after this many months per row
+    #[arg(long, value_name = "MONTHS", num_args = 0..=1, default_missing_value = "8")]
+    wrap: Option<usize>,
 
-    // Check if the library path exists and is a directory
-    if !std::path::Path::new(&args.library).exists() {
-        error!("Library path {} does not exist", args.library);
-        panic!("Library path does not exist");
-    }
+    /// Count the values of a metadata variable, or of combinations of comma-separated
+    /// variables. Can be given several times
+    #[arg(long, value_name = "VARIABLES")]
+    count: Vec<String>,
+
+    /// Show a map of where photos were taken, with cells of this many degrees
+    #[arg(long, value_name = "DEGREES", num_args = 0..=1, default_missing_value = "1")]
+    grid: Option<f64>,
+}
 
-    if !std::path::Path::new(&args.library).is_dir() {
-        error!("Library path {} is not a directory", args.library);
-        panic!("Library path is not a directory");
+impl SummaryArgs {
+    fn options(&self, config: &LibraryConfig) -> SummaryOptions {
+        let preset = match self.summary {
+            Some(ref name) => config_value(config.summary_preset(name)),
+            None => SummaryOptions::default(),
+        };
+        SummaryOptions {
+            months_per_row: self.wrap.or(preset.months_per_row),
+            count: preset
+                .count
+                .into_iter()
+                .chain(
+                    self.count
+                        .iter()
+                        .map(|variables| variables.split(',').map(String::from).collect()),
+                )
+                .collect(),
+            grid: self.grid.or(preset.grid),
+        }
     }
+}
 
-    // Initialize the database connection with args.library + "/photocat.db"
-    indexdb::init_connection(&args.library);
+/// Value from photocat.toml, exiting with an error message if it cannot be
+/// used. Values are checked when the library is opened, so this happens
+/// for unknown preset names.
+fn config_value<T>(value: Result<T, ConfigError>) -> T {
+    value.unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    })
+}
 
-    let filenames: Option<String> = if args.photo_location.is_empty() {
-        None
-    } else {
-        Some(args.photo_location.join(","))
-    };
+#[derive(clap::Args, Debug)]
+struct SummarizeArgs {
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    #[command(flatten)]
+    summary: SummaryArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct SuggestMappingsArgs {
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    /// Metadata variable to suggest mappings for (e.g. Lens, LensModel, Model, Software)
+    #[arg(long)]
+    mapping_variable: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct MetaHistoryArgs {
+    /// File hashes
+    #[arg(required = true)]
+    sha256: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExportArgs {
+    /// Folder to write meta/ab/cd/<sha256>.json files to, the library folder by default
+    #[arg(long, short)]
+    output: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct GeotagArgs {
+    /// GPX file with tracks, can be given several times
+    #[arg(long, required = true, value_name = "FILE")]
+    gpx: Vec<String>,
+
+    /// Seconds the camera clock was ahead of the true time, negative if it was behind [default: 0]
+    #[arg(long, value_name = "SECONDS", allow_hyphen_values = true)]
+    clock_offset: Option<i64>,
+
+    /// UTC offset of the time zone the camera clock was set to, e.g. +02:00 [default: +00:00]
+    #[arg(long, value_name = "OFFSET", allow_hyphen_values = true)]
+    timezone: Option<FixedOffset>,
+
+    /// Photos taken more than this many seconds from a track point are out of range [default: 300]
+    #[arg(long, value_name = "SECONDS")]
+    max_gap: Option<i64>,
 
-    let min_date = args.min_date.map(|date| dateparser::parse(&date).unwrap());
-    let max_date = args.max_date.map(|date| dateparser::parse(&date).unwrap());
-
-    if args.action == Action::Show {
-        let mut wtr = Writer::from_writer(io::stdout());
-        let meta_columns = indexdb::get_meta_columns();
-        let mut columns: Vec<String> = vec![
-            String::from("url"),
-            String::from("filename"),
-            String::from("sha256"),
-            String::from("created_at"),
-            String::from("modified_at"),
-        ];
-        columns.extend(meta_columns.unwrap().into_iter().map(|x| x.1));
-        wtr.write_record(columns).unwrap();
-
-        query_fileindex(
-            &args.list_sha,
-            &filenames,
-            &args.list_url,
-            &args.list_limit,
-            &min_date,
-            &max_date,
-            |record: IndexFile| {
-                let mut row: Vec<String> = vec![
-                    record.url,
-                    record.filename,
-                    record.sha256,
-                    record.created_at.to_string(),
-                    record.modified_at.to_string(),
-                ];
-                row.extend(record.meta.into_iter().map(|x| x.value.to_string()));
-                wtr.write_record(row).unwrap();
+    /// Only show the report, without storing the inferred locations
+    #[arg(long)]
+    dry_run: bool,
+
+    #[command(flatten)]
+    filter: FilterArgs,
+}
+
+impl GeotagArgs {
+    /// Fill in options that are not given, first from photocat.toml and then
+    /// from the built-in defaults
+    fn apply_defaults(&mut self, config: &LibraryConfig) {
+        for defaults in [&config.defaults, &Defaults::built_in()] {
+            self.clock_offset = self.clock_offset.or(defaults.clock_offset);
+            self.max_gap = self.max_gap.or(defaults.max_gap);
+            if self.timezone.is_none() {
+                self.timezone = config_value(defaults.timezone_offset());
+            }
+        }
+    }
+}
+
+impl FilterArgs {
+    fn query_filter(&self, config: &LibraryConfig) -> QueryFilter {
+        let preset = match self.filter {
+            Some(ref name) => config_value(config.filter_preset(name)),
+            None => QueryFilter::default(),
+        };
+        QueryFilter {
+            sha256s: self.sha.clone().or(preset.sha256s),
+            filename: if self.filename.is_empty() {
+                preset.filename
+            } else {
+                Some(self.filename.join(","))
             },
-        )
-        .expect("Query to fileindex failed");
-        wtr.flush().unwrap();
-    } else if args.action == Action::Summarize {
-        let mut summary: SummaryStats = SummaryStats::new(&args.summary_options);
-
-        query_fileindex(
-            &args.list_sha,
-            &filenames,
-            &args.list_url,
-            &args.list_limit,
-            &min_date,
-            &max_date,
-            |record: IndexFile| {
-                summary.add(&record);
+            url: self.url.clone().or(preset.url),
+            limit: self.limit.or(preset.limit),
+            min_date: self.min_date.or(preset.min_date),
+            max_date: self.max_date.or(preset.max_date),
+            near: self.near.or(preset.near),
+            meta_equals: if self.where_.is_empty() {
+                preset.meta_equals
+            } else {
+                self.where_.clone()
             },
-        )
-        .expect("Query to fileindex failed");
+            places: false,
+        }
+    }
+}
 
-        println!("{}", summary);
-    } else if args.action == Action::MetaColumns {
-        let meta_columns = indexdb::get_meta_columns();
-        match meta_columns {
-            Ok(meta_columns) => {
-                if meta_columns.len() > 0 {
-                    for (_, name, ctype) in meta_columns {
-                        println!("{}: {}", name, ctype)
-                    }
-                } else {
-                    println!("No metadata columns are available.")
-                }
+/// Parse a date given on the command line, in any format of `dateparser`
+fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+    dateparser::parse(date).map_err(|e| e.to_string())
+}
+
+/// Value of an option with `--<option>` and `--no-<option>` flags, or the
+/// default if neither is given
+fn switch(on: bool, off: bool, default: Option<bool>) -> bool {
+    if on || off {
+        on
+    } else {
+        default.unwrap_or(false)
+    }
+}
+
+impl WalkArgs {
+    /// Fill in options that are not given from photocat.toml
+    fn apply_defaults(&mut self, defaults: &Defaults) {
+        let or_default = |values: &mut Vec<String>, default: &Option<Vec<String>>| {
+            if values.is_empty() {
+                *values = default.clone().unwrap_or_default();
             }
-            Err(e) => println!("No metadata columns are available. {:?}", e),
+        };
+        or_default(&mut self.allowed_extensions, &defaults.allowed_extensions);
+        or_default(&mut self.include, &defaults.include);
+        or_default(&mut self.exclude, &defaults.exclude);
+        self.max_depth = self.max_depth.or(defaults.max_depth);
+        self.follow_links = switch(
+            self.follow_links,
+            self.no_follow_links,
+            defaults.follow_links,
+        );
+        self.skip_hidden = switch(self.skip_hidden, self.no_skip_hidden, defaults.skip_hidden);
+    }
+
+    fn options(&self) -> WalkOptions {
+        WalkOptions {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            extensions: self
+                .allowed_extensions
+                .iter()
+                .map(|ext| ext.to_lowercase())
+                .collect(),
+            max_depth: self.max_depth,
+            follow_links: self.follow_links,
+            skip_hidden: self.skip_hidden,
         }
-    } else if args.action == Action::Index || args.action == Action::List {
-        // enumerate files specified in the photo location
-        let files = args
-            .photo_location
-            .iter()
-            .flat_map(|dir| WalkDir::new(dir))
-            .into_iter()
-            .filter_map(Result::ok)
-            .filter(|entry| {
-                entry.path().is_file() && {
-                    let extension = entry.path().extension().and_then(|ext| ext.to_str());
-                    match extension {
-                        Some(ext) => args.allowed_extensions.contains(&ext.to_lowercase()),
-                        None => false,
-                    }
+    }
+
+    /// Enumerate the files below the photo locations, with their number so
+    /// that progress can be shown against it. Walking does not read the files.
+    fn files(&self) -> (usize, impl Iterator<Item = FoundFile>) {
+        let counted = filewalk::walk_files(&self.photo_location, self.options())
+            .expect("Invalid --include or --exclude pattern");
+        let total = processing::precount(counted, true);
+        let files = filewalk::walk_files(&self.photo_location, self.options())
+            .expect("Invalid --include or --exclude pattern");
+        (total, files)
+    }
+}
+
+impl MetaArgs {
+    fn apply_defaults(&mut self, defaults: &Defaults) {
+        self.meta_cmd = self.meta_cmd.take().or_else(|| defaults.meta_cmd.clone());
+        self.meta_timeout = self.meta_timeout.or(defaults.meta_timeout);
+        self.meta_backend = self.meta_backend.or(defaults.meta_backend);
+        if self.extractor.is_empty() {
+            self.extractor = defaults.extractor.clone().unwrap_or_default();
+        }
+        self.meta_merge = self.meta_merge.or(defaults.meta_merge);
+    }
+}
+
+impl IndexArgs {
+    /// Fill in options that are not given, first from photocat.toml and then
+    /// from the built-in defaults
+    fn apply_defaults(&mut self, config: &LibraryConfig) {
+        for defaults in [&config.defaults, &Defaults::built_in()] {
+            self.walk.apply_defaults(defaults);
+            self.meta.apply_defaults(defaults);
+            self.concurrency = self.concurrency.or(defaults.concurrency);
+            self.hash_concurrency = self.hash_concurrency.or(defaults.hash_concurrency);
+            self.meta_concurrency = self.meta_concurrency.or(defaults.meta_concurrency);
+            self.db_batch_size = self.db_batch_size.or(defaults.db_batch_size);
+            self.watch_delay = self.watch_delay.or(defaults.watch_delay);
+            self.output_format = self.output_format.or(defaults.output_format);
+        }
+    }
+}
+
+impl ListArgs {
+    fn apply_defaults(&mut self, config: &LibraryConfig) {
+        self.walk.apply_defaults(&config.defaults);
+        self.concurrency = self.concurrency.or(config.defaults.concurrency);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let cli = Cli::parse();
+    debug!("{:?}", cli);
+
+    // Open the database connection with cli.library + "/photocat.db"
+    let library = match cli.command {
+        Command::Init => Library::init(&cli.library),
+        _ => Library::open(&cli.library),
+    };
+    let library = match library {
+        Ok(library) => library,
+        Err(e) => {
+            error!("Cannot open library {}: {}", cli.library, e);
+            panic!("Cannot open library");
+        }
+    };
+
+    match cli.command {
+        Command::Init => match library.schema_version() {
+            Ok(version) => info!(
+                "Library {} is ready, schema version {}",
+                cli.library, version
+            ),
+            Err(e) => error!("Cannot read schema version: {}", e),
+        },
+        Command::Index(mut args) => {
+            args.apply_defaults(library.config());
+            index(&library, args).await
+        }
+        Command::List(mut args) => {
+            args.apply_defaults(library.config());
+            async fn list_fun(entry: FoundFile, _context: ()) {
+                // reading the file to detect its type blocks
+                if let Ok(Some(path)) =
+                    tokio::task::spawn_blocking(move || entry.is_indexed().then_some(entry.path))
+                        .await
+                {
+                    println!("{}", path);
                 }
-            })
-            .map(|x| String::from(x.path().to_str().unwrap()));
-
-        async fn action_fun(entry: String, context: (Action, String, bool)) {
-            let (action, meta_cmd, meta_merge) = context;
-            debug!("Action on file: {:?}", entry);
-            match action {
-                Action::Index => {
-                    // this needs to be run as a separate blocking thread so it runs in parallel
-                    let result = tokio::task::spawn_blocking(move || {
-                        indexdb::index_file(
-                            entry,
-                            if meta_cmd.is_empty() {
-                                None
-                            } else {
-                                Some(meta_cmd)
-                            },
-                            meta_merge,
-                        )
-                    })
-                    .await;
-                    if let Err(err) = result {
-                        error!("Error processing file: {:?}", err);
+            }
+            let (total, files) = args.walk.files();
+            processing::consume_concurrently(
+                files,
+                list_fun,
+                &(),
+                true,
+                Some(total),
+                processing::ConcurrencyOptions {
+                    concurrency: args.concurrency,
+                    ..processing::ConcurrencyOptions::default()
+                },
+            )
+            .await;
+        }
+        Command::Show(filter) => {
+            let filter = QueryFilter {
+                places: true,
+                ..filter.query_filter(library.config())
+            };
+            show(&library, &filter)
+        }
+        Command::Summarize(args) => {
+            let summary = library
+                .summarize(
+                    &args.filter.query_filter(library.config()),
+                    &args.summary.options(library.config()),
+                )
+                .expect("Query to fileindex failed");
+
+            println!("{}", summary);
+        }
+        Command::MetaColumns => {
+            let meta_columns = library.meta_columns();
+            match meta_columns {
+                Ok(meta_columns) => {
+                    if !meta_columns.is_empty() {
+                        for (_, name, ctype) in meta_columns {
+                            println!("{}: {}", name, ctype)
+                        }
+                    } else {
+                        println!("No metadata columns are available.")
                     }
                 }
-                Action::List => {
-                    println!("{}", entry);
+                Err(e) => println!("No metadata columns are available. {:?}", e),
+            }
+        }
+        Command::SuggestMappings(args) => suggest_mappings(&library, args),
+        Command::Config(ConfigCommand::Show) => {
+            print!("{}", config_show(library.path(), library.config()))
+        }
+        Command::MetaHistory(args) => {
+            for sha256 in args.sha256 {
+                println!("{}:", sha256);
+                match library.meta_history(&sha256) {
+                    Ok(history) => print!("{}", history),
+                    Err(e) => println!("No metadata available. {}", e),
                 }
-                _ => {}
             }
         }
+        Command::Export(args) => {
+            if library.config().storage.meta == MetaStore::Sidecar {
+                warn!("Library stores metadata in sidecar files, only metadata in the database are exported");
+            }
+            let output = args.output.unwrap_or(cli.library);
+            match library.export_sidecars(std::path::Path::new(&output)) {
+                Ok(written) => info!("Exported metadata of {} files to {}", written, output),
+                Err(e) => error!("Cannot export metadata to {}: {}", output, e),
+            }
+        }
+        Command::Geotag(mut args) => {
+            args.apply_defaults(library.config());
+            geotag(&library, &args);
+        }
+    }
+}
+
+/// The effective configuration of a library in photocat.toml format, with the
+/// source of each option default as a comment
+fn config_show(path: &std::path::Path, config: &LibraryConfig) -> String {
+    #[derive(Serialize)]
+    struct Sections<'a> {
+        filter: &'a BTreeMap<String, FilterPreset>,
+        summary: &'a BTreeMap<String, SummaryPreset>,
+        extractor: &'a Vec<ExtractorConfig>,
+        merge: &'a MergeConfig,
+        storage: &'a StorageConfig,
+        geocoding: &'a GeocodingConfig,
+    }
 
-        // Iterate list of files in parallel
-        let action = args.action;
-        let meta_cmd = args.meta_cmd;
-        let meta_merge = args.meta_merge.unwrap_or(false);
-        processing::consume_concurrently(
-            files,
-            action_fun,
-            &(action, meta_cmd, meta_merge),
-            true,
-            None,
+    let mut output = format!("# {}\n[defaults]\n", path.join("photocat.toml").display());
+    for (name, value, source) in config.defaults.effective() {
+        let line = match (value.is_null(), source) {
+            (true, _) => format!("# {} is not set", name),
+            (false, ValueSource::Config) => format!("{} = {}  # photocat.toml", name, value),
+            (false, ValueSource::BuiltIn) => format!("{} = {}  # built-in default", name, value),
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    let sections = Sections {
+        filter: &config.filter,
+        summary: &config.summary,
+        extractor: &config.extractor,
+        merge: &config.merge,
+        storage: &config.storage,
+        geocoding: &config.geocoding,
+    };
+    output.push('\n');
+    output.push_str(&toml::to_string(&sections).expect("Cannot format configuration"));
+    output
+}
+
+/// Print the selected entries of the index as CSV
+fn show(library: &Library, filter: &QueryFilter) {
+    let mut wtr = Writer::from_writer(io::stdout());
+    let meta_columns = library.meta_columns();
+    let mut columns: Vec<String> = vec![
+        String::from("url"),
+        String::from("filename"),
+        String::from("sha256"),
+        String::from("created_at"),
+        String::from("modified_at"),
+        String::from("media_type"),
+    ];
+    columns.extend(meta_columns.unwrap().into_iter().map(|x| x.1));
+    wtr.write_record(columns).unwrap();
+
+    library
+        .query(filter, |record: IndexFile| {
+            let mut row: Vec<String> = vec![
+                record.url,
+                record.filename,
+                record.sha256,
+                record.created_at.to_string(),
+                record.modified_at.to_string(),
+                record.media_type.unwrap_or_default(),
+            ];
+            row.extend(record.meta.into_iter().map(|x| x.value.to_string()));
+            wtr.write_record(row).unwrap();
+        })
+        .expect("Query to fileindex failed");
+    wtr.flush().unwrap();
+}
+
+/// Print suggested mappings for near-duplicate values of a metadata variable
+fn suggest_mappings(library: &Library, args: SuggestMappingsArgs) {
+    let variable = args.mapping_variable;
+    let value_counts = library
+        .value_counts(&args.filter.query_filter(library.config()), &variable)
+        .expect("Query to fileindex failed")
+        .unwrap_or_else(|| {
+            warn!("No values found for metadata variable {}", variable);
+            HashMap::new()
+        });
+    let mappings = mappingsuggest::suggest_mappings(&variable, &value_counts);
+    if mappings.is_empty() {
+        println!("# No near-duplicate values found for {}", variable);
+    } else {
+        print!("{}", variablemapping::mappings_to_toml(&mappings));
+    }
+}
+
+/// Index files below the photo locations, or files that failed before
+async fn index(library: &Library, args: IndexArgs) {
+    let (total, files): (usize, Box<dyn Iterator<Item = FoundFile>>) = if args.retry_failed {
+        let files = failed_files(library, &args.walk.photo_location);
+        (files.len(), Box::new(files.into_iter()))
+    } else {
+        let (total, files) = args.walk.files();
+        (total, Box::new(files))
+    };
+
+    let concurrency = args
+        .concurrency
+        .unwrap_or_else(|| std::thread::available_parallelism().unwrap().get());
+    let options = IndexOptions {
+        concurrency: args.concurrency,
+        hash_concurrency: Some(args.hash_concurrency.unwrap_or(concurrency)),
+        meta_concurrency: Some(args.meta_concurrency.unwrap_or(concurrency)),
+        db_batch_size: args.db_batch_size,
+        progress: true,
+        total: Some(total),
+        ..IndexOptions::default()
+    };
+
+    if args.dry_run {
+        let report = library
+            .dry_run(files, options)
+            .await
+            .expect("Query to fileindex failed");
+        match args.output_format.unwrap() {
+            OutputFormat::Table => print!("{}", report.to_table()),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        }
+        return;
+    }
+
+    let extractors = library.meta_extractors(&ExtractorOptions {
+        meta_cmd: args.meta.meta_cmd.clone().unwrap_or_default(),
+        timeout: args.meta.meta_timeout.unwrap(),
+        backend: args.meta.meta_backend.unwrap(),
+        selected: args.meta.extractor.clone(),
+        pool_size: args.meta_concurrency.or(args.concurrency),
+    });
+    let extractors = extractors.unwrap_or_else(|e| {
+        error!("{}", e);
+        panic!("Invalid extractor configuration");
+    });
+    let merge_rules = if args.meta.meta_merge.unwrap_or(false) {
+        Some(library.config().merge.rules(args.meta.merge_strategy))
+    } else {
+        None
+    };
+    let options = IndexOptions {
+        extractors,
+        merge_rules,
+        ..options
+    };
+    let started_at = Utc::now();
+    library.index(files, options.clone()).await;
+    print_index_error_summary(library, &started_at);
+
+    if args.watch {
+        let options = IndexOptions {
+            progress: false,
+            total: None,
+            ..options
+        };
+        let result = watch::watch(
+            library,
+            &args.walk.photo_location,
+            args.walk.options(),
+            Duration::from_secs(args.watch_delay.unwrap()),
+            options,
+            async {
+                let _ = tokio::signal::ctrl_c().await;
+            },
         )
         .await;
+        if let Err(e) = result {
+            error!("{}", e);
+            panic!("Cannot watch photo locations");
+        }
+    }
+}
+
+/// Match the photos selected by the filter to GPX tracks, store the inferred
+/// locations and print a report
+fn geotag(library: &Library, args: &GeotagArgs) {
+    let tracks: Vec<Track> = args
+        .gpx
+        .iter()
+        .map(|path| {
+            Track::load(std::path::Path::new(path)).unwrap_or_else(|e| {
+                error!("{}", e);
+                panic!("Cannot read GPX file");
+            })
+        })
+        .collect();
+    for track in &tracks {
+        info!("Loaded {} track points from {}", track.points(), track.name);
+    }
+    let clock = CameraClock {
+        offset_seconds: args.clock_offset.unwrap(),
+        timezone: args.timezone.unwrap(),
+    };
+
+    let report = library
+        .geotag(
+            &args.filter.query_filter(library.config()),
+            &tracks,
+            &clock,
+            args.max_gap.unwrap(),
+            !args.dry_run,
+        )
+        .expect("Query to fileindex failed");
+    print!("{}", report);
+}
+
+/// Files to index again with --retry-failed, limited to the given locations if any
+fn failed_files(library: &Library, locations: &[String]) -> Vec<FoundFile> {
+    let prefixes: Vec<std::path::PathBuf> = locations
+        .iter()
+        .filter_map(|l| std::fs::canonicalize(l).ok())
+        .collect();
+    library
+        .failed_files()
+        .expect("Cannot read index_errors table")
+        .into_iter()
+        .filter(|f| {
+            locations.is_empty()
+                || prefixes
+                    .iter()
+                    .any(|p| std::path::Path::new(f).starts_with(p))
+        })
+        .map(FoundFile::from)
+        .collect()
+}
+
+/// Print the number of files that failed during this run for each stage
+fn print_index_error_summary(library: &Library, started_at: &DateTime<Utc>) {
+    match library.index_error_summary(started_at) {
+        Ok(counts) if !counts.is_empty() => {
+            let stages = counts
+                .iter()
+                .map(|(stage, count)| format!("{}: {}", stage, count))
+                .collect::<Vec<String>>()
+                .join(", ");
+            println!(
+                "Files with errors by stage: {}. Use index --retry-failed to process them again.",
+                stages
+            );
+        }
+        Ok(_) => {}
+        Err(e) => error!("Cannot summarize indexing errors: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from([
+            "photocat",
+            "-l",
+            "data",
+            "summarize",
+            "-d",
+            "2024-06-01T00:00:00Z",
+            "--wrap",
+            "--count",
+            "Lens",
+            "--count",
+            "media_type,Model",
+        ])
+        .unwrap();
+        let Command::Summarize(args) = cli.command else {
+            panic!("Expected summarize");
+        };
+        assert_eq!(
+            args.filter.min_date,
+            Some("2024-06-01T00:00:00Z".parse().unwrap())
+        );
+        let options = args.summary.options(&LibraryConfig::default());
+        assert_eq!(options.months_per_row, Some(8));
+        assert_eq!(
+            options.count,
+            vec![
+                vec![String::from("Lens")],
+                vec![String::from("media_type"), String::from("Model")]
+            ]
+        );
+
+        // options of other actions are rejected
+        assert!(Cli::try_parse_from([
+            "photocat",
+            "-l",
+            "data",
+            "summarize",
+            "--meta-cmd",
+            "exiftool -j -"
+        ])
+        .is_err());
+        assert!(
+            Cli::try_parse_from(["photocat", "-l", "data", "list", "-d", "2024-06-01"]).is_err()
+        );
+        assert!(
+            Cli::try_parse_from(["photocat", "-l", "data", "show", "-d", "not a date"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let config: LibraryConfig = toml::from_str(
+            "[defaults]\n\
+             meta-cmd = 'exiftool -j -'\n\
+             exclude = ['@eaDir']\n\
+             skip-hidden = true\n\
+             output-format = 'json'\n\
+             [filter.summer]\n\
+             limit = 10\n\
+             url = 'holiday'\n\
+             [summary.gear]\n\
+             count = ['Lens']\n",
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_from([
+            "photocat",
+            "-l",
+            "data",
+            "index",
+            "photos",
+            "--exclude",
+            "tmp",
+            "--meta-timeout",
+            "5",
+        ])
+        .unwrap();
+        let Command::Index(mut args) = cli.command else {
+            panic!("Expected index");
+        };
+        args.apply_defaults(&config);
+        assert_eq!(args.meta.meta_cmd.as_deref(), Some("exiftool -j -"));
+        assert_eq!(args.meta.meta_timeout, Some(5));
+        assert_eq!(args.meta.meta_backend, Some(MetaBackend::Command));
+        assert_eq!(args.walk.exclude, vec!["tmp"]);
+        assert!(args.walk.skip_hidden);
+        assert!(!args.walk.follow_links);
+        assert_eq!(args.output_format, Some(OutputFormat::Json));
+        assert_eq!(args.db_batch_size, Some(500));
+
+        // the command line turns off what photocat.toml turns on
+        let cli = Cli::try_parse_from([
+            "photocat",
+            "-l",
+            "data",
+            "list",
+            "photos",
+            "--no-skip-hidden",
+            "--follow-links",
+        ])
+        .unwrap();
+        let Command::List(mut args) = cli.command else {
+            panic!("Expected list");
+        };
+        args.apply_defaults(&config);
+        assert!(!args.walk.skip_hidden);
+        assert!(args.walk.follow_links);
+        let cli = Cli::try_parse_from([
+            "photocat",
+            "-l",
+            "data",
+            "list",
+            "--skip-hidden",
+            "--no-skip-hidden",
+        ])
+        .unwrap();
+        let Command::List(mut args) = cli.command else {
+            panic!("Expected list");
+        };
+        args.apply_defaults(&LibraryConfig::default());
+        assert!(!args.walk.skip_hidden);
+
+        let cli = Cli::try_parse_from([
+            "photocat",
+            "-l",
+            "data",
+            "summarize",
+            "--filter",
+            "summer",
+            "-u",
+            "trip",
+            "--summary",
+            "gear",
+            "--count",
+            "Model",
+        ])
+        .unwrap();
+        let Command::Summarize(args) = cli.command else {
+            panic!("Expected summarize");
+        };
+        let filter = args.filter.query_filter(&config);
+        assert_eq!(filter.url.as_deref(), Some("trip"));
+        assert_eq!(filter.limit, Some(10));
+        assert_eq!(
+            args.summary.options(&config).count,
+            vec![vec![String::from("Lens")], vec![String::from("Model")]]
+        );
+
+        let cli = Cli::try_parse_from([
+            "photocat",
+            "-l",
+            "data",
+            "geotag",
+            "--gpx",
+            "walk.gpx",
+            "--clock-offset",
+            "-90",
+            "--timezone",
+            "-05:00",
+            "IMG_",
+        ])
+        .unwrap();
+        let Command::Geotag(mut args) = cli.command else {
+            panic!("Expected geotag");
+        };
+        args.apply_defaults(&config);
+        assert_eq!(args.clock_offset, Some(-90));
+        assert_eq!(
+            args.timezone,
+            Some(FixedOffset::west_opt(5 * 3600).unwrap())
+        );
+        assert_eq!(args.max_gap, Some(300));
+        assert_eq!(args.filter.filename, vec!["IMG_"]);
+
+        let shown = config_show(std::path::Path::new("data"), &config);
+        assert!(shown.contains("meta-cmd = \"exiftool -j -\"  # photocat.toml\n"));
+        assert!(shown.contains("meta-timeout = 60  # built-in default\n"));
+        assert!(shown.contains("# max-depth is not set\n"));
+        assert!(shown.contains("[filter.summer]"));
     }
 }
@@ -0,0 +1,256 @@
+use itertools::Itertools;
+use std::collections::HashMap;
+
+use crate::variablemapping::{Mapping, Mappings};
+
+/// Leading words that different tools add to or drop from lens and camera names
+const MANUFACTURER_PREFIXES: &[&str] = &[
+    "apple",
+    "canon",
+    "corporation",
+    "fujifilm",
+    "fujinon",
+    "google",
+    "leica",
+    "lumix",
+    "nikon",
+    "nikkor",
+    "olympus",
+    "panasonic",
+    "samsung",
+    "sigma",
+    "sony",
+    "tamron",
+    "zeiss",
+    "af",
+    "af-p",
+    "af-s",
+    "ef",
+    "ef-s",
+    "fe",
+    "rf",
+    "xf",
+];
+
+/// Manufacturer prefixes that name a brand, and the brand they stand for
+const BRANDS: &[(&str, &str)] = &[
+    ("apple", "apple"),
+    ("canon", "canon"),
+    ("fujifilm", "fujifilm"),
+    ("fujinon", "fujifilm"),
+    ("google", "google"),
+    ("leica", "leica"),
+    ("lumix", "panasonic"),
+    ("nikon", "nikon"),
+    ("nikkor", "nikon"),
+    ("olympus", "olympus"),
+    ("panasonic", "panasonic"),
+    ("samsung", "samsung"),
+    ("sigma", "sigma"),
+    ("sony", "sony"),
+    ("tamron", "tamron"),
+    ("zeiss", "zeiss"),
+];
+
+/// Split a value into the first brand among its manufacturer prefixes and a
+/// key without the prefixes
+fn brand_and_key(value: &str) -> (Option<&'static str>, String) {
+    let lowercase = value.to_lowercase();
+    let mut tokens: Vec<String> = Vec::new();
+    for token in lowercase.split_whitespace() {
+        let previous_is_number = tokens
+            .last()
+            .map(|t| t.ends_with(|c: char| c.is_ascii_digit()))
+            .unwrap_or(false);
+        if token == "mm" && previous_is_number {
+            tokens.last_mut().unwrap().push_str("mm");
+        } else {
+            tokens.push(String::from(token));
+        }
+    }
+
+    let trimmed: Vec<&str> = tokens
+        .iter()
+        .map(|t| t.trim_matches(|c: char| !c.is_alphanumeric()))
+        .collect();
+    let prefix_len = trimmed
+        .iter()
+        .take_while(|t| MANUFACTURER_PREFIXES.contains(t))
+        .count();
+    let brand = trimmed[..prefix_len].iter().find_map(|t| {
+        BRANDS
+            .iter()
+            .find(|(prefix, _)| prefix == t)
+            .map(|(_, brand)| *brand)
+    });
+    let key: String = trimmed[prefix_len..]
+        .iter()
+        .flat_map(|t| t.chars())
+        .filter(|c| c.is_alphanumeric() || *c == '.')
+        .collect();
+
+    if key.is_empty() {
+        (None, lowercase.trim().to_string())
+    } else {
+        (brand, key)
+    }
+}
+
+/// Reduce a value to a key that is shared by near-identical strings.
+///
+/// Ignores case, whitespace, punctuation, known manufacturer prefixes
+/// and spacing between a number and `mm`.
+pub fn normalize_value(value: &str) -> String {
+    brand_and_key(value).1
+}
+
+/// Suggest mappings for a variable given the counts of its distinct values.
+///
+/// Values with the same normalized key and brand are grouped, and each group
+/// with more than one value is mapped to its most frequent value (ties go to
+/// the shorter, then alphabetically first value). Values without a brand join
+/// the group of the same key if only one brand uses that key, so that
+/// "Sigma 35mm F1.4" and "Tamron 35mm F1.4" are kept apart.
+pub fn suggest_mappings(variable: &str, value_counts: &HashMap<String, usize>) -> Mappings {
+    let keyed: Vec<(Option<&str>, String, &String, usize)> = value_counts
+        .iter()
+        .map(|(value, count)| {
+            let (brand, key) = brand_and_key(value);
+            (brand, key, value, *count)
+        })
+        .collect();
+    let mut brands: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (brand, key, _, _) in &keyed {
+        if let Some(brand) = brand {
+            let key_brands = brands.entry(key).or_default();
+            if !key_brands.contains(brand) {
+                key_brands.push(brand);
+            }
+        }
+    }
+
+    // (key, brand) -> values and counts
+    let mut clusters: HashMap<_, Vec<(&String, usize)>> = HashMap::new();
+    for (brand, key, value, count) in &keyed {
+        let brand = match (brand, brands.get(key.as_str()).map(Vec::as_slice)) {
+            (None, Some([only])) => Some(*only),
+            _ => *brand,
+        };
+        clusters
+            .entry((key.as_str(), brand))
+            .or_default()
+            .push((value, *count));
+    }
+
+    let mut mappings: Mappings = Vec::new();
+    for (_, values) in clusters.into_iter().sorted_by(|a, b| a.0.cmp(&b.0)) {
+        if values.len() < 2 {
+            continue;
+        }
+        let sorted_values: Vec<&String> = values
+            .into_iter()
+            .sorted_by(|a, b| {
+                b.1.cmp(&a.1)
+                    .then(a.0.len().cmp(&b.0.len()))
+                    .then(a.0.cmp(b.0))
+            })
+            .map(|x| x.0)
+            .collect();
+        let assign_value = sorted_values[0];
+        let match_values: Vec<String> = sorted_values[1..]
+            .iter()
+            .map(|x| String::from(x.as_str()))
+            .sorted()
+            .collect();
+        mappings.push(Mapping::new(variable, match_values, assign_value));
+    }
+    mappings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fileindex::{MetaValue, MetaVariable};
+    use crate::variablemapping::apply_mappings;
+
+    #[test]
+    fn test_normalize_value() {
+        assert_eq!(
+            normalize_value("15 mm f/4.5"),
+            normalize_value("15mm f/4.5")
+        );
+        assert_eq!(normalize_value("85MM F/1.8"), normalize_value("85mm f/1.8"));
+        assert_eq!(
+            normalize_value("Nikon AF-S NIKKOR 105mm f/1.4E"),
+            normalize_value("105mm f/1.4E")
+        );
+        assert_eq!(normalize_value("NIKON Z 6"), normalize_value("Z 6"));
+        assert_eq!(normalize_value("  Z  6_2 "), normalize_value("Z 6_2"));
+        assert_ne!(normalize_value("85mm f/1.8"), normalize_value("35mm f/1.8"));
+        assert_eq!(normalize_value("Nikon"), "nikon");
+        assert_eq!(brand_and_key("NIKKOR Z 50mm").0, Some("nikon"));
+        assert_eq!(brand_and_key("AF-S 50mm").0, None);
+    }
+
+    #[test]
+    fn test_suggest_mappings_keeps_brands_apart() {
+        let value_counts: HashMap<String, usize> = HashMap::from([
+            (String::from("Sigma 35mm F1.4"), 10),
+            (String::from("Tamron 35mm F1.4"), 8),
+            (String::from("35mm F1.4"), 2),
+            (String::from("Nikon AF-S NIKKOR 105mm f/1.4E"), 5),
+            (String::from("AF-S Nikkor 105mm f/1.4E"), 1),
+            (String::from("105mm f/1.4E"), 3),
+        ]);
+
+        let mappings = suggest_mappings("Lens", &value_counts);
+        let mut variables: Vec<MetaVariable> = ["Tamron 35mm F1.4", "35mm F1.4", "105mm f/1.4E"]
+            .into_iter()
+            .map(|value| MetaVariable {
+                name: String::from("Lens"),
+                value: MetaValue::String(String::from(value)),
+            })
+            .collect();
+        apply_mappings(&mappings, &mut variables);
+        assert_eq!(format!("{}", variables[0].value), "Tamron 35mm F1.4");
+        // ambiguous between Sigma and Tamron
+        assert_eq!(format!("{}", variables[1].value), "35mm F1.4");
+        assert_eq!(
+            format!("{}", variables[2].value),
+            "Nikon AF-S NIKKOR 105mm f/1.4E"
+        );
+    }
+
+    #[test]
+    fn test_suggest_mappings() {
+        let value_counts: HashMap<String, usize> = HashMap::from([
+            (String::from("15mm f/4.5"), 10),
+            (String::from("15 mm f/4.5"), 3),
+            (String::from("15 MM F/4.5"), 1),
+            (String::from("85mm f/1.8"), 52),
+            (String::from("35mm f/1.8"), 36),
+        ]);
+
+        let mappings = suggest_mappings("Lens", &value_counts);
+        assert_eq!(mappings.len(), 1);
+
+        let mut variables: Vec<MetaVariable> = vec![
+            MetaVariable {
+                name: String::from("Lens"),
+                value: MetaValue::String(String::from("15 MM F/4.5")),
+            },
+            MetaVariable {
+                name: String::from("Lens"),
+                value: MetaValue::String(String::from("15 mm f/4.5")),
+            },
+            MetaVariable {
+                name: String::from("Lens"),
+                value: MetaValue::String(String::from("85mm f/1.8")),
+            },
+        ];
+        apply_mappings(&mappings, &mut variables);
+        assert_eq!(format!("{}", variables[0].value), "15mm f/4.5");
+        assert_eq!(format!("{}", variables[1].value), "15mm f/4.5");
+        assert_eq!(format!("{}", variables[2].value), "85mm f/1.8");
+    }
+}
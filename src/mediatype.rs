@@ -0,0 +1,177 @@
+//! Detect the type of photo and video files from their first bytes.
+//!
+//! Media types are returned as MIME types such as `image/jpeg` or
+//! `video/quicktime`. Raw formats that are plain TIFF files (NEF, ARW, DNG,
+//! ...) can only be told apart by their extension.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Number of bytes needed to detect all supported types
+const HEADER_LEN: usize = 32;
+
+/// Raw formats that use a plain TIFF header, by extension
+const TIFF_RAW_EXTENSIONS: &[(&str, &str)] = &[
+    ("nef", "image/x-nikon-nef"),
+    ("nrw", "image/x-nikon-nrw"),
+    ("arw", "image/x-sony-arw"),
+    ("srf", "image/x-sony-srf"),
+    ("sr2", "image/x-sony-sr2"),
+    ("dng", "image/x-adobe-dng"),
+    ("pef", "image/x-pentax-pef"),
+    ("3fr", "image/x-hasselblad-3fr"),
+    ("erf", "image/x-epson-erf"),
+    ("raw", "image/x-raw"),
+];
+
+/// Detect the media type of a file, None if it is not a known photo or video format
+pub fn detect_media_type(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    Ok(media_type_from_header(&header, extension.as_deref()))
+}
+
+/// Detect the media type from the first bytes of a file
+pub fn media_type_from_header(header: &[u8], extension: Option<&str>) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    if at(0, &[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if at(0, b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        Some("image/gif")
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        Some("image/webp")
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        Some("video/x-msvideo")
+    } else if at(0, b"FUJIFILMCCD-RAW") {
+        Some("image/x-fujifilm-raf")
+    } else if at(0, b"IIRO") || at(0, b"IIRS") || at(0, b"MMOR") {
+        Some("image/x-olympus-orf")
+    } else if at(0, b"IIU\0") {
+        Some("image/x-panasonic-rw2")
+    } else if at(0, b"II*\0") && at(8, b"CR") {
+        Some("image/x-canon-cr2")
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        let raw = TIFF_RAW_EXTENSIONS
+            .iter()
+            .find(|(ext, _)| Some(*ext) == extension)
+            .map(|(_, media_type)| *media_type);
+        Some(raw.unwrap_or("image/tiff"))
+    } else if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("video/x-matroska")
+    } else if at(4, b"ftyp") {
+        iso_media_type(header.get(8..12)?, header.get(16..)?)
+    } else if at(4, b"moov") || at(4, b"mdat") || at(4, b"wide") || at(4, b"free") {
+        // QuickTime files written without a file type box
+        Some("video/quicktime")
+    } else {
+        None
+    }
+}
+
+/// Media type of an ISO base media file (MP4, MOV, HEIF, AVIF, CR3) from its
+/// major brand and the start of its compatible brands
+fn iso_media_type(brand: &[u8], compatible: &[u8]) -> Option<&'static str> {
+    match brand {
+        b"avif" | b"avis" => Some("image/avif"),
+        b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" => Some("image/heic"),
+        b"mif1" | b"msf1" => {
+            if compatible.chunks(4).any(|b| b == b"avif") {
+                Some("image/avif")
+            } else {
+                Some("image/heic")
+            }
+        }
+        b"crx " => Some("image/x-canon-cr3"),
+        b"qt  " => Some("video/quicktime"),
+        b"3gp4" | b"3gp5" | b"3gp6" | b"3g2a" => Some("video/3gpp"),
+        _ => Some("video/mp4"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::fixture;
+
+    fn ftyp(brand: &[u8], compatible: &[u8]) -> Vec<u8> {
+        let mut header = vec![0, 0, 0, 0x20];
+        header.extend_from_slice(b"ftyp");
+        header.extend_from_slice(brand);
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header.extend_from_slice(compatible);
+        header
+    }
+
+    #[test]
+    fn test_media_type_from_header() {
+        assert_eq!(
+            media_type_from_header(&[0xFF, 0xD8, 0xFF, 0xE1], None),
+            Some("image/jpeg")
+        );
+        assert_eq!(
+            media_type_from_header(b"RIFF\0\0\0\0WEBPVP8 ", Some("webp")),
+            Some("image/webp")
+        );
+        assert_eq!(
+            media_type_from_header(b"II*\0\x10\0\0\0CR\x02\0", Some("cr2")),
+            Some("image/x-canon-cr2")
+        );
+        assert_eq!(
+            media_type_from_header(b"II*\0\x08\0\0\0", Some("nef")),
+            Some("image/x-nikon-nef")
+        );
+        assert_eq!(
+            media_type_from_header(b"MM\0*\0\0\0\x08", None),
+            Some("image/tiff")
+        );
+        assert_eq!(
+            media_type_from_header(b"FUJIFILMCCD-RAW 0201", None),
+            Some("image/x-fujifilm-raf")
+        );
+        assert_eq!(
+            media_type_from_header(&ftyp(b"heic", b"mif1heic"), None),
+            Some("image/heic")
+        );
+        assert_eq!(
+            media_type_from_header(&ftyp(b"mif1", b"avifmiaf"), None),
+            Some("image/avif")
+        );
+        assert_eq!(
+            media_type_from_header(&ftyp(b"crx ", b"isom"), None),
+            Some("image/x-canon-cr3")
+        );
+        assert_eq!(
+            media_type_from_header(&ftyp(b"qt  ", b"qt  "), None),
+            Some("video/quicktime")
+        );
+        assert_eq!(
+            media_type_from_header(&ftyp(b"isom", b"iso2avc1mp41"), None),
+            Some("video/mp4")
+        );
+        assert_eq!(media_type_from_header(b"[{\"SourceFile\"", None), None);
+        assert_eq!(media_type_from_header(b"", None), None);
+    }
+
+    #[test]
+    fn test_detect_media_type() {
+        assert_eq!(
+            detect_media_type(Path::new(&fixture("exif.jpg"))).unwrap(),
+            Some("image/jpeg")
+        );
+        assert_eq!(
+            detect_media_type(Path::new(&fixture("exif.tif"))).unwrap(),
+            Some("image/tiff")
+        );
+        assert!(detect_media_type(Path::new(&fixture("missing.jpg"))).is_err());
+    }
+}
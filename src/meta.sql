@@ -3,6 +3,34 @@
 -- Drop the view if it exists
 DROP TABLE IF EXISTS meta;
 
+-- Decimal degrees from exiftool GPS coordinates, either formatted such as
+-- 37 deg 46' 29.64" N or numeric with -n. South and west are negative, from
+-- the sign, a trailing S or W, or the reference tag (S, South, W, West)
+CREATE OR REPLACE TEMP MACRO gps_degrees(value, ref) AS (
+    CASE WHEN regexp_matches(value, '^\s*-?[0-9.]+') THEN
+        (CASE
+            WHEN starts_with(trim(value), '-')
+                OR regexp_matches(value, '[SW]\s*$')
+                OR upper(trim(COALESCE(ref, ''))) IN ('S', 'SOUTH', 'W', 'WEST')
+            THEN -1 ELSE 1
+        END) * (
+            abs(CAST(regexp_extract(value, '^\s*(-?[0-9.]+)', 1) AS DOUBLE))
+            + COALESCE(TRY_CAST(regexp_extract(value, 'deg\s*([0-9.]+)''', 1) AS DOUBLE), 0) / 60
+            + COALESCE(TRY_CAST(regexp_extract(value, '([0-9.]+)"', 1) AS DOUBLE), 0) / 3600
+        )
+    END
+);
+
+-- Metres from exiftool GPS altitudes such as 15.2 m Above Sea Level, or numeric
+-- with -n where a reference of 1 means below sea level
+CREATE OR REPLACE TEMP MACRO gps_altitude(value, ref) AS (
+    (CASE
+        WHEN contains(COALESCE(value, '') || COALESCE(ref, ''), 'Below')
+            OR trim(COALESCE(ref, '')) = '1'
+        THEN -1 ELSE 1
+    END) * abs(TRY_CAST(regexp_extract(value, '^\s*(-?[0-9.]+)', 1) AS DOUBLE))
+);
+
 -- Create a table to store the extracted EXIF values
 CREATE TEMP TABLE meta (
     sha256 TEXT PRIMARY KEY,
@@ -23,7 +51,19 @@ CREATE TEMP TABLE meta (
     FocalLengthIn35mmFormat TEXT,
     DateTakenStr TEXT,
     DateTaken TIMESTAMP,
-    LensInferred TEXT
+    LensInferred TEXT,
+    GPSLatitude DOUBLE,
+    GPSLongitude DOUBLE,
+    GPSAltitude DOUBLE,
+    Country TEXT,
+    CountryCode TEXT,
+    Region TEXT,
+    City TEXT,
+    InferredLatitude DOUBLE,
+    InferredLongitude DOUBLE,
+    InferredAltitude DOUBLE,
+    InferredTrack TEXT,
+    MetaError TEXT
 );
 
 -- Insert data into the meta table
@@ -62,8 +102,36 @@ SELECT
          WHEN json_extract(read_json_auto, '$.Model') ILIKE '%iPhone%' THEN json_extract(read_json_auto, '$.Model')
          ELSE NULL
      END 
-    ) AS LensInferred
-FROM read_json_auto('{{datapath}}/*.json', ignore_errors=true, union_by_name=true);
+    ) AS LensInferred,
+    gps_degrees(
+        json_extract_string(read_json_auto, '$.GPSLatitude'),
+        json_extract_string(read_json_auto, '$.GPSLatitudeRef')
+    ) AS GPSLatitude,
+    gps_degrees(
+        json_extract_string(read_json_auto, '$.GPSLongitude'),
+        json_extract_string(read_json_auto, '$.GPSLongitudeRef')
+    ) AS GPSLongitude,
+    gps_altitude(
+        json_extract_string(read_json_auto, '$.GPSAltitude'),
+        json_extract_string(read_json_auto, '$.GPSAltitudeRef')
+    ) AS GPSAltitude,
+    -- filled from the GPS location by the geocoder in indexdb.rs
+    NULL AS Country,
+    NULL AS CountryCode,
+    NULL AS Region,
+    NULL AS City,
+    -- matched to GPS tracks by the geotag action, never from the camera
+    TRY_CAST(json_extract_string(read_json_auto, '$.inferred_location.latitude') AS DOUBLE) AS InferredLatitude,
+    TRY_CAST(json_extract_string(read_json_auto, '$.inferred_location.longitude') AS DOUBLE) AS InferredLongitude,
+    TRY_CAST(json_extract_string(read_json_auto, '$.inferred_location.altitude') AS DOUBLE) AS InferredAltitude,
+    json_extract_string(read_json_auto, '$.inferred_location.track') AS InferredTrack,
+    json_extract_string(read_json_auto, '$.meta_error.message') AS MetaError
+FROM (
+    -- {{metajson}} combines top-level metadata with the output of named extractors,
+    -- {{metasource}} is the sidecar files or the rawmeta table
+    SELECT sha256, {{metajson}} AS read_json_auto
+    FROM {{metasource}}
+);
 
 -- Create an index for the sha256 column
 CREATE INDEX sha256_index ON meta (sha256);
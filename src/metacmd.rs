@@ -0,0 +1,603 @@
+//! Module to run the external metadata command for a file.
+//!
+//! The command line is split into arguments with shell quoting rules and
+//! executed directly (no shell). The file is streamed to the command's
+//! stdin, unless an argument contains the `{path}` placeholder, in which
+//! case the placeholder is replaced with the file path instead.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::exiftoolpool::ExiftoolPool;
+use crate::libraryconfig::ExtractorConfig;
+use crate::nativeexif;
+
+/// Placeholder for the file path in a metadata command
+pub const PATH_PLACEHOLDER: &str = "{path}";
+
+/// How long to wait for the output pipes to close after the command exited
+/// or was killed. A process started by the command can keep them open.
+const PIPE_GRACE: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+pub struct MetaCommand {
+    argv: Vec<String>,
+    timeout: Duration,
+}
+
+/// Output of a successful metadata command run
+#[derive(Debug)]
+pub struct MetaOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Debug)]
+pub enum MetaCommandError {
+    /// The command could not be started or its I/O failed
+    Io(io::Error),
+    /// The command did not finish within the timeout and was killed
+    Timeout(Duration),
+    /// The command exited with a non-zero status
+    Failed { status: ExitStatus, stderr: String },
+    /// The command produced no output for the file
+    NoOutput { stderr: String },
+}
+
+impl fmt::Display for MetaCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetaCommandError::Io(e) => write!(f, "I/O error: {}", e),
+            MetaCommandError::Timeout(t) => write!(f, "timed out after {:?}", t),
+            MetaCommandError::Failed { status, stderr } => {
+                write!(f, "exited with {}: {}", status, stderr.trim())
+            }
+            MetaCommandError::NoOutput { stderr } => write!(f, "no output: {}", stderr.trim()),
+        }
+    }
+}
+
+impl std::error::Error for MetaCommandError {}
+
+impl From<io::Error> for MetaCommandError {
+    fn from(e: io::Error) -> Self {
+        MetaCommandError::Io(e)
+    }
+}
+
+impl MetaCommand {
+    /// Parse a command line such as `exiftool -j -b -` into a command.
+    /// Returns None when the command line is empty or cannot be split.
+    pub fn parse(command_line: &str, timeout: Duration) -> Option<MetaCommand> {
+        let argv = shlex::split(command_line)?;
+        if argv.is_empty() {
+            None
+        } else {
+            Some(MetaCommand { argv, timeout })
+        }
+    }
+
+    pub fn argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// The command line, quoted for display
+    pub fn command_line(&self) -> String {
+        shlex::try_join(self.argv.iter().map(|x| x.as_str()))
+            .unwrap_or_else(|_| self.argv.join(" "))
+    }
+
+    fn uses_path_placeholder(&self) -> bool {
+        self.argv.iter().any(|a| a.contains(PATH_PLACEHOLDER))
+    }
+
+    /// Run the command for a single file
+    pub fn run(&self, path: &str) -> Result<MetaOutput, MetaCommandError> {
+        let uses_path = self.uses_path_placeholder();
+        let args: Vec<String> = self.argv[1..]
+            .iter()
+            .map(|a| a.replace(PATH_PLACEHOLDER, path))
+            .collect();
+
+        // open the file before spawning so a missing file does not start the command
+        let input = if uses_path {
+            None
+        } else {
+            Some(File::open(path)?)
+        };
+
+        let mut child = Command::new(&self.argv[0])
+            .args(&args)
+            .stdin(if uses_path {
+                Stdio::null()
+            } else {
+                Stdio::piped()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // stdin, stdout and stderr are handled in separate threads so that
+        // a command that writes a lot of output before reading all of its
+        // input cannot block us
+        let start = Instant::now();
+        let stdin_result = match (child.stdin.take(), input) {
+            (Some(mut stdin), Some(mut input)) => Some(in_thread(move || {
+                // commands may stop reading once they have what they need
+                match io::copy(&mut input, &mut stdin) {
+                    Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e),
+                    _ => Ok(()),
+                }
+            })),
+            _ => None,
+        };
+        let stdout_result = read_in_thread(child.stdout.take());
+        let stderr_result = read_in_thread(child.stderr.take());
+
+        let status = wait_with_timeout(&mut child, self.timeout)?;
+
+        // the threads are not joined: if the pipes are still held open they
+        // are left to finish on their own instead of blocking the indexer
+        let deadline = (start + self.timeout).max(Instant::now() + PIPE_GRACE);
+        let receive = |result: &Receiver<Vec<u8>>| {
+            result.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        };
+        let (status, stdout, stderr) =
+            match (status, receive(&stdout_result), receive(&stderr_result)) {
+                (Some(status), Ok(stdout), Ok(stderr)) => (status, stdout, stderr),
+                _ => return Err(MetaCommandError::Timeout(self.timeout)),
+            };
+        if let Some(stdin_result) = stdin_result {
+            if let Ok(Err(e)) = stdin_result.recv_timeout(PIPE_GRACE) {
+                return Err(MetaCommandError::Io(e));
+            }
+        }
+
+        if status.success() {
+            Ok(MetaOutput {
+                stdout: String::from_utf8_lossy(&stdout).to_string(),
+                stderr: String::from_utf8_lossy(&stderr).to_string(),
+            })
+        } else {
+            Err(MetaCommandError::Failed {
+                status,
+                stderr: String::from_utf8_lossy(&stderr).to_string(),
+            })
+        }
+    }
+}
+
+/// How to run a metadata command
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetaBackend {
+    /// Run the metadata command once per file
+    Command,
+    /// Keep exiftool processes running (-stay_open) and send them each file. The metadata
+    /// command must be an exiftool command line; falls back to running it once per file if
+    /// exiftool cannot be started
+    ExiftoolPool,
+    /// Read EXIF data with the built-in reader, no metadata command is run
+    Native,
+}
+
+/// The ways of extracting metadata for a file
+#[derive(Debug, Clone)]
+pub enum MetaExtractor {
+    /// Run a command for each file
+    Command(MetaCommand),
+    /// Send each file to a pool of running exiftool processes
+    ExiftoolPool(Arc<ExiftoolPool>),
+    /// Read EXIF data with the built-in reader
+    Native,
+}
+
+impl MetaExtractor {
+    pub fn command_line(&self) -> String {
+        match self {
+            MetaExtractor::Command(cmd) => cmd.command_line(),
+            MetaExtractor::ExiftoolPool(pool) => pool.command_line(),
+            MetaExtractor::Native => String::from("native EXIF reader"),
+        }
+    }
+
+    pub fn run(&self, path: &str) -> Result<MetaOutput, MetaCommandError> {
+        match self {
+            MetaExtractor::Command(cmd) => cmd.run(path),
+            MetaExtractor::ExiftoolPool(pool) => pool.run(path),
+            MetaExtractor::Native => match nativeexif::read_exif(path) {
+                Ok(json_val) => Ok(MetaOutput {
+                    stdout: json_val.to_string(),
+                    stderr: String::new(),
+                }),
+                Err(exif::Error::Io(e)) => Err(MetaCommandError::Io(e)),
+                Err(e) => Err(MetaCommandError::NoOutput {
+                    stderr: e.to_string(),
+                }),
+            },
+        }
+    }
+}
+
+/// A metadata extractor together with where its output is stored and
+/// which files it applies to
+#[derive(Debug, Clone)]
+pub struct NamedExtractor {
+    /// Name of the extractor, or None for the default metadata command
+    /// whose output is stored at the top level of the JSON metadata
+    pub name: Option<String>,
+    /// Lowercase file extensions to run on, all files if empty
+    pub extensions: Vec<String>,
+    pub extractor: MetaExtractor,
+}
+
+impl NamedExtractor {
+    pub fn applies_to(&self, path: &str) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str());
+        match extension {
+            Some(ext) => self.extensions.contains(&ext.to_lowercase()),
+            None => false,
+        }
+    }
+}
+
+/// Why the metadata extractors cannot be set up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtractorError {
+    /// Extractors were selected, but photocat.toml configures none
+    NoneConfigured,
+    /// A selected extractor is not configured in photocat.toml
+    Unknown(String),
+    /// A configured extractor has no command and does not use the native backend
+    NoCommand(String),
+    /// A command line cannot be split into arguments
+    InvalidCommand(String),
+}
+
+impl fmt::Display for ExtractorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtractorError::NoneConfigured => {
+                write!(f, "No extractors are configured in photocat.toml")
+            }
+            ExtractorError::Unknown(name) => {
+                write!(f, "Extractor {} is not configured in photocat.toml", name)
+            }
+            ExtractorError::NoCommand(name) => write!(f, "Extractor {} has no command", name),
+            ExtractorError::InvalidCommand(command_line) => {
+                write!(f, "Cannot parse metadata command {}", command_line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtractorError {}
+
+/// How to run metadata commands, from the command line and photocat.toml
+#[derive(Debug, Clone)]
+pub struct ExtractorOptions {
+    /// Command to run when photocat.toml configures no extractors
+    pub meta_cmd: String,
+    /// Timeout in seconds, unless an extractor sets its own
+    pub timeout: u64,
+    /// Backend, unless an extractor sets its own
+    pub backend: MetaBackend,
+    /// Names of the configured extractors to run, all of them if empty
+    pub selected: Vec<String>,
+    /// Number of exiftool processes of a pool (number of CPUs by default)
+    pub pool_size: Option<usize>,
+}
+
+impl MetaExtractor {
+    /// Set up metadata extraction for a backend. Returns None if there is no
+    /// metadata command to run.
+    pub fn new(
+        command_line: &str,
+        timeout: u64,
+        backend: MetaBackend,
+        pool_size: Option<usize>,
+    ) -> Result<Option<MetaExtractor>, ExtractorError> {
+        if backend == MetaBackend::Native {
+            return Ok(Some(MetaExtractor::Native));
+        }
+        if command_line.trim().is_empty() {
+            return Ok(None);
+        }
+        let meta_cmd = MetaCommand::parse(command_line, Duration::from_secs(timeout))
+            .ok_or_else(|| ExtractorError::InvalidCommand(command_line.to_string()))?;
+        if backend != MetaBackend::ExiftoolPool {
+            return Ok(Some(MetaExtractor::Command(meta_cmd)));
+        }
+        let size = pool_size.unwrap_or_else(|| std::thread::available_parallelism().unwrap().get());
+        match ExiftoolPool::from_command(&meta_cmd, size) {
+            Ok(pool) => Ok(Some(MetaExtractor::ExiftoolPool(Arc::new(pool)))),
+            Err(e) => {
+                warn!(
+                    "Cannot start exiftool pool ({}), running {} for each file instead",
+                    e,
+                    meta_cmd.command_line()
+                );
+                Ok(Some(MetaExtractor::Command(meta_cmd)))
+            }
+        }
+    }
+}
+
+/// The metadata extractors to run when indexing. These are the configured
+/// extractors if there are any, or the metadata command otherwise.
+pub fn named_extractors(
+    configured: &[ExtractorConfig],
+    options: &ExtractorOptions,
+) -> Result<Vec<NamedExtractor>, ExtractorError> {
+    if configured.is_empty() {
+        if !options.selected.is_empty() {
+            return Err(ExtractorError::NoneConfigured);
+        }
+        let extractor = MetaExtractor::new(
+            &options.meta_cmd,
+            options.timeout,
+            options.backend,
+            options.pool_size,
+        )?;
+        return Ok(extractor
+            .map(|extractor| NamedExtractor {
+                name: None,
+                extensions: Vec::new(),
+                extractor,
+            })
+            .into_iter()
+            .collect());
+    }
+
+    if let Some(name) = options
+        .selected
+        .iter()
+        .find(|name| !configured.iter().any(|e| e.name == **name))
+    {
+        return Err(ExtractorError::Unknown(name.clone()));
+    }
+    configured
+        .iter()
+        .filter(|e| options.selected.is_empty() || options.selected.contains(&e.name))
+        .map(|e| {
+            let extractor = MetaExtractor::new(
+                &e.command,
+                e.timeout.unwrap_or(options.timeout),
+                e.backend.unwrap_or(options.backend),
+                options.pool_size,
+            )?
+            .ok_or_else(|| ExtractorError::NoCommand(e.name.clone()))?;
+            Ok(NamedExtractor {
+                name: Some(e.name.clone()),
+                extensions: e.extensions.iter().map(|x| x.to_lowercase()).collect(),
+                extractor,
+            })
+        })
+        .collect()
+}
+
+/// Run a function in a new thread and receive its result from a channel
+fn in_thread<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Receiver<T> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(f());
+    });
+    receiver
+}
+
+fn read_in_thread<R: Read + Send + 'static>(pipe: Option<R>) -> Receiver<Vec<u8>> {
+    in_thread(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+/// Wait for a child process, killing it when the timeout expires.
+/// Returns None if the process was killed.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libraryconfig::LibraryConfig;
+    use crate::testutil::TempDir;
+    use std::io::Write;
+
+    /// Create a file with the given contents in a temporary directory
+    fn temp_file(dir: &TempDir, name: &str, contents: &str) -> String {
+        let path = dir.join(name);
+        let mut file = File::create(&path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        let cmd = MetaCommand::parse("exiftool -j -b -", Duration::from_secs(1)).unwrap();
+        assert_eq!(cmd.argv, vec!["exiftool", "-j", "-b", "-"]);
+        let cmd = MetaCommand::parse("my-tool 'a b' {path}", Duration::from_secs(1)).unwrap();
+        assert_eq!(cmd.argv, vec!["my-tool", "a b", "{path}"]);
+        assert!(cmd.uses_path_placeholder());
+        assert!(MetaCommand::parse("", Duration::from_secs(1)).is_none());
+        assert!(MetaCommand::parse("unbalanced 'quote", Duration::from_secs(1)).is_none());
+    }
+
+    #[test]
+    fn test_run_with_stdin() {
+        let dir = TempDir::new("metacmd");
+        let path = temp_file(&dir, "stdin.json", "{\"a\": 1}");
+        let cmd = MetaCommand::parse("cat", Duration::from_secs(10)).unwrap();
+        let output = cmd.run(&path).unwrap();
+        assert_eq!(output.stdout, "{\"a\": 1}");
+    }
+
+    #[test]
+    fn test_run_with_path_placeholder() {
+        let dir = TempDir::new("metacmd");
+        // file names with spaces and quotes are passed through as a single argument
+        let path = temp_file(&dir, "it's a file.json", "{\"b\": 2}");
+        let cmd = MetaCommand::parse("cat {path}", Duration::from_secs(10)).unwrap();
+        let output = cmd.run(&path).unwrap();
+        assert_eq!(output.stdout, "{\"b\": 2}");
+    }
+
+    #[test]
+    fn test_run_failure() {
+        let dir = TempDir::new("metacmd");
+        let path = temp_file(&dir, "fail.json", "");
+        let script = temp_file(
+            &dir,
+            "fail.sh",
+            "#!/bin/sh\necho \"cannot read $1\" >&2\nexit 3\n",
+        );
+        let cmd = MetaCommand::parse(&format!("sh {} {{path}}", script), Duration::from_secs(10))
+            .unwrap();
+        match cmd.run(&path) {
+            Err(MetaCommandError::Failed { status, stderr }) => {
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stderr.trim(), format!("cannot read {}", path));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_run_timeout() {
+        let dir = TempDir::new("metacmd");
+        let path = temp_file(&dir, "timeout.json", "");
+        let cmd = MetaCommand::parse("sleep 10", Duration::from_millis(100)).unwrap();
+        let start = Instant::now();
+        assert!(matches!(cmd.run(&path), Err(MetaCommandError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_timeout_with_background_process() {
+        let dir = TempDir::new("metacmd");
+        // the background sleep keeps stdout and stderr open after the
+        // command is killed
+        let path = temp_file(&dir, "background.json", "");
+        let cmd = MetaCommand::parse(
+            "sh -c 'sleep 10 & sleep 10' {path}",
+            Duration::from_millis(100),
+        )
+        .unwrap();
+        let start = Instant::now();
+        assert!(matches!(cmd.run(&path), Err(MetaCommandError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_applies_to() {
+        let extractor = NamedExtractor {
+            name: Some(String::from("ffprobe")),
+            extensions: vec![String::from("mov")],
+            extractor: MetaExtractor::Command(
+                MetaCommand::parse("ffprobe {path}", Duration::from_secs(1)).unwrap(),
+            ),
+        };
+        assert!(extractor.applies_to("/photos/clip.MOV"));
+        assert!(!extractor.applies_to("/photos/image.jpg"));
+        assert!(!extractor.applies_to("/photos/no_extension"));
+    }
+
+    #[test]
+    fn test_run_missing_command() {
+        let dir = TempDir::new("metacmd");
+        let path = temp_file(&dir, "missing.json", "");
+        let cmd =
+            MetaCommand::parse("photocat-command-does-not-exist", Duration::from_secs(1)).unwrap();
+        assert!(matches!(cmd.run(&path), Err(MetaCommandError::Io(_))));
+    }
+
+    #[test]
+    fn test_named_extractors() {
+        let options = ExtractorOptions {
+            meta_cmd: String::from("exiftool -j -b -"),
+            timeout: 60,
+            backend: MetaBackend::Command,
+            selected: Vec::new(),
+            pool_size: None,
+        };
+        let extractors = named_extractors(&[], &options).unwrap();
+        assert_eq!(extractors.len(), 1);
+        assert_eq!(extractors[0].name, None);
+        assert_eq!(extractors[0].extractor.command_line(), "exiftool -j -b -");
+        let no_command = ExtractorOptions {
+            meta_cmd: String::from(" "),
+            ..options.clone()
+        };
+        assert!(named_extractors(&[], &no_command).unwrap().is_empty());
+
+        let configured = toml::from_str::<LibraryConfig>(
+            "[[extractor]]\nname = 'exif'\nbackend = 'native'\n\
+             [[extractor]]\nname = 'ffprobe'\ncommand = 'ffprobe {path}'\nextensions = ['MOV']\n",
+        )
+        .unwrap()
+        .extractor;
+        let extractors = named_extractors(&configured, &options).unwrap();
+        assert_eq!(extractors.len(), 2);
+        assert_eq!(extractors[1].extensions, vec!["mov"]);
+        let selected = ExtractorOptions {
+            selected: vec![String::from("ffprobe")],
+            ..options.clone()
+        };
+        let extractors = named_extractors(&configured, &selected).unwrap();
+        assert_eq!(extractors[0].name.as_deref(), Some("ffprobe"));
+
+        let unknown = ExtractorOptions {
+            selected: vec![String::from("mediainfo")],
+            ..options.clone()
+        };
+        assert_eq!(
+            named_extractors(&configured, &unknown).unwrap_err(),
+            ExtractorError::Unknown(String::from("mediainfo"))
+        );
+        assert_eq!(
+            named_extractors(&[], &unknown).unwrap_err(),
+            ExtractorError::NoneConfigured
+        );
+        let configured = toml::from_str::<LibraryConfig>("[[extractor]]\nname = 'empty'\n")
+            .unwrap()
+            .extractor;
+        assert_eq!(
+            named_extractors(&configured, &options).unwrap_err(),
+            ExtractorError::NoCommand(String::from("empty"))
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! Built-in EXIF reader, used when exiftool is not available.
+//!
+//! Reads EXIF from JPEG, TIFF-based raw files (DNG, NEF, ARW, ...), HEIF/HEIC,
+//! PNG and WebP, and produces JSON in the same shape as `exiftool -j`, using
+//! exiftool's key names and value formatting for the variables in `meta.sql`.
+
+use exif::{Exif, In, Rational, Reader, Tag, Value};
+use serde_json::{json, Map, Number};
+use std::fs::File;
+use std::io::BufReader;
+
+/// Read EXIF metadata from a file, returning a single element array like `exiftool -j`
+pub fn read_exif(path: &str) -> Result<serde_json::Value, exif::Error> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+    let exif = Reader::new().read_from_container(&mut reader)?;
+    Ok(json!([exif_to_json(&exif)]))
+}
+
+fn exif_to_json(exif: &Exif) -> Map<String, serde_json::Value> {
+    let mut meta = Map::new();
+    let mut insert_string = |key: &str, value: Option<String>| {
+        if let Some(value) = value {
+            meta.insert(String::from(key), serde_json::Value::String(value));
+        }
+    };
+
+    insert_string("Make", ascii(exif, Tag::Make));
+    insert_string("Model", ascii(exif, Tag::Model));
+    insert_string("Software", ascii(exif, Tag::Software));
+    insert_string("Artist", ascii(exif, Tag::Artist));
+    insert_string("LensMake", ascii(exif, Tag::LensMake));
+    insert_string("LensModel", ascii(exif, Tag::LensModel));
+    // exiftool derives Lens from maker notes, the lens model is the closest we have
+    insert_string("Lens", ascii(exif, Tag::LensModel));
+    insert_string("LensInfo", lens_info(exif));
+    insert_string("Orientation", uint(exif, Tag::Orientation).map(orientation));
+    insert_string("ModifyDate", ascii(exif, Tag::DateTime));
+    insert_string("DateTimeOriginal", ascii(exif, Tag::DateTimeOriginal));
+    insert_string("CreateDate", ascii(exif, Tag::DateTimeDigitized));
+    insert_string(
+        "FocalLength",
+        rational(exif, Tag::FocalLength).map(|f| format!("{:.1} mm", f.to_f64())),
+    );
+    insert_string(
+        "FocalLengthIn35mmFormat",
+        uint(exif, Tag::FocalLengthIn35mmFilm).map(|f| format!("{} mm", f)),
+    );
+
+    let exposure_time = rational(exif, Tag::ExposureTime).map(|x| x.to_f64());
+    let shutter_speed = exposure_time
+        .or_else(|| srational(exif, Tag::ShutterSpeedValue).map(|apex| 2f64.powf(-apex)));
+    if let Some(exposure_time) = exposure_time {
+        meta.insert(String::from("ExposureTime"), exposure(exposure_time));
+    }
+    if let Some(shutter_speed) = shutter_speed {
+        meta.insert(String::from("ShutterSpeed"), exposure(shutter_speed));
+    }
+
+    let f_number = rational(exif, Tag::FNumber).map(|x| x.to_f64());
+    let aperture = f_number
+        .or_else(|| rational(exif, Tag::ApertureValue).map(|apex| 2f64.powf(apex.to_f64() / 2.0)));
+    if let Some(f_number) = f_number {
+        meta.insert(String::from("FNumber"), f_number_value(f_number));
+    }
+    if let Some(aperture) = aperture {
+        meta.insert(String::from("Aperture"), f_number_value(aperture));
+    }
+
+    if let Some(iso) = uint(exif, Tag::PhotographicSensitivity) {
+        meta.insert(String::from("ISO"), json!(iso));
+    }
+
+    // TIFF files give the image size in the primary image, other formats only
+    // in the EXIF pixel dimensions
+    let width = uint(exif, Tag::ImageWidth).or_else(|| uint(exif, Tag::PixelXDimension));
+    let height = uint(exif, Tag::ImageLength).or_else(|| uint(exif, Tag::PixelYDimension));
+    if let Some(width) = width {
+        meta.insert(String::from("ImageWidth"), json!(width));
+    }
+    if let Some(height) = height {
+        meta.insert(String::from("ImageHeight"), json!(height));
+    }
+
+    for (key, tag, ref_tag, hemispheres) in [
+        (
+            "GPSLatitude",
+            Tag::GPSLatitude,
+            Tag::GPSLatitudeRef,
+            ("N", "North", "South"),
+        ),
+        (
+            "GPSLongitude",
+            Tag::GPSLongitude,
+            Tag::GPSLongitudeRef,
+            ("E", "East", "West"),
+        ),
+    ] {
+        let hemisphere = ascii(exif, ref_tag);
+        if let Some(value) =
+            rationals(exif, tag).and_then(|v| gps_coordinate(v, hemisphere.as_deref()))
+        {
+            meta.insert(String::from(key), json!(value));
+        }
+        if let Some(hemisphere) = hemisphere {
+            let (positive, positive_name, negative_name) = hemispheres;
+            let name = if hemisphere == positive {
+                positive_name
+            } else {
+                negative_name
+            };
+            meta.insert(format!("{}Ref", key), json!(name));
+        }
+    }
+    if let Some(altitude) = rational(exif, Tag::GPSAltitude) {
+        meta.insert(
+            String::from("GPSAltitude"),
+            json!(format!("{} m", short_number(altitude.to_f64()))),
+        );
+        let below = uint(exif, Tag::GPSAltitudeRef) == Some(1);
+        meta.insert(
+            String::from("GPSAltitudeRef"),
+            json!(if below {
+                "Below Sea Level"
+            } else {
+                "Above Sea Level"
+            }),
+        );
+    }
+
+    meta
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(ref values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_end_matches(['\0', ' ']);
+            if value.is_empty() {
+                None
+            } else {
+                Some(String::from(value))
+            }
+        }
+        _ => None,
+    }
+}
+
+fn uint(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+fn rationals(exif: &Exif, tag: Tag) -> Option<&[Rational]> {
+    match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(ref values) => Some(values),
+        _ => None,
+    }
+}
+
+fn rational(exif: &Exif, tag: Tag) -> Option<Rational> {
+    rationals(exif, tag)?
+        .first()
+        .filter(|x| x.denom != 0)
+        .copied()
+}
+
+fn srational(exif: &Exif, tag: Tag) -> Option<f64> {
+    match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::SRational(ref values) => values.first().filter(|x| x.denom != 0).map(|x| x.to_f64()),
+        _ => None,
+    }
+}
+
+/// A number formatted by exiftool, which is written to JSON as a number
+fn number(formatted: &str) -> serde_json::Value {
+    match formatted.parse::<f64>().ok().and_then(Number::from_f64) {
+        Some(n) if formatted.contains('.') => serde_json::Value::Number(n),
+        _ => match formatted.parse::<i64>() {
+            Ok(i) => json!(i),
+            Err(_) => serde_json::Value::String(String::from(formatted)),
+        },
+    }
+}
+
+/// Format a number like `%g`-style output in exiftool (no trailing zeros)
+fn short_number(value: f64) -> String {
+    let formatted = format!("{:.2}", value);
+    String::from(formatted.trim_end_matches('0').trim_end_matches('.'))
+}
+
+/// Exposure time as exiftool prints it: fractions for short exposures
+fn exposure(seconds: f64) -> serde_json::Value {
+    if seconds > 0.0 && seconds < 0.25001 {
+        serde_json::Value::String(format!("1/{}", (0.5 + 1.0 / seconds) as u64))
+    } else {
+        let formatted = format!("{:.1}", seconds);
+        number(formatted.trim_end_matches(".0"))
+    }
+}
+
+fn f_number_value(f_number: f64) -> serde_json::Value {
+    if f_number >= 1.0 {
+        number(&format!("{:.1}", f_number))
+    } else {
+        number(&format!("{:.2}", f_number))
+    }
+}
+
+/// Lens focal length and aperture range, e.g. `24-70mm f/3.5-5.6`
+fn lens_info(exif: &Exif) -> Option<String> {
+    let spec = rationals(exif, Tag::LensSpecification)?;
+    if spec.len() < 4 {
+        return None;
+    }
+    let value = |r: &Rational| {
+        if r.denom == 0 || r.num == 0 {
+            String::from("?")
+        } else {
+            short_number(r.to_f64())
+        }
+    };
+    let range = |a: &Rational, b: &Rational| {
+        let (a, b) = (value(a), value(b));
+        if a == b {
+            a
+        } else {
+            format!("{}-{}", a, b)
+        }
+    };
+    Some(format!(
+        "{}mm f/{}",
+        range(&spec[0], &spec[1]),
+        range(&spec[2], &spec[3])
+    ))
+}
+
+/// GPS coordinate as exiftool prints it, e.g. `37 deg 46' 29.64" N`
+fn gps_coordinate(values: &[Rational], hemisphere: Option<&str>) -> Option<String> {
+    if values.len() < 3 || values.iter().any(|v| v.denom == 0) {
+        return None;
+    }
+    let degrees = values[0].to_f64() + values[1].to_f64() / 60.0 + values[2].to_f64() / 3600.0;
+    let whole_degrees = degrees.trunc();
+    let minutes = ((degrees - whole_degrees) * 60.0).trunc();
+    let seconds = (degrees - whole_degrees - minutes / 60.0) * 3600.0;
+    let formatted = format!("{} deg {}' {:.2}\"", whole_degrees, minutes, seconds);
+    Some(match hemisphere {
+        Some(hemisphere) => format!("{} {}", formatted, hemisphere),
+        None => formatted,
+    })
+}
+
+fn orientation(value: u32) -> String {
+    String::from(match value {
+        1 => "Horizontal (normal)",
+        2 => "Mirror horizontal",
+        3 => "Rotate 180",
+        4 => "Mirror vertical",
+        5 => "Mirror horizontal and rotate 270 CW",
+        6 => "Rotate 90 CW",
+        7 => "Mirror horizontal and rotate 90 CW",
+        8 => "Rotate 270 CW",
+        _ => "Unknown",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::fixture;
+
+    #[test]
+    fn test_read_exif_jpeg() {
+        let meta = read_exif(&fixture("exif.jpg")).unwrap();
+        let expected = json!([{
+            "Make": "NIKON CORPORATION",
+            "Model": "NIKON Z 6",
+            "Software": "Ver.03.00",
+            "Artist": "Photocat Test",
+            "LensModel": "NIKKOR Z 85mm f/1.8 S",
+            "Lens": "NIKKOR Z 85mm f/1.8 S",
+            "LensInfo": "85mm f/1.8",
+            "Orientation": "Horizontal (normal)",
+            "ModifyDate": "2024:07:02 09:00:00",
+            "DateTimeOriginal": "2024:07:01 12:34:56",
+            "CreateDate": "2024:07:01 12:34:56",
+            "FocalLength": "85.0 mm",
+            "FocalLengthIn35mmFormat": "85 mm",
+            "ExposureTime": "1/250",
+            "ShutterSpeed": "1/250",
+            "FNumber": 1.8,
+            "Aperture": 1.8,
+            "ISO": 200,
+            "ImageWidth": 8,
+            "ImageHeight": 8,
+        }]);
+        assert_eq!(meta, expected);
+    }
+
+    #[test]
+    fn test_read_exif_tiff() {
+        let meta = read_exif(&fixture("exif.tif")).unwrap();
+        let expected = json!([{
+            "Make": "SONY",
+            "Model": "ILCE-7M3",
+            "LensModel": "FE 24-70mm F4 ZA OSS",
+            "Lens": "FE 24-70mm F4 ZA OSS",
+            "LensInfo": "24-70mm f/4",
+            "Orientation": "Rotate 90 CW",
+            "DateTimeOriginal": "2023:12:24 18:00:00",
+            "FocalLength": "24.0 mm",
+            "ExposureTime": 2,
+            "ShutterSpeed": 2,
+            "FNumber": 4.0,
+            "Aperture": 4.0,
+            "ISO": 100,
+            "ImageWidth": 1,
+            "ImageHeight": 1,
+        }]);
+        assert_eq!(meta, expected);
+    }
+
+    #[test]
+    fn test_read_exif_no_exif() {
+        assert!(read_exif(&format!("{}/Cargo.toml", env!("CARGO_MANIFEST_DIR"))).is_err());
+    }
+
+    #[test]
+    fn test_gps_coordinate() {
+        let dms = [
+            Rational::from((37, 1)),
+            Rational::from((46, 1)),
+            Rational::from((2964, 100)),
+        ];
+        assert_eq!(
+            gps_coordinate(&dms, Some("N")).unwrap(),
+            "37 deg 46' 29.64\" N"
+        );
+        // decimal degrees in the first value
+        let decimal = [
+            Rational::from((1225, 10)),
+            Rational::from((0, 1)),
+            Rational::from((0, 1)),
+        ];
+        assert_eq!(
+            gps_coordinate(&decimal, None).unwrap(),
+            "122 deg 30' 0.00\""
+        );
+        assert!(gps_coordinate(&dms[..2], Some("N")).is_none());
+    }
+
+    #[test]
+    fn test_exposure() {
+        assert_eq!(exposure(1.0 / 250.0), json!("1/250"));
+        assert_eq!(exposure(0.5), json!(0.5));
+        assert_eq!(exposure(30.0), json!(30));
+    }
+}
@@ -10,18 +10,250 @@ use std::{
 };
 use tokio::task;
 
-use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+/// Count the items of an iterator, so that they can be processed against a
+/// known total. Shows a spinner with the number of items found so far.
+pub fn precount<I, T>(iter: I, progress: bool) -> usize
+where
+    I: IntoIterator<Item = T>,
+{
+    let spinner = if progress {
+        let spinner = ProgressBar::new_spinner();
+        spinner.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.green} {msg}")
+                .unwrap(),
+        );
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        Some(spinner)
+    } else {
+        None
+    };
+    let mut count = 0;
+    for _ in iter {
+        count += 1;
+        if let Some(ref spinner) = spinner {
+            if count % 100 == 0 {
+                spinner.set_message(format!("Counting: {}", count));
+            }
+        }
+    }
+    if let Some(spinner) = spinner {
+        spinner.finish_and_clear();
+    }
+    count
+}
+
+/// Progress message with the number of items and bytes processed per second,
+/// and the current number of concurrent tasks if it is adjusted
+fn progress_message(
+    count: usize,
+    bytes: u64,
+    elapsed: f64,
+    show_bytes: bool,
+    concurrency: Option<usize>,
+) -> String {
+    let (items_per_second, bytes_per_second) = if elapsed > 0.0 {
+        (count as f64 / elapsed, bytes as f64 / elapsed)
+    } else {
+        (0.0, 0.0)
+    };
+    let mut message = format!("#{} {:.2} items/second", count, items_per_second);
+    if show_bytes {
+        message.push_str(&format!(" {:.2} MB/s", bytes_per_second / 1_000_000.0));
+    }
+    if let Some(concurrency) = concurrency {
+        message.push_str(&format!(" concurrency {}", concurrency));
+    }
+    message
+}
+
+/// Throughput this many times lower than the baseline causes a back off
+const BACKOFF_RATIO: f64 = 2.0;
+/// Throughput within this ratio of the baseline allows one more concurrent task
+const RECOVER_RATIO: f64 = 1.25;
+/// The baseline is multiplied by this at each adjustment, so that a fast
+/// period long ago does not keep the limit down
+const BASELINE_DECAY: f64 = 0.9;
+/// Shortest time over which throughput is measured before adjusting
+const ADJUST_INTERVAL: Duration = Duration::from_secs(2);
+/// Work counted for each task on top of its bytes, as opening a file takes
+/// about as long as reading this many bytes. Without it small files would
+/// look slow next to large ones.
+const TASK_OVERHEAD_BYTES: u64 = 1_000_000;
+
+/// Chooses the number of concurrent tasks from their throughput, so that
+/// saturated storage (such as a network share) is not thrashed by too many
+/// readers. Starts at the maximum, halves the limit when the throughput of
+/// tasks drops compared to the baseline, and adds one task at a time while
+/// it is close to the baseline again. The baseline is the best throughput
+/// seen, decaying over time.
+#[derive(Debug)]
+struct AdaptiveConcurrency {
+    limit: usize,
+    max: usize,
+    /// Bytes per second of a task
+    baseline: Option<f64>,
+}
+
+impl AdaptiveConcurrency {
+    fn new(max: usize) -> Self {
+        AdaptiveConcurrency {
+            limit: max,
+            max,
+            baseline: None,
+        }
+    }
+
+    /// New limit after tasks processed `throughput` bytes per second each
+    fn adjust(&mut self, throughput: f64) -> usize {
+        let baseline = match self.baseline {
+            Some(baseline) => (baseline * BASELINE_DECAY).max(throughput),
+            None => throughput,
+        };
+        self.baseline = Some(baseline);
+        if throughput * BACKOFF_RATIO < baseline {
+            self.limit = (self.limit / 2).max(1);
+        } else if throughput * RECOVER_RATIO >= baseline && self.limit < self.max {
+            self.limit += 1;
+        }
+        self.limit
+    }
+}
+
+/// Work done by tasks over an interval
+#[derive(Debug, Default)]
+struct Throughput {
+    count: usize,
+    /// Bytes processed, including the overhead of each task
+    work: u64,
+    /// Sum of the durations of the tasks
+    busy: Duration,
+}
+
+impl Throughput {
+    fn add(&mut self, bytes: u64, duration: Duration) {
+        self.count += 1;
+        self.work += bytes + TASK_OVERHEAD_BYTES;
+        self.busy += duration;
+    }
+
+    /// Bytes per second of a task
+    fn per_task(&self) -> f64 {
+        self.work as f64 / self.busy.as_secs_f64().max(1e-9)
+    }
+}
+
+/// Limits the number of concurrent tasks of a stage, such as reading files,
+/// and adjusts the limit from their throughput
+pub struct AdaptiveLimiter {
+    semaphore: Arc<Semaphore>,
+    limit: AtomicUsize,
+    state: Mutex<AdaptiveState>,
+}
+
+struct AdaptiveState {
+    control: AdaptiveConcurrency,
+    started: Instant,
+    throughput: Throughput,
+}
+
+impl AdaptiveLimiter {
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        AdaptiveLimiter {
+            semaphore: Arc::new(Semaphore::new(max)),
+            limit: AtomicUsize::new(max),
+            state: Mutex::new(AdaptiveState {
+                control: AdaptiveConcurrency::new(max),
+                started: Instant::now(),
+                throughput: Throughput::default(),
+            }),
+        }
+    }
+
+    /// Current number of tasks allowed at once
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::SeqCst)
+    }
+
+    /// Run a task processing `bytes` once the limit allows it. Only the time
+    /// the task runs is measured, not the time waiting for its turn.
+    pub async fn run<Fut: std::future::Future>(&self, bytes: u64, task: Fut) -> Fut::Output {
+        let _permit = self.semaphore.acquire().await.unwrap();
+        let started = Instant::now();
+        let output = task.await;
+        self.record(bytes, started.elapsed());
+        output
+    }
+
+    /// Record how long a task took, and change the limit once enough tasks
+    /// have finished
+    fn record(&self, bytes: u64, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.throughput.add(bytes, duration);
+        if state.started.elapsed() < ADJUST_INTERVAL || state.throughput.count < state.control.limit
+        {
+            return;
+        }
+        let throughput = std::mem::take(&mut state.throughput).per_task();
+        let old_limit = state.control.limit;
+        let new_limit = state.control.adjust(throughput);
+        state.started = Instant::now();
+        self.limit.store(new_limit, Ordering::SeqCst);
+        if new_limit > old_limit {
+            self.semaphore.add_permits(new_limit - old_limit);
+        } else if new_limit < old_limit {
+            // take permits back as running tasks finish
+            let semaphore = self.semaphore.clone();
+            task::spawn(async move {
+                if let Ok(permits) = semaphore
+                    .acquire_many_owned((old_limit - new_limit) as u32)
+                    .await
+                {
+                    permits.forget();
+                }
+            });
+        }
+    }
+}
+
+/// How many items `consume_concurrently` processes at once, and what it shows
+/// about that
+pub struct ConcurrencyOptions<T> {
+    /// The number of concurrent tasks to run (number of CPUs by default)
+    pub concurrency: Option<usize>,
+    /// The limiter of the stage of `f` that is adjusted, to show its limit
+    pub throttle: Option<Arc<AdaptiveLimiter>>,
+    /// Size of an item, to show throughput in MB/s
+    pub item_bytes: Option<fn(&T) -> u64>,
+}
+
+// not derived, which would require T: Default
+impl<T> Default for ConcurrencyOptions<T> {
+    fn default() -> Self {
+        ConcurrencyOptions {
+            concurrency: None,
+            throttle: None,
+            item_bytes: None,
+        }
+    }
+}
 
 /// Consume a stream of items concurrently into a function
 /// Args:
 /// iter: An iterator of items to process
 /// f: A function that processes an item
 /// progress: Whether to show a progress bar
-/// concurrency_opt: The number of concurrent tasks to run
+/// total: Number of items to show progress against, when the iterator does not know it
+/// options: Concurrency and what to show about it
 /// Returns: None
 ///
 /// Example:
+/// ```ignore
 /// async fn process_item(item: i32) {
 ///     // Simulate some async work
 ///     tokio::time::sleep(std::time::Duration::from_millis(10)).await;
@@ -30,14 +262,16 @@ use std::sync::Arc;
 ///
 /// async fn main() {
 ///    let items = vec![1, 2, 3, 4, 5];
-/// >  consume_concurrently(items, process_item, false, None).await;
+///    consume_concurrently(items, process_item, &(), false, None, ConcurrencyOptions::default()).await;
 /// }
+/// ```
 pub async fn consume_concurrently<I, T, F, C, Fut>(
     iter: I,
     f: F,
     context: &C,
     progress: bool,
-    concurrency_opt: Option<usize>,
+    total: Option<usize>,
+    options: ConcurrencyOptions<T>,
 ) where
     I: IntoIterator<Item = T>,
     T: Send + 'static,
@@ -45,22 +279,29 @@ pub async fn consume_concurrently<I, T, F, C, Fut>(
     Fut: std::future::Future<Output = ()> + Send + 'static,
     C: Clone + Send + 'static,
 {
+    let ConcurrencyOptions {
+        concurrency: concurrency_opt,
+        throttle,
+        item_bytes,
+    } = options;
     let concurrency: usize;
     if let Some(conc) = concurrency_opt {
         concurrency = conc;
     } else {
         concurrency = std::thread::available_parallelism().unwrap().get();
     }
+    let concurrency = concurrency.max(1);
     let tstream = stream::iter(iter);
     let (_, upper) = tstream.size_hint();
+    let total = upper.or(total);
     let progress_bar: Option<ProgressBar>;
     if progress {
-        if upper.is_some() {
-            progress_bar = Some(ProgressBar::new(upper.unwrap() as u64));
+        if let Some(total) = total {
+            progress_bar = Some(ProgressBar::new(total as u64));
             progress_bar.as_ref().unwrap().set_style(
                 ProgressStyle::default_bar()
                     .template(
-                        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+                        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}",
                     )
                     .expect("Progress template creation failed")
                     .progress_chars("#>-"),
@@ -86,8 +327,9 @@ pub async fn consume_concurrently<I, T, F, C, Fut>(
         progress_bar = None;
     }
 
-    // Atomic counter to keep track of the number of items processed
+    // Atomic counters to keep track of the number of items and bytes processed
     let counter = Arc::new(AtomicUsize::new(0));
+    let byte_counter = Arc::new(AtomicU64::new(0));
     let done = Arc::new(AtomicBool::new(false));
 
     let handle: tokio::task::JoinHandle<()>;
@@ -95,7 +337,9 @@ pub async fn consume_concurrently<I, T, F, C, Fut>(
         // Start time to calculate elapsed time
         let start_time = Instant::now();
         let counter_clone = Arc::clone(&counter);
+        let byte_counter_clone = Arc::clone(&byte_counter);
         let done_clone = done.clone();
+        let show_bytes = item_bytes.is_some();
 
         // thread to update the spinner message with items per second
         let pb = Arc::new(progress_bar.clone());
@@ -106,26 +350,28 @@ pub async fn consume_concurrently<I, T, F, C, Fut>(
                     // Calculate elapsed time
                     let elapsed = start_time.elapsed().as_secs_f64();
 
-                    // Get the current count
-                    let count = counter_clone.load(Ordering::SeqCst);
-
-                    // Calculate items per second
-                    let items_per_second = if elapsed > 0.0 {
-                        count as f64 / elapsed
-                    } else {
-                        0.0
-                    };
-
-                    // Update spinner message
+                    // Update progress and message
                     let this_count = counter_clone.load(Ordering::SeqCst);
+                    let bytes = byte_counter_clone.load(Ordering::SeqCst);
                     pb.inc((this_count - last_count) as u64);
                     last_count = this_count;
-                    let message = format!("#{} {:.2} items/second", this_count, items_per_second);
-                    pb.set_message(message);
+                    let concurrency = throttle.as_ref().map(|throttle| throttle.limit());
+                    pb.set_message(progress_message(
+                        this_count,
+                        bytes,
+                        elapsed,
+                        show_bytes,
+                        concurrency,
+                    ));
 
                     // Break the loop if work is done
                     let is_done = done_clone.load(Ordering::SeqCst);
                     if is_done {
+                        // the total may have been counted before the items changed
+                        if total.is_some() {
+                            pb.set_length(this_count as u64);
+                        }
+                        pb.finish();
                         break;
                     }
                     // Sleep for a short duration before updating again
@@ -135,34 +381,33 @@ pub async fn consume_concurrently<I, T, F, C, Fut>(
         });
     }
 
-    let tasks;
-    {
-        tasks = tstream
-            .map(|item| {
-                let f = f.clone();
-                let c: C = context.clone();
-                let counter_clone = counter.clone();
-                task::spawn(async move {
-                    f(item, c).await;
-                    counter_clone.fetch_add(1, Ordering::SeqCst);
-                })
+    // results are drained as tasks complete, so they are not kept in memory
+    tstream
+        .map(|item| {
+            let f = f.clone();
+            let c: C = context.clone();
+            let counter_clone = counter.clone();
+            let byte_counter_clone = byte_counter.clone();
+            let bytes = item_bytes.map(|item_bytes| item_bytes(&item)).unwrap_or(0);
+            task::spawn(async move {
+                f(item, c).await;
+                counter_clone.fetch_add(1, Ordering::SeqCst);
+                byte_counter_clone.fetch_add(bytes, Ordering::SeqCst);
             })
-            .buffer_unordered(concurrency) // Adjust concurrency level as needed
-            .collect::<Vec<_>>()
-            .await;
-    }
+        })
+        .buffer_unordered(concurrency)
+        .for_each(|task| async move {
+            if let Err(e) = task {
+                error!("Task failed: {:?}", e);
+            }
+        })
+        .await;
 
     // terminate update thread
     done.store(true, Ordering::SeqCst);
     if let Err(e) = handle.await {
         error!("Progress update thread failed to join: {:?}", e);
     }
-
-    for task in tasks {
-        if let Err(e) = task {
-            error!("Task failed: {:?}", e);
-        }
-    }
 }
 
 #[cfg(test)]
@@ -191,7 +436,18 @@ mod tests {
         }
 
         let start_time = Instant::now();
-        consume_concurrently(items, process_item, &results, false, Some(5)).await;
+        consume_concurrently(
+            items,
+            process_item,
+            &results,
+            false,
+            None,
+            ConcurrencyOptions {
+                concurrency: Some(5),
+                ..ConcurrencyOptions::default()
+            },
+        )
+        .await;
         let end_time = Instant::now();
 
         let elapsed_time = end_time - start_time;
@@ -212,4 +468,88 @@ mod tests {
         assert!(results.contains(&4));
         assert!(results.contains(&5));
     }
+
+    #[test]
+    fn test_precount() {
+        assert_eq!(precount((0..250).filter(|x| x % 2 == 0), false), 125);
+    }
+
+    #[test]
+    fn test_progress_message() {
+        assert_eq!(
+            progress_message(10, 5_000_000, 2.0, true, None),
+            "#10 5.00 items/second 2.50 MB/s"
+        );
+        assert_eq!(
+            progress_message(10, 0, 0.0, false, Some(4)),
+            "#10 0.00 items/second concurrency 4"
+        );
+    }
+
+    #[test]
+    fn test_adaptive_concurrency() {
+        let mut control = AdaptiveConcurrency::new(8);
+        // steady throughput keeps the maximum
+        assert_eq!(control.adjust(100.0), 8);
+        assert_eq!(control.adjust(90.0), 8);
+        // tasks slowing down halve the limit, down to one
+        assert_eq!(control.adjust(40.0), 4);
+        assert_eq!(control.adjust(20.0), 2);
+        assert_eq!(control.adjust(20.0), 1);
+        // in between, the limit stays until the baseline has decayed
+        assert_eq!(control.adjust(45.0), 1);
+        // fast tasks add one at a time, up to the maximum
+        assert_eq!(control.adjust(90.0), 2);
+        assert_eq!(control.adjust(100.0), 3);
+        for _ in 0..10 {
+            control.adjust(100.0);
+        }
+        assert_eq!(control.limit, 8);
+    }
+
+    #[test]
+    fn test_adaptive_concurrency_recovers_from_slower_storage() {
+        let mut control = AdaptiveConcurrency::new(8);
+        control.adjust(100.0);
+        assert_eq!(control.adjust(30.0), 4);
+        // the fast period is forgotten while the storage stays slower
+        let limits: Vec<usize> = (0..20).map(|_| control.adjust(30.0)).collect();
+        assert_eq!(*limits.last().unwrap(), 8);
+    }
+
+    #[test]
+    fn test_adaptive_concurrency_with_mixed_file_sizes() {
+        // storage that opens a file in 10 ms and reads 100 MB/s, whatever
+        // the number of readers
+        let duration = |bytes: u64| Duration::from_secs_f64(0.01 + bytes as f64 / 100e6);
+        let mut control = AdaptiveConcurrency::new(8);
+        for size in [
+            200_000,
+            200_000,
+            30_000_000,
+            30_000_000,
+            5_000,
+            200_000_000,
+            5_000,
+        ] {
+            let mut throughput = Throughput::default();
+            for _ in 0..8 {
+                throughput.add(size, duration(size));
+            }
+            assert_eq!(control.adjust(throughput.per_task()), 8, "{}", size);
+        }
+
+        // more readers than the storage can serve make each one slower
+        let mut throughput = Throughput::default();
+        throughput.add(30_000_000, duration(30_000_000) * 3);
+        assert_eq!(control.adjust(throughput.per_task()), 4);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_limiter() {
+        let limiter = AdaptiveLimiter::new(4);
+        assert_eq!(limiter.run(1000, async { 42 }).await, 42);
+        assert_eq!(limiter.limit(), 4);
+        assert_eq!(AdaptiveLimiter::new(0).limit(), 1);
+    }
 }
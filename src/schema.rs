@@ -0,0 +1,212 @@
+//! Versioned schema of the index database.
+//!
+//! Each change to the tables is a migration with the next version number.
+//! Migrations that have not been applied to a library yet are run in order
+//! when it is opened, and recorded in the `schema_version` table. Libraries
+//! created before versioning have no `schema_version` table; their tables are
+//! adopted by the first migrations, which only create what is missing.
+//! Migrations can also change the files of a library folder.
+
+use chrono::Utc;
+use duckdb::{params, Connection, Result};
+use log::info;
+use std::path::Path;
+
+use crate::indexdb::OpenError;
+use crate::sidecar;
+
+/// A change to the database schema
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    /// SQL statements separated by `;`
+    pub sql: &'static str,
+    /// Changes to the library folder, run before the SQL statements
+    pub files: Option<fn(&Path) -> std::io::Result<usize>>,
+}
+
+/// All migrations, in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create fileindex",
+        sql: "CREATE TABLE IF NOT EXISTS fileindex (
+                filename TEXT NOT NULL,
+                url TEXT NOT NULL,
+                sha256 TEXT NOT NULL,
+                created_at TIMESTAMP NOT NULL,
+                modified_at TIMESTAMP NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_sha256 ON fileindex (sha256);",
+        files: None,
+    },
+    Migration {
+        version: 2,
+        description: "add media_type to fileindex",
+        sql: "ALTER TABLE fileindex ADD COLUMN IF NOT EXISTS media_type TEXT;",
+        files: None,
+    },
+    Migration {
+        version: 3,
+        description: "create index_errors",
+        sql: "CREATE TABLE IF NOT EXISTS index_errors (
+                filename TEXT NOT NULL,
+                stage TEXT NOT NULL,
+                message TEXT NOT NULL,
+                created_at TIMESTAMP NOT NULL
+            );",
+        files: None,
+    },
+    Migration {
+        version: 4,
+        description: "move metadata files into meta/ab/cd/",
+        sql: "",
+        files: Some(sidecar::move_to_shards),
+    },
+    Migration {
+        version: 5,
+        description: "create rawmeta",
+        sql: "CREATE TABLE IF NOT EXISTS rawmeta (
+                sha256 TEXT PRIMARY KEY,
+                metadata JSON NOT NULL,
+                updated_at TIMESTAMP NOT NULL
+            );",
+        files: None,
+    },
+    Migration {
+        version: 6,
+        description: "create metahistory",
+        sql: "CREATE TABLE IF NOT EXISTS metahistory (
+                sha256 TEXT PRIMARY KEY,
+                history JSON NOT NULL,
+                updated_at TIMESTAMP NOT NULL
+            );",
+        files: None,
+    },
+];
+
+/// Version of the schema after all migrations
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Version of the schema of a database, 0 if it is not versioned
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER NOT NULL,
+            description TEXT NOT NULL,
+            applied_at TIMESTAMP NOT NULL
+        );",
+        [],
+    )?;
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Migrations that have not been applied to a database at a given version
+pub fn pending(version: i64) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS.iter().filter(move |m| m.version > version)
+}
+
+/// Apply all pending migrations to the database and folder of a library, each
+/// in its own transaction. Returns the number of migrations applied.
+pub fn migrate(conn: &mut Connection, library: &Path) -> std::result::Result<usize, OpenError> {
+    let version = schema_version(conn)?;
+    let mut applied = 0;
+    for migration in pending(version) {
+        info!(
+            "Upgrading library to schema version {}: {}",
+            migration.version, migration.description
+        );
+        if let Some(files) = migration.files {
+            files(library).map_err(|e| OpenError::Migration(migration.version, e))?;
+        }
+        let tx = conn.transaction()?;
+        for statement in migration.sql.split(';') {
+            if !statement.trim().is_empty() {
+                tx.execute(statement, [])?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)",
+            params![
+                migration.version,
+                migration.description,
+                Utc::now().to_string()
+            ],
+        )?;
+        tx.commit()?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexdb::{Library, OpenError, DB_FILE};
+    use crate::testutil::{fixture, TempDir};
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1);
+            assert!(!migration.sql.trim().is_empty() || migration.files.is_some());
+        }
+        assert_eq!(latest_version(), MIGRATIONS.len() as i64);
+        assert_eq!(pending(0).count(), MIGRATIONS.len());
+        assert_eq!(pending(latest_version()).count(), 0);
+        assert_eq!(pending(2).next().map(|m| m.version), Some(3));
+    }
+
+    #[test]
+    fn test_upgrade_unversioned_library() {
+        let dir = TempDir::new("schema-upgrade");
+        {
+            let conn = Connection::open(dir.join(DB_FILE)).unwrap();
+            let sql = std::fs::read_to_string(fixture("photocat-v0.sql")).unwrap();
+            conn.execute_batch(&sql).unwrap();
+        }
+        let sha256 = "b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c";
+        let sidecar = dir.join(format!("{}.json", sha256));
+        std::fs::write(&sidecar, r#"{"Make": "Canon"}"#).unwrap();
+
+        let library = Library::open(dir.to_str().unwrap()).unwrap();
+        assert_eq!(library.schema_version().unwrap(), latest_version());
+        let files = library.indexed_files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].filename, "/photos/2019/IMG_0001.JPG");
+        assert!(!sidecar.exists());
+        assert!(sidecar::sidecar_path(&dir, sha256).is_file());
+        drop(library);
+
+        // opening again has nothing left to migrate
+        let mut conn = Connection::open(dir.join(DB_FILE)).unwrap();
+        assert_eq!(migrate(&mut conn, &dir).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_init_library() {
+        let dir = TempDir::new("schema-init");
+        let missing = dir.join("missing");
+        assert!(matches!(
+            Library::open(missing.to_str().unwrap()),
+            Err(OpenError::NotADirectory(_))
+        ));
+        let path = dir.to_str().unwrap();
+        assert!(matches!(
+            Library::open(path),
+            Err(OpenError::NotALibrary(_))
+        ));
+
+        let library = Library::init(path).unwrap();
+        assert_eq!(library.schema_version().unwrap(), latest_version());
+        assert!(library.indexed_files().unwrap().is_empty());
+        drop(library);
+        assert!(Library::open(path).is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+//! Layout of the JSON metadata files ("sidecars") in a library folder.
+//!
+//! Each indexed file has its metadata in `meta/ab/cd/<sha256>.json`, where
+//! `ab` and `cd` are the first two pairs of hex digits of the hash. Sharding
+//! keeps directories small enough for file managers and filesystems when a
+//! library has hundreds of thousands of files. Libraries created before this
+//! layout kept all sidecars next to `photocat.db`; they are moved by a schema
+//! migration. The change log of the metadata of a file is kept next to its
+//! sidecar in `<sha256>.history.jsonl`, one version per line.
+
+use log::info;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Folder below the library root that holds the sidecars
+pub const SIDECAR_DIR: &str = "meta";
+
+/// Glob below the library root that matches all sidecars
+pub const SIDECAR_GLOB: &str = "meta/*/*/*.json";
+
+/// Path of the sidecar for a file hash
+pub fn sidecar_path(library: &Path, sha256: &str) -> PathBuf {
+    let shard = |range: std::ops::Range<usize>| sha256.get(range).unwrap_or("_");
+    library
+        .join(SIDECAR_DIR)
+        .join(shard(0..2))
+        .join(shard(2..4))
+        .join(format!("{}.json", sha256))
+}
+
+/// Path of the change log for a file hash, next to its sidecar. The
+/// extension keeps it out of `SIDECAR_GLOB`.
+pub fn history_path(library: &Path, sha256: &str) -> PathBuf {
+    sidecar_path(library, sha256).with_extension("history.jsonl")
+}
+
+/// Return the hash if a file name is `<sha256>.json`
+fn sidecar_hash(file_name: &str) -> Option<&str> {
+    let sha256 = file_name.strip_suffix(".json")?;
+    (sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit())).then_some(sha256)
+}
+
+/// All sidecars of a folder in the sharded layout, with their hashes. Empty if
+/// there is no `meta/` folder.
+pub fn list_sidecars(library: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let subdirs = |dir: &Path| -> io::Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+        Ok(dirs)
+    };
+    let root = library.join(SIDECAR_DIR);
+    if !root.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut sidecars = Vec::new();
+    for shard in subdirs(&root)? {
+        for subshard in subdirs(&shard)? {
+            for entry in std::fs::read_dir(subshard)? {
+                let entry = entry?;
+                if let Some(sha256) = entry.file_name().to_str().and_then(sidecar_hash) {
+                    sidecars.push((sha256.to_string(), entry.path()));
+                }
+            }
+        }
+    }
+    sidecars.sort();
+    Ok(sidecars)
+}
+
+/// Move sidecars from the library root into the sharded layout. Other files
+/// are left alone. Returns the number of files moved.
+pub fn move_to_shards(library: &Path) -> io::Result<usize> {
+    let mut moved = 0;
+    for entry in std::fs::read_dir(library)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let sha256 = match file_name.to_str().and_then(sidecar_hash) {
+            Some(sha256) if entry.file_type()?.is_file() => sha256,
+            _ => continue,
+        };
+        let target = sidecar_path(library, sha256);
+        std::fs::create_dir_all(target.parent().unwrap())?;
+        std::fs::rename(entry.path(), target)?;
+        moved += 1;
+    }
+    if moved > 0 {
+        info!("Moved {} metadata files into {}/", moved, SIDECAR_DIR);
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_move_to_shards() {
+        let dir = TempDir::new("sidecar");
+        let sha256 = "b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c";
+        std::fs::write(dir.join(format!("{}.json", sha256)), "{}").unwrap();
+        std::fs::write(dir.join("notes.json"), "{}").unwrap();
+        std::fs::write(dir.join("mapping.toml"), "").unwrap();
+
+        assert_eq!(move_to_shards(&dir).unwrap(), 1);
+        let target = sidecar_path(&dir, sha256);
+        assert_eq!(
+            target,
+            dir.join("meta/b5/bb").join(format!("{}.json", sha256))
+        );
+        assert!(target.is_file());
+        assert_eq!(
+            list_sidecars(&dir).unwrap(),
+            vec![(sha256.to_string(), target.clone())]
+        );
+        assert_eq!(
+            history_path(&dir, sha256),
+            dir.join("meta/b5/bb")
+                .join(format!("{}.history.jsonl", sha256))
+        );
+        assert!(dir.join("notes.json").is_file());
+        assert!(dir.join("mapping.toml").is_file());
+        // nothing left to move
+        assert_eq!(move_to_shards(&dir).unwrap(), 0);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::datesummary::DateSummary;
 use crate::fileindex::IndexFile;
+use crate::geo::GeoGridSummary;
 use crate::valuecountsummary::ValueCounter;
 use std::fmt;
 
@@ -7,32 +8,37 @@ pub trait FileIndexSummarizer: fmt::Display {
     fn add(&mut self, f: &IndexFile);
 }
 
+/// Which summaries to show. The date summary is always shown.
+#[derive(Debug, Clone, Default)]
+pub struct SummaryOptions {
+    /// Wrap the date summary after this many months
+    pub months_per_row: Option<usize>,
+    /// Groups of metadata variables to count combinations of values for
+    pub count: Vec<Vec<String>>,
+    /// Show a map of photo locations with cells of this many degrees
+    pub grid: Option<f64>,
+}
+
 pub struct SummaryStats {
     summaries: Vec<Box<dyn FileIndexSummarizer>>,
 }
 
 impl SummaryStats {
-    pub fn new(options: &Option<String>) -> SummaryStats {
+    pub fn new(options: &SummaryOptions) -> SummaryStats {
         let mut summaries: Vec<Box<dyn FileIndexSummarizer>> = Vec::new();
 
-        let options = if let Some(options) = options {
-            options.split(';').map(|s| s.trim()).collect::<Vec<_>>()
-        } else {
-            Vec::new()
-        };
-
-        let wrap_months: bool = options.contains(&"wrap");
-        if wrap_months {
-            summaries.push(Box::new(DateSummary::new_wrapping(8)));
-        } else {
-            summaries.push(Box::new(DateSummary::new()));
+        match options.months_per_row {
+            Some(months_per_row) => {
+                summaries.push(Box::new(DateSummary::new_wrapping(months_per_row)))
+            }
+            None => summaries.push(Box::new(DateSummary::new())),
         }
 
-        for o in options {
-            if let Some(to_count) = o.strip_prefix("count:") {
-                let variables: Vec<String> = to_count.split(",").map(|x| String::from(x)).collect();
-                summaries.push(Box::new(ValueCounter::new(variables)));
-            }
+        for variables in &options.count {
+            summaries.push(Box::new(ValueCounter::new(variables.clone())));
+        }
+        if let Some(cell_degrees) = options.grid {
+            summaries.push(Box::new(GeoGridSummary::new(cell_degrees)));
         }
         SummaryStats { summaries }
     }
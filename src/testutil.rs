@@ -0,0 +1,54 @@
+//! Helpers shared by the tests of several modules
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::filewalk::FoundFile;
+use crate::indexdb::{self, IndexRecord};
+
+/// An empty folder below the system temporary folder, removed with its
+/// contents when dropped, also when an assertion fails
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create a folder named after `name`, unique to this process and call
+    pub fn new(name: &str) -> TempDir {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "photocat-{}-{}-{}",
+            name,
+            std::process::id(),
+            CREATED.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Path of a file in `tests/fixtures`
+pub fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Hash the `exif.jpg` fixture, without metadata
+pub fn hash_exif_jpg() -> IndexRecord {
+    indexdb::hash_file(&FoundFile::from(fixture("exif.jpg"))).unwrap()
+}
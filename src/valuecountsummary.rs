@@ -15,7 +15,7 @@ pub struct ValueCounter {
 impl ValueCounter {
     pub fn new(variables: Vec<String>) -> ValueCounter {
         ValueCounter {
-            variables: variables.iter().sorted().map(|x| x.clone()).collect(),
+            variables: variables.iter().sorted().cloned().collect(),
             counts: HashMap::new(),
             variable_values: HashMap::new(),
         }
@@ -26,13 +26,7 @@ impl FileIndexSummarizer for ValueCounter {
     fn add(&mut self, f: &crate::fileindex::IndexFile) {
         let mut keys: Vec<String> = Vec::new();
         for v in &self.variables {
-            let mut value: Option<&MetaValue> = None;
-            for iv in &f.meta {
-                if iv.name == *v {
-                    value = Some(&iv.value);
-                    break;
-                }
-            }
+            let value: Option<MetaValue> = f.value(v);
             let key = match value {
                 Some(x) => {
                     format!("{}:{}:{}", v, x.string_type(), x)
@@ -41,7 +35,7 @@ impl FileIndexSummarizer for ValueCounter {
             };
             self.variable_values
                 .entry(v.clone())
-                .or_insert(HashSet::new())
+                .or_default()
                 .insert(key.clone());
             keys.push(key);
         }
@@ -93,7 +87,7 @@ impl Display for ValueCounter {
                 let val_1_substring = val_1.splitn(3, ':').nth(2).unwrap_or("");
                 let mut row = vec![String::from(val_1_substring)];
                 for val_2 in &vals_2 {
-                    let key = vec![val_1, val_2].iter().join(",");
+                    let key = [val_1, val_2].iter().join(",");
                     let count = self.counts.get(&key).unwrap_or(&0);
                     row.push(format!("{}", count));
                 }
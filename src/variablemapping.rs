@@ -1,14 +1,14 @@
 use std::collections::HashMap;
 use std::io;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::fileindex::MetaValue;
 use crate::fileindex::MetaVariable;
 
 pub type Mappings = Vec<Mapping>;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Mapping {
     variable: String,
     match_values: Vec<String>,
@@ -16,6 +16,14 @@ pub struct Mapping {
 }
 
 impl Mapping {
+    pub fn new(variable: &str, match_values: Vec<String>, assign_value: &str) -> Mapping {
+        Mapping {
+            variable: String::from(variable),
+            match_values,
+            assign_value: String::from(assign_value),
+        }
+    }
+
     pub fn apply(&self, variable: &str, value: &str) -> Option<String> {
         if variable == self.variable && self.match_values.contains(&value.to_string()) {
             Some(self.assign_value.clone())
@@ -27,17 +35,23 @@ impl Mapping {
 
 /// Load mappings from a file
 pub fn load_mappings(filename: &str) -> io::Result<Mappings> {
-    let parsed_contents: HashMap<String, Mappings>;
     let mut mappings: Mappings = Vec::new();
 
     let file_contents = std::fs::read_to_string(filename)?;
-    parsed_contents =
-        toml::from_str(&file_contents).expect(&format!("File {} cannot be parsed!", filename));
-    mappings.extend(parsed_contents["mapping"].iter().map(|x| x.clone()));
+    let parsed_contents: HashMap<String, Mappings> = toml::from_str(&file_contents)
+        .unwrap_or_else(|_| panic!("File {} cannot be parsed!", filename));
+    mappings.extend(parsed_contents["mapping"].iter().cloned());
 
     Ok(mappings)
 }
 
+/// Write mappings in the same `[[mapping]]` format that `load_mappings` reads
+pub fn mappings_to_toml(mappings: &Mappings) -> String {
+    let mut contents: HashMap<String, &Mappings> = HashMap::new();
+    contents.insert(String::from("mapping"), mappings);
+    toml::to_string(&contents).expect("Mappings cannot be serialized!")
+}
+
 /// Apply mappings to list of variables
 pub fn apply_mappings(mappings: &Mappings, variables: &mut Vec<MetaVariable>) {
     for v in variables {
@@ -81,6 +95,27 @@ mod tests {
         assert_eq!(items[1].assign_value, "E");
     }
 
+    #[test]
+    fn test_mappings_to_toml() {
+        let mappings: Mappings = vec![
+            Mapping::new("V1", vec![String::from("A"), String::from("B")], "C"),
+            Mapping::new("V2", vec![String::from("D")], "E"),
+        ];
+        let toml_string = mappings_to_toml(&mappings);
+        let items_table: HashMap<String, Vec<Mapping>> = from_str(&toml_string).unwrap();
+        let items: &[Mapping] = &items_table["mapping"];
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].variable, "V1");
+        assert_eq!(
+            items[0].match_values,
+            vec![String::from("A"), String::from("B")]
+        );
+        assert_eq!(items[0].assign_value, "C");
+        assert_eq!(items[1].variable, "V2");
+        assert_eq!(items[1].match_values, vec![String::from("D")]);
+        assert_eq!(items[1].assign_value, "E");
+    }
+
     #[test]
     fn test_apply_mappings() {
         let mut variables: Vec<MetaVariable> = vec![
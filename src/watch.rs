@@ -0,0 +1,480 @@
+//! Keep the index of folders up to date while files appear in them.
+//!
+//! File system events are collected per path and only acted on once a path
+//! has been quiet for the debounce delay, so that files still being copied
+//! are not hashed half-written. New and changed files go through the same
+//! indexing pipeline as `index`, unless they are indexed with the same
+//! contents already. Deleted files are removed from the index, but their
+//! metadata and history are kept. Since the hash of a file includes its
+//! location, a moved file is removed from its old location and indexed at
+//! the new one.
+
+use log::{debug, error, info, warn};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::fileindex::IndexFile;
+use crate::filewalk::{self, FoundFile, PathFilter, WalkOptions};
+use crate::indexdb::Library;
+use crate::indexpipeline::IndexOptions;
+
+/// What happened to a path, as far as the index is concerned
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Change {
+    /// Created, written to or moved here: index it
+    Update,
+    /// Deleted or moved away: remove it from the index
+    Remove,
+}
+
+/// Changes a file system event makes to paths
+pub fn changes(event: &Event) -> Vec<(PathBuf, Change)> {
+    let change = match event.kind {
+        EventKind::Create(_)
+        | EventKind::Modify(ModifyKind::Data(_))
+        | EventKind::Modify(ModifyKind::Any)
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        | EventKind::Access(AccessKind::Close(AccessMode::Write)) => Change::Update,
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            Change::Remove
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            return vec![
+                (event.paths[0].clone(), Change::Remove),
+                (event.paths[1].clone(), Change::Update),
+            ];
+        }
+        // a rename where it is unknown which side this is
+        EventKind::Modify(ModifyKind::Name(_)) => {
+            return event
+                .paths
+                .iter()
+                .map(|path| {
+                    let change = if path.exists() {
+                        Change::Update
+                    } else {
+                        Change::Remove
+                    };
+                    (path.clone(), change)
+                })
+                .collect();
+        }
+        _ => return Vec::new(),
+    };
+    event
+        .paths
+        .iter()
+        .map(|path| (path.clone(), change))
+        .collect()
+}
+
+/// Holds back the changes of each path until it has been quiet for a delay.
+/// The last change of a path wins.
+pub struct Debouncer {
+    delay: Duration,
+    pending: HashMap<PathBuf, (Change, Instant)>,
+}
+
+impl Debouncer {
+    pub fn new(delay: Duration) -> Self {
+        Debouncer {
+            delay,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, path: PathBuf, change: Change, now: Instant) {
+        self.pending.insert(path, (change, now));
+    }
+
+    /// Changes of paths that have been quiet for the delay, sorted by path
+    pub fn take_ready(&mut self, now: Instant) -> Vec<(PathBuf, Change)> {
+        let ready: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, at))| now.duration_since(*at) >= self.delay)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut changes: Vec<(PathBuf, Change)> = ready
+            .into_iter()
+            .map(|path| {
+                let (change, _) = self.pending.remove(&path).unwrap();
+                (path, change)
+            })
+            .collect();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Why folders cannot be watched
+#[derive(Debug)]
+pub enum WatchError {
+    /// A folder cannot be resolved
+    Location(String, std::io::Error),
+    /// An include or exclude pattern is invalid
+    Pattern(globset::Error),
+    /// The file system cannot be watched
+    Notify(notify::Error),
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchError::Location(location, e) => write!(f, "Cannot watch {}: {}", location, e),
+            WatchError::Pattern(e) => write!(f, "Invalid pattern: {}", e),
+            WatchError::Notify(e) => write!(f, "Cannot watch files: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
+/// Watch folders and index files that are added or changed below them, until
+/// `stop` completes. Only files that walking the folders would return are
+/// indexed.
+pub async fn watch(
+    library: &Library,
+    locations: &[String],
+    walk_options: WalkOptions,
+    delay: Duration,
+    options: IndexOptions,
+    stop: impl Future<Output = ()>,
+) -> Result<(), WatchError> {
+    // index rows use canonical paths, so watch those
+    let roots = locations
+        .iter()
+        .map(|location| {
+            std::fs::canonicalize(location).map_err(|e| WatchError::Location(location.clone(), e))
+        })
+        .collect::<Result<Vec<PathBuf>, WatchError>>()?;
+    let filter = PathFilter::new(walk_options.clone()).map_err(WatchError::Pattern)?;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    })
+    .map_err(WatchError::Notify)?;
+    for root in &roots {
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .map_err(WatchError::Notify)?;
+        info!("Watching {}", root.display());
+    }
+
+    let mut debouncer = Debouncer::new(delay);
+    let tick = delay.min(Duration::from_millis(500)) / 2;
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = tokio::time::sleep(tick) => {}
+        }
+        let now = Instant::now();
+        while let Ok(event) = receiver.try_recv() {
+            match event {
+                Ok(event) => {
+                    if event.need_rescan() {
+                        warn!("Missed file system events, run index again to catch up");
+                    }
+                    for (path, change) in changes(&event) {
+                        debouncer.add(path, change, now);
+                    }
+                }
+                Err(e) => error!("Error watching files: {}", e),
+            }
+        }
+        let ready = debouncer.take_ready(now);
+        if !ready.is_empty() {
+            apply(library, &roots, &filter, &walk_options, &options, ready).await;
+        }
+    }
+    if !debouncer.is_empty() {
+        warn!("Stopped with changes not yet indexed, run index again to catch up");
+    }
+    Ok(())
+}
+
+/// Update the index for changed paths
+async fn apply(
+    library: &Library,
+    roots: &[PathBuf],
+    filter: &PathFilter,
+    walk_options: &WalkOptions,
+    options: &IndexOptions,
+    changes: Vec<(PathBuf, Change)>,
+) {
+    let mut removed_folders = Vec::new();
+    let mut files = Vec::new();
+    for (path, change) in changes {
+        let Some(root) = roots.iter().find(|root| path.starts_with(root)) else {
+            continue;
+        };
+        let Some(name) = path.to_str().map(String::from) else {
+            warn!("Skipping path that is not valid UTF-8: {}", path.display());
+            continue;
+        };
+        if change == Change::Remove {
+            // it is gone, so whether it was a folder is unknown
+            removed_folders.push(name);
+            continue;
+        }
+        if path.is_dir() {
+            // a folder moved or copied in, events for the files in it may
+            // have been sent before it was watched. Walked files are checked
+            // against the patterns of the whole location.
+            match filewalk::walk_files(&[name], walk_options.clone()) {
+                Ok(walked) => files
+                    .extend(walked.filter_map(|file| filter.accepts(root, Path::new(&file.path)))),
+                Err(e) => error!("Invalid pattern: {}", e),
+            }
+        } else {
+            files.extend(filter.accepts(root, &path));
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files.dedup_by(|a, b| a.path == b.path);
+    let (files, removed_files) = changed_files(library, files).await;
+
+    match library.remove_files(&removed_files, &removed_folders) {
+        Ok(count) => debug!("Removed {} files from the index", count),
+        Err(e) => error!("Cannot remove files from the index: {}", e),
+    }
+    if !files.is_empty() {
+        debug!("Indexing {:?}", files);
+        let count = files.len();
+        library.index(files, options.clone()).await;
+        info!("Indexed {} files", count);
+    }
+}
+
+/// The files that are not indexed with their current contents, and the
+/// paths of those whose index rows are outdated and need to be replaced.
+/// Only files already indexed at their path are hashed.
+async fn changed_files(library: &Library, files: Vec<FoundFile>) -> (Vec<FoundFile>, Vec<String>) {
+    let indexed: HashMap<String, String> = match library.indexed_files() {
+        Ok(indexed) => indexed
+            .into_iter()
+            .map(|f| (f.filename, f.sha256))
+            .collect(),
+        Err(e) => {
+            error!("Cannot read the index: {}", e);
+            HashMap::new()
+        }
+    };
+    let hashing = tokio::task::spawn_blocking(move || {
+        let mut outdated = Vec::new();
+        let files = files
+            .into_iter()
+            .filter(|file| {
+                let Some(sha256) = indexed.get(&file.path) else {
+                    return true;
+                };
+                match IndexFile::new(&file.path) {
+                    Ok(fileinfo) if &fileinfo.sha256 == sha256 => false,
+                    _ => {
+                        outdated.push(file.path.clone());
+                        true
+                    }
+                }
+            })
+            .collect();
+        (files, outdated)
+    });
+    match hashing.await {
+        Ok(changed) => changed,
+        Err(e) => {
+            error!("Cannot hash changed files: {}", e);
+            (Vec::new(), Vec::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metacmd::{MetaCommand, MetaExtractor, NamedExtractor};
+    use crate::sidecar;
+    use crate::testutil::{fixture, TempDir};
+    use notify::event::{CreateKind, DataChange, RemoveKind};
+
+    #[test]
+    fn test_changes() {
+        let event = |kind, paths: &[&str]| {
+            paths.iter().fold(Event::new(kind), |event, path| {
+                event.add_path(PathBuf::from(path))
+            })
+        };
+        assert_eq!(
+            changes(&event(EventKind::Create(CreateKind::File), &["/a.jpg"])),
+            vec![(PathBuf::from("/a.jpg"), Change::Update)]
+        );
+        assert_eq!(
+            changes(&event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                &["/a.jpg"]
+            )),
+            vec![(PathBuf::from("/a.jpg"), Change::Update)]
+        );
+        assert_eq!(
+            changes(&event(EventKind::Remove(RemoveKind::File), &["/a.jpg"])),
+            vec![(PathBuf::from("/a.jpg"), Change::Remove)]
+        );
+        assert_eq!(
+            changes(&event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["/a.jpg", "/b.jpg"]
+            )),
+            vec![
+                (PathBuf::from("/a.jpg"), Change::Remove),
+                (PathBuf::from("/b.jpg"), Change::Update)
+            ]
+        );
+        assert!(changes(&event(
+            EventKind::Access(AccessKind::Open(AccessMode::Read)),
+            &["/a.jpg"]
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn test_debouncer() {
+        let start = Instant::now();
+        let delay = Duration::from_secs(2);
+        let mut debouncer = Debouncer::new(delay);
+        let a = PathBuf::from("/a.jpg");
+        let b = PathBuf::from("/b.jpg");
+        debouncer.add(a.clone(), Change::Update, start);
+        debouncer.add(b.clone(), Change::Update, start);
+        // a is still being written
+        debouncer.add(a.clone(), Change::Update, start + Duration::from_secs(1));
+        assert!(debouncer
+            .take_ready(start + Duration::from_secs(1))
+            .is_empty());
+        assert_eq!(
+            debouncer.take_ready(start + delay),
+            vec![(b.clone(), Change::Update)]
+        );
+        // then deleted before it was indexed
+        debouncer.add(a.clone(), Change::Remove, start + delay);
+        assert_eq!(
+            debouncer.take_ready(start + delay * 2),
+            vec![(a, Change::Remove)]
+        );
+        assert!(debouncer.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_apply() {
+        let dir = TempDir::new("watch");
+        let photos = dir.join("photos");
+        std::fs::create_dir_all(photos.join("trip")).unwrap();
+        let photos = std::fs::canonicalize(photos).unwrap();
+        let library = Library::init(dir.join("library").to_str().unwrap()).unwrap();
+        let walk_options = WalkOptions {
+            extensions: vec![String::from("jpg")],
+            ..WalkOptions::default()
+        };
+        let filter = PathFilter::new(walk_options.clone()).unwrap();
+        let roots = [photos.clone()];
+        // the metadata command counts the files indexed
+        let runs_file = dir.join("runs");
+        let command = format!("sh -c 'echo >> {}; echo [{{}}]'", runs_file.display());
+        let options = IndexOptions {
+            extractors: vec![NamedExtractor {
+                name: None,
+                extensions: Vec::new(),
+                extractor: MetaExtractor::Command(
+                    MetaCommand::parse(&command, Duration::from_secs(10)).unwrap(),
+                ),
+            }],
+            ..IndexOptions::default()
+        };
+        let runs = || std::fs::read_to_string(&runs_file).unwrap().lines().count();
+        let apply = |changes: Vec<(PathBuf, Change)>| {
+            apply(&library, &roots, &filter, &walk_options, &options, changes)
+        };
+        // file names relative to the photos folder, and their hashes
+        let indexed = || -> Vec<(String, String)> {
+            let mut files: Vec<(String, String)> = library
+                .indexed_files()
+                .unwrap()
+                .into_iter()
+                .map(|f| {
+                    let name = Path::new(&f.filename).strip_prefix(&photos).unwrap();
+                    (name.to_str().unwrap().to_string(), f.sha256)
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let names = || -> Vec<String> { indexed().into_iter().map(|(name, _)| name).collect() };
+
+        std::fs::copy(fixture("exif.jpg"), photos.join("a.jpg")).unwrap();
+        std::fs::copy(fixture("exif.jpg"), photos.join("trip/c.jpg")).unwrap();
+        std::fs::write(photos.join("notes.txt"), "not a photo").unwrap();
+        apply(vec![
+            (photos.join("a.jpg"), Change::Update),
+            (photos.join("notes.txt"), Change::Update),
+            (photos.join("trip"), Change::Update),
+        ])
+        .await;
+        assert_eq!(names(), vec!["a.jpg", "trip/c.jpg"]);
+        assert_eq!(runs(), 2);
+        let sidecar = |name: &str| {
+            let (_, sha256) = indexed().into_iter().find(|(n, _)| n == name).unwrap();
+            sidecar::sidecar_path(library.path(), &sha256)
+        };
+        let a_sidecar = sidecar("a.jpg");
+        let c_sidecar = sidecar("trip/c.jpg");
+        for path in [&a_sidecar, &c_sidecar] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "{}").unwrap();
+        }
+
+        // the folder changing does not remove what is in it, and files that
+        // did not change are not indexed again
+        std::fs::copy(fixture("exif.jpg"), photos.join("trip/d.jpg")).unwrap();
+        apply(vec![
+            (photos.join("trip"), Change::Update),
+            (photos.join("a.jpg"), Change::Update),
+        ])
+        .await;
+        assert_eq!(names(), vec!["a.jpg", "trip/c.jpg", "trip/d.jpg"]);
+        assert!(a_sidecar.is_file());
+        assert!(c_sidecar.is_file());
+        assert_eq!(runs(), 3);
+
+        // a file whose contents changed replaces its index row
+        std::fs::write(photos.join("a.jpg"), "edited").unwrap();
+        apply(vec![(photos.join("a.jpg"), Change::Update)]).await;
+        assert_eq!(names(), vec!["a.jpg", "trip/c.jpg", "trip/d.jpg"]);
+        assert_eq!(runs(), 4);
+        assert_ne!(sidecar("a.jpg"), a_sidecar);
+
+        std::fs::rename(photos.join("a.jpg"), photos.join("b.jpg")).unwrap();
+        apply(vec![
+            (photos.join("a.jpg"), Change::Remove),
+            (photos.join("b.jpg"), Change::Update),
+        ])
+        .await;
+        assert_eq!(names(), vec!["b.jpg", "trip/c.jpg", "trip/d.jpg"]);
+        // metadata of moved and deleted files is kept
+        assert!(a_sidecar.is_file());
+
+        std::fs::remove_dir_all(photos.join("trip")).unwrap();
+        apply(vec![(photos.join("trip"), Change::Remove)]).await;
+        assert_eq!(names(), vec!["b.jpg"]);
+        assert!(c_sidecar.is_file());
+    }
+}
@@ -0,0 +1,16 @@
+-- Index database as created by photocat before schema versioning
+CREATE TABLE fileindex (
+    filename TEXT NOT NULL,
+    url TEXT NOT NULL,
+    sha256 TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    modified_at TIMESTAMP NOT NULL
+);
+CREATE INDEX idx_sha256 ON fileindex (sha256);
+INSERT INTO fileindex VALUES (
+    '/photos/2019/IMG_0001.JPG',
+    'file:///photos/2019/IMG_0001.JPG',
+    'b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c',
+    '2019-06-01 10:00:00',
+    '2019-06-01 10:00:00'
+);